@@ -3,6 +3,7 @@
 extern crate alloc;
 
 use deravel_kernel_api::*;
+use log::*;
 
 #[derive(Debug)]
 enum Image<'a> {
@@ -49,8 +50,8 @@ impl Image<'_> {
 }
 
 fn main(args: ImageViewerArgs) {
-    let image = unsafe { &(*map_shared(args.image)).0 };
-    let image = parse_image(image);
+    let mapped = map_shared(args.image);
+    let image = parse_image(unsafe { &(*mapped).0 });
     let min_width: usize = 400;
     let min_height: usize = 300;
     let scale = min_width
@@ -60,8 +61,16 @@ fn main(args: ImageViewerArgs) {
     let window_height = image.height() * scale;
     let window = args
         .windowing
-        .create_window(window_width as u32, window_height as u32);
-    let mut framebuffer = Framebuffer::map(window_width, window_height, window.framebuffer());
+        .try_create_window(window_width as u32, window_height as u32);
+    let Ok(window) = window else {
+        error!("failed to create window");
+        exit();
+    };
+    let Ok(memory) = window.try_framebuffer() else {
+        error!("failed to map window");
+        exit();
+    };
+    let mut framebuffer = Framebuffer::map(window_width, window_height, memory);
     for y in 0..image.height() {
         for x in 0..image.width() {
             let (r, g, b) = image.rgb(x, y);
@@ -72,9 +81,12 @@ fn main(args: ImageViewerArgs) {
             }
         }
     }
-    window.draw();
+    if let Err(failure) = window.try_draw() {
+        error!("windowing {failure} during draw");
+    }
+    unsafe { unmap_shared(mapped) };
     loop {
-        yield_();
+        wait(&[], None);
     }
 }
 
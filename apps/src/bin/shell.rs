@@ -4,6 +4,9 @@
 use deravel_kernel_api::*;
 use log::*;
 
+/// Seconds to wait for the filesystem before giving up on a command.
+const FS_TIMEOUT: f64 = 5.0;
+
 fn main(args: ShellArgs) {
     set_stdio(args.console);
     let mut buf = [0; 128];
@@ -17,20 +20,89 @@ fn main(args: ShellArgs) {
         if cmdline == "hello" {
             println!("Hello world from shell!");
         } else if let Some(file_name) = cmdline.strip_prefix("read ") {
-            let file = args.fs.read(file_name);
-            print!("{}", str::from_utf8(&file).unwrap());
+            match with_timeout(FS_TIMEOUT, || args.fs.read(file_name)) {
+                Ok(file) => print!("{}", str::from_utf8(&file).unwrap()),
+                Err(err) => println!("read {file_name}: {err}"),
+            }
         } else if let Some(file_name) = cmdline.strip_prefix("write ") {
             let mut file_buf = [0; 512];
             let Some(file) = getmultiline(&mut file_buf) else {
                 println!("\nfile contents too long");
                 continue;
             };
-            args.fs.write(file_name, file.as_bytes());
+            if let Err(err) = with_timeout(FS_TIMEOUT, || args.fs.write(file_name, file.as_bytes()))
+            {
+                println!("write {file_name}: {err}");
+            }
+        } else if let Some(file_name) = cmdline.strip_prefix("append ") {
+            let mut file_buf = [0; 512];
+            let Some(file) = getmultiline(&mut file_buf) else {
+                println!("\nfile contents too long");
+                continue;
+            };
+            if let Err(err) =
+                with_timeout(FS_TIMEOUT, || args.fs.append(file_name, file.as_bytes()))
+            {
+                println!("append {file_name}: {err}");
+            }
+        } else if let Some(file_name) = cmdline.strip_prefix("rm ") {
+            if let Err(err) = with_timeout(FS_TIMEOUT, || args.fs.remove(file_name)) {
+                println!("rm {file_name}: {err}");
+            }
+        } else if let Some(path) = cmdline.strip_prefix("mkdir ") {
+            if let Err(err) = with_timeout(FS_TIMEOUT, || args.fs.mkdir(path)) {
+                println!("mkdir {path}: {err}");
+            }
+        } else if let Some(path) = cmdline.strip_prefix("rmdir ") {
+            if let Err(err) = with_timeout(FS_TIMEOUT, || args.fs.rmdir(path)) {
+                println!("rmdir {path}: {err}");
+            }
+        } else if let Some(paths) = cmdline.strip_prefix("mv ") {
+            let Some((from, to)) = paths.split_once(' ') else {
+                println!("usage: mv <from> <to>");
+                continue;
+            };
+            if let Err(err) = with_timeout(FS_TIMEOUT, || args.fs.rename(from, to)) {
+                println!("mv {from} {to}: {err}");
+            }
+        } else if cmdline == "ls" || cmdline.starts_with("ls ") {
+            let path = cmdline[2..].trim_start();
+            match with_timeout(FS_TIMEOUT, || args.fs.list(path)) {
+                Ok(files) => {
+                    for file in files {
+                        if file.attributes & ATTR_DIRECTORY != 0 {
+                            println!("{}/", file.name);
+                        } else {
+                            println!("{} {}", file.name, file.size);
+                        }
+                    }
+                }
+                Err(err) => println!("ls {path}: {err}"),
+            }
+        } else if let Some(path) = cmdline.strip_prefix("stat ") {
+            match with_timeout(FS_TIMEOUT, || args.fs.stat(path)) {
+                Ok(file) => {
+                    println!("name: {}", file.name);
+                    println!("size: {}", file.size);
+                    println!("attributes: {:#04x}", file.attributes);
+                    println!("created: {}", file.created);
+                    println!("modified: {}", file.modified);
+                }
+                Err(err) => println!("stat {path}: {err}"),
+            }
         } else if let Some(file_name) = cmdline.strip_prefix("image ") {
-            let file = args.fs.read_large(file_name);
-            let file = forward(file, Actor::Kernel);
-            let windowing = forward(args.windowing, Actor::Kernel);
-            args.image_viewer.spawn(file, windowing);
+            let file = match with_timeout(FS_TIMEOUT, || args.fs.read_large(file_name)) {
+                Ok(file) => file,
+                Err(err) => {
+                    println!("image {file_name}: {err}");
+                    continue;
+                }
+            };
+            let windowing =
+                forward_with_rights(args.windowing, current_pid(), Windowing::CREATE_WINDOW);
+            if args.image_viewer.spawn(file, windowing).is_none() {
+                println!("image {file_name}: failed to start the viewer");
+            }
         } else if let Some(domain) = cmdline.strip_prefix("dns ") {
             let ip = args.net.dns(domain);
             println!("{ip}");
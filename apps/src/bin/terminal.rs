@@ -4,6 +4,7 @@ extern crate alloc;
 
 include!(concat!(env!("OUT_DIR"), "/font.rs"));
 
+use alloc::collections::VecDeque;
 use deravel_kernel_api::input::*;
 use deravel_kernel_api::*;
 use log::*;
@@ -15,7 +16,8 @@ struct Renderer {
     window_height: i32,
     framebuffer: Framebuffer,
     window: Capability<Window>,
-    events: &'static RingBuffer<InputEvent>,
+    input: VecDeque<u8>,
+    getchar_waiting: VecDeque<Deferred<u8>>,
 }
 
 impl Renderer {
@@ -39,7 +41,10 @@ impl Renderer {
             self.cursor_x = FONT.leftpad as i32;
             self.cursor_y -= FONT.height as i32;
         }
-        self.window.draw();
+        if let Err(failure) = self.window.try_draw() {
+            error!("windowing {failure} during draw");
+            exit();
+        }
     }
 
     fn render_glyph(&mut self, glyph: &Glyph) {
@@ -70,57 +75,12 @@ impl Renderer {
 }
 
 impl ConsoleServer for Renderer {
-    fn getchar(&mut self, _: &mut Ctx<Self>, _: ()) -> u8 {
-        loop {
-            let Some(event) = self.events.poll() else {
-                yield_();
-                continue;
-            };
-
-            // TODO: This assumes the entire sequence is always inserted at once.
-            while let Some(following_event) = self.events.poll() {
-                if following_event.type_ == 0 {
-                    break;
-                }
-            }
-            if event.value == 1 {
-                break match event.code {
-                    KEY_A => b'a',
-                    KEY_B => b'b',
-                    KEY_C => b'c',
-                    KEY_D => b'd',
-                    KEY_E => b'e',
-                    KEY_F => b'f',
-                    KEY_G => b'g',
-                    KEY_H => b'h',
-                    KEY_I => b'i',
-                    KEY_J => b'j',
-                    KEY_K => b'k',
-                    KEY_L => b'l',
-                    KEY_M => b'm',
-                    KEY_N => b'n',
-                    KEY_O => b'o',
-                    KEY_P => b'p',
-                    KEY_Q => b'q',
-                    KEY_R => b'r',
-                    KEY_S => b's',
-                    KEY_T => b't',
-                    KEY_U => b'u',
-                    KEY_V => b'v',
-                    KEY_W => b'w',
-                    KEY_X => b'x',
-                    KEY_Y => b'y',
-                    KEY_Z => b'z',
-                    KEY_ENTER => b'\r',
-                    KEY_DOT => b'.',
-                    KEY_SPACE => b' ',
-                    _ => {
-                        warn!("unrecognized {event:?}");
-                        continue;
-                    }
-                };
-            }
+    fn getchar(&mut self, ctx: &mut Ctx<Self>, _: ()) -> u8 {
+        if let Some(c) = self.input.pop_front() {
+            return c;
         }
+        self.getchar_waiting.push_back(ctx.defer());
+        0
     }
 
     fn putchar(&mut self, _: &mut Ctx<Self>, _: (), c: u8) {
@@ -128,6 +88,57 @@ impl ConsoleServer for Renderer {
     }
 }
 
+impl Observer<InputEvent, ()> for Renderer {
+    fn observe(&mut self, _: OCtx<Self>, event: InputEvent, _: ()) {
+        if event.type_ != EV_KEY || event.value != 1 {
+            return;
+        }
+        let c = match event.code {
+            KEY_A => b'a',
+            KEY_B => b'b',
+            KEY_C => b'c',
+            KEY_D => b'd',
+            KEY_E => b'e',
+            KEY_F => b'f',
+            KEY_G => b'g',
+            KEY_H => b'h',
+            KEY_I => b'i',
+            KEY_J => b'j',
+            KEY_K => b'k',
+            KEY_L => b'l',
+            KEY_M => b'm',
+            KEY_N => b'n',
+            KEY_O => b'o',
+            KEY_P => b'p',
+            KEY_Q => b'q',
+            KEY_R => b'r',
+            KEY_S => b's',
+            KEY_T => b't',
+            KEY_U => b'u',
+            KEY_V => b'v',
+            KEY_W => b'w',
+            KEY_X => b'x',
+            KEY_Y => b'y',
+            KEY_Z => b'z',
+            KEY_ENTER => b'\r',
+            KEY_DOT => b'.',
+            KEY_SPACE => b' ',
+            _ => {
+                warn!("unrecognized {event:?}");
+                return;
+            }
+        };
+        while let Some(getchar) = self.getchar_waiting.pop_front() {
+            let cancelled = getchar.is_cancelled();
+            getchar.reply(c);
+            if !cancelled {
+                return;
+            }
+        }
+        self.input.push_back(c);
+    }
+}
+
 fn find_glyph(c: u8) -> Option<&'static Glyph> {
     FONT.glyphs.iter().find(|character| character.ascii == c)
 }
@@ -135,8 +146,15 @@ fn find_glyph(c: u8) -> Option<&'static Glyph> {
 fn main(args: TerminalArgs) {
     let width = 400;
     let height = 300;
-    let window = args.windowing.create_window(width, height);
-    let framebuffer = Framebuffer::map(width as usize, height as usize, window.framebuffer());
+    let Ok(window) = args.windowing.try_create_window(width, height) else {
+        error!("failed to create window");
+        exit();
+    };
+    let Ok(memory) = window.try_framebuffer() else {
+        error!("failed to map window");
+        exit();
+    };
+    let framebuffer = Framebuffer::map(width as usize, height as usize, memory);
     let mut renderer = Renderer {
         cursor_x: FONT.leftpad as i32,
         cursor_y: 0,
@@ -144,12 +162,15 @@ fn main(args: TerminalArgs) {
         window_height: height as i32,
         framebuffer,
         window,
-        events: window.events(),
+        input: VecDeque::new(),
+        getchar_waiting: VecDeque::new(),
     };
 
     renderer.clear_screen();
 
-    Dispatch::new(renderer).run();
+    let mut dispatch = Dispatch::new(renderer);
+    dispatch.observe((), window.events());
+    dispatch.run();
 }
 
 app! { main }
@@ -22,7 +22,7 @@ struct Server {
     display_width: u32,
     display_height: u32,
     display_framebuffer: Framebuffer,
-    windows: Vec<WindowData>,
+    windows: Vec<Option<WindowData>>,
     active_window: Option<usize>,
     cursor_x: i32,
     cursor_y: i32,
@@ -33,6 +33,7 @@ struct Server {
     global_shortcut: Shortcut,
     shell_spawner: Capability<ShellSpawner>,
     terminal_spawner: Capability<TerminalSpawner>,
+    sessions: Vec<Option<Session>>,
     abs_x_info: InputAbsinfo,
     abs_y_info: InputAbsinfo,
 }
@@ -42,16 +43,24 @@ struct WindowData {
     y: i32,
     width: u32,
     height: u32,
-    status: WindowStatus,
     framebuffer: Framebuffer,
     memory: Capability<SharedMemory>,
-    event_ring: Option<&'static RingBuffer<InputEvent>>,
+    events: Option<EventRing>,
+    owner: ProcessId,
+    capability: Capability<Window>,
+    forwarded: Vec<Capability<SharedMemory>>,
 }
 
-#[derive(Eq, PartialEq)]
-enum WindowStatus {
-    Open,
-    Closed,
+struct EventRing {
+    ring: &'static RingBuffer<InputEvent>,
+    memory: *mut PageAligned<[u8]>,
+    capability: Capability<SharedMemory>,
+}
+
+struct Session {
+    terminal: Capability<ProcessHandle>,
+    shell: Capability<ProcessHandle>,
+    exited: [&'static RingBuffer<u8>; 2],
 }
 
 #[derive(Clone, Copy)]
@@ -60,15 +69,79 @@ struct KeyboardTag;
 #[derive(Clone, Copy)]
 struct MouseTag;
 
+#[derive(Clone, Copy)]
+struct SessionTag(usize);
+
 impl Server {
     fn draw_window(&mut self, window_id: usize) {
-        let window = &self.windows[window_id];
+        let window = self.windows[window_id].as_ref().unwrap();
         self.display_framebuffer.copy_from_rect(
             window.x as isize,
             window.y as isize,
             &window.framebuffer,
         );
     }
+
+    fn open_session(&mut self, ctx: &mut OCtx<Self>) {
+        let Some((term, terminal)) = self.terminal_spawner.spawn(ctx.grant(())) else {
+            error!("failed to spawn terminal");
+            return;
+        };
+        let Some((_, shell)) = self.shell_spawner.spawn(
+            term,
+            self.fs,
+            self.image_viewer,
+            ctx.grant(()),
+            self.net,
+            self.shutdown,
+        ) else {
+            error!("failed to spawn shell");
+            terminal.kill();
+            return;
+        };
+        let session_id = free_slot(&mut self.sessions);
+        let exited = [terminal.exited(), shell.exited()];
+        self.sessions[session_id] = Some(Session {
+            terminal,
+            shell,
+            exited,
+        });
+        for ring in exited {
+            ctx.observe(SessionTag(session_id), ring);
+        }
+    }
+
+    fn close_window(&mut self, window_id: usize) {
+        if self.active_window == Some(window_id) {
+            self.active_window = None;
+        }
+        let window = self.windows[window_id].take().unwrap();
+        revoke(window.capability);
+        for memory in window.forwarded {
+            revoke(memory);
+        }
+        window.framebuffer.free();
+        release(window.memory);
+        if let Some(events) = window.events {
+            unsafe { free_shared(events.memory) };
+            release(events.capability);
+        }
+        self.display_framebuffer.fill_rect(
+            window.x.max(0).min(self.display_width as i32) as usize,
+            window.y.max(0).min(self.display_height as i32) as usize,
+            (window.x + window.width as i32)
+                .max(0)
+                .min(self.display_width as i32) as usize,
+            (window.y + window.height as i32)
+                .max(0)
+                .min(self.display_height as i32) as usize,
+            191,
+            215,
+            234,
+            255,
+        );
+        self.display.draw();
+    }
 }
 
 impl WindowingServer for Server {
@@ -79,47 +152,62 @@ impl WindowingServer for Server {
         width: u32,
         height: u32,
     ) -> Capability<Window> {
-        let window_id = self.windows.len();
+        let window_id = free_slot(&mut self.windows);
         let (framebuffer, memory) = Framebuffer::alloc(width as usize, height as usize);
-        self.windows.push(WindowData {
+        let capability = ctx.grant(window_id);
+        self.windows[window_id] = Some(WindowData {
             x: self.cursor_x - width as i32 / 2,
             y: self.cursor_y - height as i32 / 2,
             width,
             height,
-            status: WindowStatus::Open,
             framebuffer,
             memory,
-            event_ring: None,
+            events: None,
+            owner: ctx.sender(),
+            capability,
+            forwarded: Vec::new(),
         });
         self.active_window = Some(window_id);
-        ctx.grant_to_sender(window_id)
+        capability
     }
 }
 
 impl WindowServer<usize> for Server {
-    fn framebuffer(&mut self, ctx: &mut Ctx<Self>, window_id: usize) -> Capability<SharedMemory> {
-        ctx.forward_to_sender(self.windows[window_id].memory)
+    fn framebuffer(&mut self, _: &mut Ctx<Self>, window_id: usize) -> Capability<SharedMemory> {
+        let window = self.windows[window_id].as_mut().unwrap();
+        let framebuffer = forward(window.memory, current_pid());
+        window.forwarded.push(framebuffer);
+        framebuffer
     }
 
-    fn draw(&mut self, _: &mut Ctx<Self>, window_id: usize) {
+    fn draw(&mut self, ctx: &mut Ctx<Self>, window_id: usize) {
         self.draw_window(window_id);
         if let Some(active) = self.active_window
             && active != window_id
         {
             self.draw_window(active);
         }
-        self.display.draw();
+        // Clients can draw often, so other requests are served while the display catches up.
+        let draw = self.display.draw_async();
+        ctx.spawn(async move {
+            if let Err(failure) = draw.await {
+                error!("display {failure} during draw");
+            }
+        });
     }
 
-    fn events(
-        &mut self,
-        ctx: &mut Ctx<Self>,
-        window_id: usize,
-    ) -> (Capability<SharedMemory>, usize) {
-        let (memory, cap) = alloc_shared(PAGE_SIZE);
+    fn events(&mut self, _: &mut Ctx<Self>, window_id: usize) -> (Capability<SharedMemory>, usize) {
+        let (memory, capability) = alloc_shared(PAGE_SIZE).expect("out of memory for event ring");
         let ring = unsafe { RingBuffer::new_in_single_page(memory) };
-        self.windows[window_id].event_ring = Some(ring);
-        (ctx.forward_to_sender(cap), ring.untype().0.data.0.len())
+        let window = self.windows[window_id].as_mut().unwrap();
+        window.events = Some(EventRing {
+            ring,
+            memory,
+            capability,
+        });
+        let cap = forward(capability, current_pid());
+        window.forwarded.push(cap);
+        (cap, ring.untype().0.data.0.len())
     }
 }
 
@@ -130,39 +218,13 @@ impl Observer<InputEvent, KeyboardTag> for Server {
                 (Shortcut::NotStarted, KEY_LEFTALT, 1) => self.global_shortcut = Shortcut::Alt,
                 (Shortcut::Alt, KEY_ESC, 1) => self.shutdown.shutdown(),
                 (Shortcut::Alt, KEY_T, 1) => {
-                    let term = self.terminal_spawner.spawn(ctx.grant_to_kernel(()));
-                    let term = forward(term, Actor::Kernel);
-                    let fs = forward(self.fs, Actor::Kernel);
-                    let image_viewer = forward(self.image_viewer, Actor::Kernel);
-                    let windowing = ctx.grant_to_kernel(());
-                    let net = forward(self.net, Actor::Kernel);
-                    let shutdown = forward(self.shutdown, Actor::Kernel);
-                    self.shell_spawner
-                        .spawn(term, fs, image_viewer, windowing, net, shutdown);
+                    self.open_session(&mut ctx);
                     self.active_window = None;
                     self.global_shortcut = Shortcut::NotStarted;
                 }
                 (Shortcut::Alt, KEY_Q, 1) => {
-                    if let Some(window_id) = self.active_window.take() {
-                        let window = &mut self.windows[window_id];
-                        window.status = WindowStatus::Closed;
-                        self.display_framebuffer.fill_rect(
-                            window.x.max(0).min(self.display_width as i32) as usize,
-                            window.y.max(0).min(self.display_height as i32) as usize,
-                            (window.x + window.width as i32)
-                                .max(0)
-                                .min(self.display_width as i32)
-                                as usize,
-                            (window.y + window.height as i32)
-                                .max(0)
-                                .min(self.display_height as i32)
-                                as usize,
-                            191,
-                            215,
-                            234,
-                            255,
-                        );
-                        self.display.draw();
+                    if let Some(window_id) = self.active_window {
+                        self.close_window(window_id);
                     }
                 }
                 (Shortcut::Alt, KEY_LEFTALT, 0) => self.global_shortcut = Shortcut::NotStarted,
@@ -171,8 +233,8 @@ impl Observer<InputEvent, KeyboardTag> for Server {
             }
         }
         if let Some(window_id) = self.active_window {
-            if let Some(event_ring) = self.windows[window_id].event_ring {
-                event_ring.push(event);
+            if let Some(events) = &self.windows[window_id].as_ref().unwrap().events {
+                events.ring.push(event);
             }
         }
     }
@@ -183,11 +245,11 @@ impl Observer<InputEvent, MouseTag> for Server {
         if event.type_ == EV_KEY {
             if event.code == BTN_LEFT && event.value == 1 {
                 for (window_index, window) in self.windows.iter().enumerate() {
-                    if self.cursor_x >= window.x
+                    if let Some(window) = window
+                        && self.cursor_x >= window.x
                         && self.cursor_x < window.x + window.width as i32
                         && self.cursor_y >= window.y
                         && self.cursor_y < window.y + window.height as i32
-                        && window.status == WindowStatus::Open
                     {
                         self.active_window = Some(window_index);
                     }
@@ -217,6 +279,32 @@ impl Observer<InputEvent, MouseTag> for Server {
     }
 }
 
+impl Observer<u8, SessionTag> for Server {
+    fn observe(&mut self, mut ctx: OCtx<Self>, _: u8, SessionTag(session_id): SessionTag) {
+        // Whichever process exits first ends the session, so the other one is no longer observed.
+        let session = self.sessions[session_id].take().unwrap();
+        for process in [session.shell, session.terminal] {
+            if let Some(reason) = process.exit_reason() {
+                info!("session process {:?} {reason}", process.pid());
+            }
+        }
+        session.terminal.kill();
+        let terminal_pid = session.terminal.pid();
+        for window_id in 0..self.windows.len() {
+            if let Some(window) = &self.windows[window_id]
+                && window.owner == terminal_pid
+            {
+                self.close_window(window_id);
+            }
+        }
+        for ring in session.exited {
+            ctx.unobserve(ring);
+        }
+        release(session.shell);
+        release(session.terminal);
+    }
+}
+
 fn main(args: WindowingArgs) {
     let width = args.display.width();
     let height = args.display.height();
@@ -245,6 +333,7 @@ fn main(args: WindowingArgs) {
         global_shortcut: Shortcut::NotStarted,
         shell_spawner: args.shell,
         terminal_spawner: args.terminal,
+        sessions: Vec::new(),
         abs_x_info: args.mouse.absinfo(ABS_X),
         abs_y_info: args.mouse.absinfo(ABS_Y),
     };
@@ -261,6 +350,13 @@ fn initialize_cursor(red: u8, green: u8, blue: u8, size: usize, display: Capabil
     display.cursor_image_modified()
 }
 
+fn free_slot<T>(slots: &mut Vec<Option<T>>) -> usize {
+    slots.iter().position(Option::is_none).unwrap_or_else(|| {
+        slots.push(None);
+        slots.len() - 1
+    })
+}
+
 fn from_abs(value: u32, info: &InputAbsinfo, res: u32) -> i32 {
     (((value - info.min) as u64 * res as u64) / (info.max - info.min) as u64) as i32
 }
@@ -5,15 +5,27 @@ use std::collections::HashSet;
 use std::iter::once;
 
 trait ContainsTypes<'a> {
-    fn fix_types(&mut self, interfaces: &HashSet<&'a str>, structs: &HashSet<&'a str>);
+    fn fix_types(&mut self, names: &TypeNames<'a>);
+}
+
+struct TypeNames<'a> {
+    errors: HashSet<&'a str>,
+    interfaces: HashSet<&'a str>,
+    structs: HashSet<&'a str>,
 }
 
 pub struct Drvli<'a> {
+    pub errors: Vec<ErrorEnum<'a>>,
     pub interfaces: Vec<Interface<'a>>,
     pub structs: Vec<Struct<'a>>,
     pub syscalls: Vec<Syscall<'a>>,
 }
 
+pub struct ErrorEnum<'a> {
+    pub name: &'a str,
+    pub variants: Vec<&'a str>,
+}
+
 pub struct Struct<'a> {
     pub name: &'a str,
     pub members: Vec<(&'a str, Type<'a>)>,
@@ -41,6 +53,8 @@ pub enum InterfaceDetails<'a> {
     App {
         args: Vec<(&'a str, Type<'a>)>,
         implements: Option<&'a str>,
+        memory_limit: Option<&'a str>,
+        priority: Option<&'a str>,
     },
     Interface,
 }
@@ -57,16 +71,21 @@ pub enum Type<'a> {
     Bytes,
     ConstArray(Box<Type<'a>>),
     ConstPtr(Box<Type<'a>>),
+    Error(&'a str),
+    ExitReason,
     I8,
     I16,
     I32,
     I64,
     Isize,
+    Loan,
+    MutLoan,
     Never,
     Option(Box<Type<'a>>),
     ProcessId,
     ProcessSpawner(&'a str),
     Ptr(Box<Type<'a>>),
+    Result(Box<Type<'a>>, Box<Type<'a>>),
     SharedMemory,
     Struct(&'a str),
     Text,
@@ -76,6 +95,7 @@ pub enum Type<'a> {
     U16,
     U32,
     U64,
+    Unit,
     Unknown(&'a str),
     UntypedCapability,
     UntypedPointer,
@@ -102,29 +122,38 @@ impl Type<'_> {
             (Array(inner) | ConstArray(inner), SyscallKernelArg) => {
                 format!("UserPtr<[{}]>", inner.rust(ctx)).into()
             }
+            (Array(inner), NormalRet | GrantableRet) => {
+                format!("Vec<{}>", inner.rust(Member)).into()
+            }
             (Bytes, Arg) => "&[u8]".into(),
             (Bytes, NormalRet | GrantableRet) => "Vec<u8>".into(),
             (ConstPtr(inner), _) => format!("*const {}", inner.rust(ctx)).into(),
+            (Error(name), _) => camel_case(name).into(),
+            (ExitReason, _) => "ExitReason".into(),
             (I8, _) => "i8".into(),
             (I16, _) => "i16".into(),
             (I32, _) => "i32".into(),
             (I64, _) => "i64".into(),
             (Isize, _) => "isize".into(),
+            (Loan, Arg) => "&[u8]".into(),
+            (MutLoan, Arg) => "&mut [u8]".into(),
             (Never, _) => "!".into(),
             (Option(inner), _) => format!("Option<{}>", inner.rust(ctx)).into(),
             (Ptr(inner), SyscallKernelArg) => format!("UserPtr<{}>", inner.rust(ctx)).into(),
             (Ptr(inner), _) => format!("*mut {}", inner.rust(ctx)).into(),
-            (ProcessId, SyscallRet) => "ProcessId".into(),
+            (ProcessId, _) => "ProcessId".into(),
+            (Result(ok, err), _) => format!("Result<{}, {}>", ok.rust(ctx), err.rust(ctx)).into(),
             (ProcessSpawner(name), _) => format!("Capability<{}Spawner>", camel_case(name)).into(),
             (SharedMemory, _) => "Capability<SharedMemory>".into(),
             (Struct(name), _) => camel_case(name).into(),
             (TypedCapability(name), _) => format!("Capability<{}>", camel_case(name)).into(),
             (Text, Arg) => "&str".into(),
-            (Text, NormalRet | GrantableRet) => "String".into(),
+            (Text, Member | NormalRet | GrantableRet) => "String".into(),
             (U8, _) => "u8".into(),
             (U16, _) => "u16".into(),
             (U32, _) => "u32".into(),
             (U64, _) => "u64".into(),
+            (Unit, _) => "()".into(),
             (UntypedCapability, _) => "RawCapability".into(),
             (UntypedPointer, _) => "*mut ()".into(),
             (Usize, _) => "usize".into(),
@@ -132,6 +161,27 @@ impl Type<'_> {
         }
     }
 
+    pub fn is_typed_capability(&self) -> bool {
+        matches!(
+            self,
+            Type::ProcessSpawner(_) | Type::SharedMemory | Type::TypedCapability(_)
+        )
+    }
+
+    /// Whether values of the type can hold capabilities, which have to be transferred.
+    pub fn contains_capability(&self) -> bool {
+        match self {
+            Type::Array(inner) | Type::Option(inner) => inner.contains_capability(),
+            Type::Result(ok, err) => ok.contains_capability() || err.contains_capability(),
+            Type::Tuple(types) => types.iter().any(Type::contains_capability),
+            _ => self.is_typed_capability(),
+        }
+    }
+
+    pub fn is_loan(&self) -> bool {
+        matches!(self, Type::Loan | Type::MutLoan)
+    }
+
     pub fn rust_borrow_or_copy(&self) -> &'static str {
         match self {
             Type::Text | Type::Bytes => "&",
@@ -140,45 +190,55 @@ impl Type<'_> {
     }
 }
 
+impl Struct<'_> {
+    /// Structs holding text can't derive Copy, as the text is stored in a String.
+    pub fn is_copy(&self) -> bool {
+        !self
+            .members
+            .iter()
+            .any(|(_, type_)| matches!(type_, Type::Text))
+    }
+}
+
 impl<'a> ContainsTypes<'a> for Drvli<'a> {
-    fn fix_types(&mut self, interfaces: &HashSet<&'a str>, structs: &HashSet<&'a str>) {
-        self.interfaces.fix_types(interfaces, structs);
-        self.structs.fix_types(interfaces, structs);
-        self.syscalls.fix_types(interfaces, structs);
+    fn fix_types(&mut self, names: &TypeNames<'a>) {
+        self.interfaces.fix_types(names);
+        self.structs.fix_types(names);
+        self.syscalls.fix_types(names);
     }
 }
 
 impl<'a> ContainsTypes<'a> for Interface<'a> {
-    fn fix_types(&mut self, interfaces: &HashSet<&'a str>, structs: &HashSet<&'a str>) {
-        self.methods.fix_types(interfaces, structs);
-        self.streams.fix_types(interfaces, structs);
-        self.details.fix_types(interfaces, structs);
+    fn fix_types(&mut self, names: &TypeNames<'a>) {
+        self.methods.fix_types(names);
+        self.streams.fix_types(names);
+        self.details.fix_types(names);
     }
 }
 
 impl<'a> ContainsTypes<'a> for Method<'a> {
-    fn fix_types(&mut self, interfaces: &HashSet<&'a str>, structs: &HashSet<&'a str>) {
+    fn fix_types(&mut self, names: &TypeNames<'a>) {
         for (_, type_) in &mut self.args {
-            type_.fix_types(interfaces, structs);
+            type_.fix_types(names);
         }
         if let Some(type_) = &mut self.return_type {
-            type_.fix_types(interfaces, structs);
+            type_.fix_types(names);
         }
     }
 }
 
 impl<'a> ContainsTypes<'a> for Stream<'a> {
-    fn fix_types(&mut self, interfaces: &HashSet<&'a str>, structs: &HashSet<&'a str>) {
-        self.type_.fix_types(interfaces, structs);
+    fn fix_types(&mut self, names: &TypeNames<'a>) {
+        self.type_.fix_types(names);
     }
 }
 
 impl<'a> ContainsTypes<'a> for InterfaceDetails<'a> {
-    fn fix_types(&mut self, interfaces: &HashSet<&'a str>, structs: &HashSet<&'a str>) {
+    fn fix_types(&mut self, names: &TypeNames<'a>) {
         match self {
             InterfaceDetails::App { args, .. } => {
                 for (_, type_) in args {
-                    type_.fix_types(interfaces, structs);
+                    type_.fix_types(names);
                 }
             }
             InterfaceDetails::Interface => {}
@@ -187,41 +247,46 @@ impl<'a> ContainsTypes<'a> for InterfaceDetails<'a> {
 }
 
 impl<'a> ContainsTypes<'a> for Struct<'a> {
-    fn fix_types(&mut self, interfaces: &HashSet<&'a str>, structs: &HashSet<&'a str>) {
+    fn fix_types(&mut self, names: &TypeNames<'a>) {
         for (_, type_) in &mut self.members {
-            type_.fix_types(interfaces, structs);
+            type_.fix_types(names);
         }
     }
 }
 
 impl<'a> ContainsTypes<'a> for Syscall<'a> {
-    fn fix_types(&mut self, interfaces: &HashSet<&'a str>, structs: &HashSet<&'a str>) {
+    fn fix_types(&mut self, names: &TypeNames<'a>) {
         for (_, type_) in &mut self.args {
-            type_.fix_types(interfaces, structs);
+            type_.fix_types(names);
         }
         if let Some(type_) = &mut self.return_type {
-            type_.fix_types(interfaces, structs);
+            type_.fix_types(names);
         }
     }
 }
 
 impl<'a> ContainsTypes<'a> for Type<'a> {
-    fn fix_types(&mut self, interfaces: &HashSet<&'a str>, structs: &HashSet<&'a str>) {
+    fn fix_types(&mut self, names: &TypeNames<'a>) {
         use Type::*;
         match self {
-            Bytes | I8 | I16 | I32 | I64 | Isize | Never | ProcessId | ProcessSpawner(_)
-            | SharedMemory | Struct(_) | Text | TypedCapability(_) | U8 | U16 | U32 | U64
-            | UntypedCapability | UntypedPointer | Usize => {}
-            Array(t) | ConstArray(t) | ConstPtr(t) | Option(t) | Ptr(t) => {
-                t.fix_types(interfaces, structs)
+            Bytes | Error(_) | ExitReason | I8 | I16 | I32 | I64 | Isize | Loan | MutLoan
+            | Never | ProcessId | ProcessSpawner(_) | SharedMemory | Struct(_) | Text
+            | TypedCapability(_) | U8 | U16 | U32 | U64 | Unit | UntypedCapability
+            | UntypedPointer | Usize => {}
+            Array(t) | ConstArray(t) | ConstPtr(t) | Option(t) | Ptr(t) => t.fix_types(names),
+            Result(ok, err) => {
+                ok.fix_types(names);
+                err.fix_types(names);
             }
             Tuple(ts) => {
-                ts.fix_types(interfaces, structs);
+                ts.fix_types(names);
             }
             Unknown(name) => {
-                if structs.contains(name) {
+                if names.structs.contains(name) {
                     *self = Struct(name);
-                } else if interfaces.contains(name) {
+                } else if names.errors.contains(name) {
+                    *self = Error(name);
+                } else if names.interfaces.contains(name) {
                     *self = TypedCapability(name);
                 } else {
                     panic!("unknown type {name:?}");
@@ -232,9 +297,9 @@ impl<'a> ContainsTypes<'a> for Type<'a> {
 }
 
 impl<'a, T: ContainsTypes<'a>> ContainsTypes<'a> for Vec<T> {
-    fn fix_types(&mut self, interfaces: &HashSet<&'a str>, structs: &HashSet<&'a str>) {
+    fn fix_types(&mut self, names: &TypeNames<'a>) {
         for x in self {
-            x.fix_types(interfaces, structs);
+            x.fix_types(names);
         }
     }
 }
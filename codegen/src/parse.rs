@@ -1,11 +1,13 @@
 use crate::{
-    ContainsTypes, Drvli, Interface, InterfaceDetails, Method, Stream, Struct, Syscall, Type,
+    ContainsTypes, Drvli, ErrorEnum, Interface, InterfaceDetails, Method, Stream, Struct, Syscall,
+    Type, TypeNames,
 };
 use std::iter::Peekable;
 use std::str::Lines;
 
 pub fn parse_drvli(text: &str) -> Drvli<'_> {
     let mut lines = text.lines().peekable();
+    let mut errors = Vec::new();
     let mut structs = Vec::new();
     let mut interfaces = Vec::new();
     let mut syscalls = Vec::new();
@@ -20,6 +22,20 @@ pub fn parse_drvli(text: &str) -> Drvli<'_> {
                 lines.next();
             }
             structs.push(Struct { name, members });
+        } else if let Some(name) = line.strip_prefix("error ") {
+            let mut variants = Vec::new();
+            while let Some(line) = lines.peek()
+                && let Some(variant) = line.strip_prefix("    ")
+            {
+                variants.push(variant);
+                lines.next();
+            }
+            // Every error can also be caused by the server dying, not replying before the deadline
+            // or replying with something that doesn't decode.
+            variants.push("disconnected");
+            variants.push("timed_out");
+            variants.push("invalid_reply");
+            errors.push(ErrorEnum { name, variants });
         } else if let Some(line) = line.strip_prefix("app ") {
             let name_len = line.find(['(', ' ']).unwrap_or(line.len());
             let name = &line[..name_len];
@@ -38,10 +54,30 @@ pub fn parse_drvli(text: &str) -> Drvli<'_> {
             } else {
                 (Vec::new(), line)
             };
-            let line = line.trim();
-            let implements = line.strip_prefix("implements ");
-            let interface =
-                parse_interface(name, &mut lines, InterfaceDetails::App { args, implements });
+            let mut implements = None;
+            let mut memory_limit = None;
+            let mut priority = None;
+            let mut attributes = line.split_whitespace();
+            while let Some(attribute) = attributes.next() {
+                let value = attributes.next().unwrap();
+                match attribute {
+                    "implements" => implements = Some(value),
+                    // In mebibytes.
+                    "memory_limit" => memory_limit = Some(value),
+                    "priority" => priority = Some(value),
+                    _ => panic!("unknown app attribute {attribute:?}"),
+                }
+            }
+            let interface = parse_interface(
+                name,
+                &mut lines,
+                InterfaceDetails::App {
+                    args,
+                    implements,
+                    memory_limit,
+                    priority,
+                },
+            );
             interfaces.push(interface);
         } else if let Some(name) = line.strip_prefix("interface ") {
             let interface = parse_interface(name, &mut lines, InterfaceDetails::Interface);
@@ -51,13 +87,27 @@ pub fn parse_drvli(text: &str) -> Drvli<'_> {
         }
     }
     let mut drvli = Drvli {
+        errors,
         interfaces,
         structs,
         syscalls,
     };
-    let interfaces = drvli.interfaces.iter().map(|i| i.name).collect();
-    let structs = drvli.structs.iter().map(|s| s.name).collect();
-    drvli.fix_types(&interfaces, &structs);
+    let names = TypeNames {
+        errors: drvli.errors.iter().map(|e| e.name).collect(),
+        interfaces: drvli.interfaces.iter().map(|i| i.name).collect(),
+        structs: drvli.structs.iter().map(|s| s.name).collect(),
+    };
+    drvli.fix_types(&names);
+    // Capabilities are only transferred from arguments and return values, not from inside structs.
+    for struct_ in &drvli.structs {
+        for (member_name, member_type) in &struct_.members {
+            assert!(
+                !member_type.contains_capability(),
+                "struct {} member {member_name} holds a capability, which can't be transferred",
+                struct_.name
+            );
+        }
+    }
     drvli
 }
 
@@ -123,6 +173,10 @@ fn parse_syscall(line: &str) -> Syscall<'_> {
 
 pub fn parse_type(src: &str) -> Type<'_> {
     use crate::Type::*;
+    if let Some(inner) = src.strip_prefix("result ") {
+        let (ok, err) = inner.rsplit_once(", ").unwrap();
+        return Result(Box::new(parse_type(ok)), Box::new(parse_type(err)));
+    }
     if src.contains(",") {
         return Tuple(src.split(", ").map(parse_type).collect());
     }
@@ -142,11 +196,14 @@ pub fn parse_type(src: &str) -> Type<'_> {
     match src {
         "bytes" => Bytes,
         "capability" => UntypedCapability,
+        "exit_reason" => ExitReason,
         "i8" => I8,
         "i16" => I16,
         "i32" => I32,
         "i64" => I64,
         "isize" => Isize,
+        "loan" => Loan,
+        "mut_loan" => MutLoan,
         "never" => Never,
         "pid" => ProcessId,
         "ptr" => UntypedPointer,
@@ -156,6 +213,7 @@ pub fn parse_type(src: &str) -> Type<'_> {
         "u16" => U16,
         "u32" => U32,
         "u64" => U64,
+        "unit" => Unit,
         "usize" => Usize,
         _ => Unknown(src),
     }
@@ -1,7 +1,7 @@
 use crate::Type;
 use crate::Type::*;
 use core::assert_matches;
-use core::ops::Deref;
+use core::ops::{Deref, Range};
 
 #[repr(C)]
 pub union Bpb {
@@ -132,6 +132,25 @@ impl Bpb {
         }
     }
 
+    pub fn fat_size(&self, type_: Type) -> u32 {
+        match type_ {
+            Fat12 | Fat16 => self.fat_sz_16 as u32,
+            Fat32 => self.as_extended_32().fat_sz_32,
+        }
+    }
+
+    pub fn fat_sectors(&self, type_: Type) -> Range<u32> {
+        let start = self.rsvd_sec_cnt as u32;
+        let total_size = self.num_fats as u32 * self.fat_size(type_);
+        start..start + total_size
+    }
+
+    pub fn root_directory_sectors(&self, type_: Type) -> Range<u32> {
+        let start = self.fat_sectors(type_).end;
+        let size = self.root_ent_cnt as u32 * 32 / self.byts_per_sec as u32;
+        start..start + size
+    }
+
     pub fn as_extended_12_16(&self) -> &BpbExtended1216 {
         // SAFETY: BPB is plain old data.
         unsafe { &self.extended_12_16 }
@@ -1,4 +1,8 @@
+use alloc::format;
 use alloc::string::String;
+use alloc::vec::Vec;
+use core::ops::Range;
+use deravel_kernel_api::ATTR_DIRECTORY;
 
 #[derive(Clone, Copy)]
 #[repr(C)]
@@ -45,8 +49,7 @@ const ATTR_READ_ONLY: u8 = 0x01;
 const ATTR_HIDDEN: u8 = 0x02;
 const ATTR_SYSTEM: u8 = 0x04;
 const ATTR_VOLUME_ID: u8 = 0x08;
-const ATTR_DIRECTORY: u8 = 0x10;
-const ATTR_ARCHIVE: u8 = 0x20;
+pub const ATTR_ARCHIVE: u8 = 0x20;
 
 const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
 const ATTR_LONG_NAME_MASK: u8 =
@@ -54,6 +57,12 @@ const ATTR_LONG_NAME_MASK: u8 =
 
 const LAST_LONG_ENTRY: u8 = 0x40;
 
+pub const DOT: [u8; 11] = *b".          ";
+pub const DOT_DOT: [u8; 11] = *b"..         ";
+
+const DELETED: u8 = 0xE5;
+const END_OF_DIRECTORY: u8 = 0x00;
+
 const MAX_LONG_RESULT_LENGTH: usize = 255;
 const MAX_LONG_BUFFER_LENGTH: usize = MAX_LONG_ENTRY_LENGTH * MAX_LONG_ENTRIES;
 const MAX_LONG_ENTRY_LENGTH: usize = 13;
@@ -62,42 +71,276 @@ const MAX_LONG_ENTRIES: usize = MAX_LONG_RESULT_LENGTH.div_ceil(MAX_LONG_ENTRY_L
 const UTF16_PERIOD: u16 = b'.' as u16;
 const UTF16_SPACE: u16 = b' ' as u16;
 
+/// Date and time in the FAT encoding, which can't represent anything before 1980.
+#[derive(Clone, Copy)]
+pub struct Timestamp {
+    date: u16,
+    time: u16,
+}
+
+impl DirectoryEntry {
+    pub fn short(short: ShortNameDirectoryEntry) -> DirectoryEntry {
+        DirectoryEntry { short }
+    }
+
+    pub fn as_short(&self) -> &ShortNameDirectoryEntry {
+        unsafe { &self.short }
+    }
+
+    pub fn as_short_mut(&mut self) -> &mut ShortNameDirectoryEntry {
+        unsafe { &mut self.short }
+    }
+
+    pub fn is_free(&self) -> bool {
+        let first = unsafe { self.short.name[0] };
+        first == DELETED || first == END_OF_DIRECTORY
+    }
+
+    pub fn mark_deleted(&mut self) {
+        unsafe { self.short.name[0] = DELETED };
+    }
+
+    pub fn from_bytes(bytes: [u8; 32]) -> DirectoryEntry {
+        unsafe { core::mem::transmute(bytes) }
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        unsafe { &*(self as *const DirectoryEntry as *const [u8; 32]) }
+    }
+}
+
 impl ShortNameDirectoryEntry {
+    pub fn new(name: [u8; 11], attr: u8, now: Timestamp) -> ShortNameDirectoryEntry {
+        ShortNameDirectoryEntry {
+            name,
+            attr,
+            nt_res: [0],
+            crt_time_tenth: 0,
+            crt_time: now.time,
+            crt_date: now.date,
+            lst_acc_date: now.date,
+            fst_clus_hi: 0,
+            wrt_time: now.time,
+            wrt_date: now.date,
+            fst_clus_lo: 0,
+            file_size: 0,
+        }
+    }
+
     pub fn fst_clus(&self) -> u32 {
         ((self.fst_clus_hi as u32) << 16) | self.fst_clus_lo as u32
     }
+
+    pub fn set_fst_clus(&mut self, cluster: u32) {
+        self.fst_clus_hi = (cluster >> 16) as u16;
+        self.fst_clus_lo = cluster as u16;
+    }
+
+    pub fn touch(&mut self, now: Timestamp) {
+        self.wrt_time = now.time;
+        self.wrt_date = now.date;
+        self.lst_acc_date = now.date;
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.name[0] == DELETED
+    }
+
+    pub fn is_end_of_directory(&self) -> bool {
+        self.name[0] == END_OF_DIRECTORY
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    pub fn is_volume_label(&self) -> bool {
+        self.attr & (ATTR_DIRECTORY | ATTR_VOLUME_ID) == ATTR_VOLUME_ID
+    }
+
+    pub fn is_dot(&self) -> bool {
+        self.name == DOT || self.name == DOT_DOT
+    }
+
+    pub fn created(&self) -> Timestamp {
+        Timestamp {
+            date: self.crt_date,
+            time: self.crt_time,
+        }
+    }
+
+    pub fn modified(&self) -> Timestamp {
+        Timestamp {
+            date: self.wrt_date,
+            time: self.wrt_time,
+        }
+    }
+
+    /// Formats the short name the way it's usually displayed, as in `README.TXT`.
+    pub fn short_name(&self) -> String {
+        let mut name = self.name;
+        // A leading 0xE5 byte is stored as 0x05, so it's not mistaken for a deleted entry.
+        if name[0] == 0x05 {
+            name[0] = DELETED;
+        }
+        let (main, extension) = name.split_at(8);
+        let trim = |part: &[u8]| -> String {
+            let length = part
+                .iter()
+                .rposition(|&byte| byte != b' ')
+                .map_or(0, |i| i + 1);
+            part[..length].iter().map(|&byte| byte as char).collect()
+        };
+        let (main, extension) = (trim(main), trim(extension));
+        if extension.is_empty() {
+            main
+        } else {
+            format!("{main}.{extension}")
+        }
+    }
 }
 
+/// Yields every short entry together with its long name, and the indices of all entries making up
+/// the pair.
 pub fn coalesce_long_names<'a>(
-    mut entries: &'a [DirectoryEntry],
-) -> impl Iterator<Item = (&'a ShortNameDirectoryEntry, Option<String>)> + 'a {
+    entries: &'a [DirectoryEntry],
+) -> impl Iterator<Item = (Range<usize>, &'a ShortNameDirectoryEntry, Option<String>)> + 'a {
+    let mut index = 0;
     core::iter::from_fn(move || {
-        if let Some(long_count) = try_as_first_long(&entries[0]) {
-            let longs = &entries[..long_count];
-            let short = unsafe { &entries[long_count].short };
-            entries = &entries[long_count..];
-
-            let checksum = compute_checksum(&short.name);
-            let mut name_buffer = [0; _];
-            let name = copy_long(longs, checksum, &mut name_buffer);
-            Some((short, Some(postprocess_long_name(name))))
-        } else {
-            let short = unsafe { &entries[0].short };
-            entries = &entries[1..];
-            Some((short, None))
+        loop {
+            let first = entries.get(index)?;
+            if let Some(long_count) = try_as_first_long(first)
+                && let Some(long_name) = read_long_name(&entries[index..], long_count)
+            {
+                let short = unsafe { &entries[index + long_count].short };
+                let range = index..index + long_count + 1;
+                index = range.end;
+                return Some((range, short, Some(long_name)));
+            }
+            index += 1;
+            // Long entries that don't make up a valid name for the short entry after them are
+            // orphans, and the short entry is then used with its short name only.
+            if !is_long(first) || first.is_free() {
+                let short = unsafe { &first.short };
+                return Some((index - 1..index, short, None));
+            }
         }
     })
 }
 
+/// Returns the long name entries for a file with the given short name, in the order they precede
+/// the short entry.
+pub fn long_name_entries(name: &str, short_name: &[u8; 11]) -> Vec<DirectoryEntry> {
+    let checksum = compute_checksum(short_name);
+    let mut padded = [0xFFFF; MAX_LONG_BUFFER_LENGTH];
+    let mut length: usize = 0;
+    for (cp, slot) in name.encode_utf16().zip(&mut padded) {
+        *slot = cp;
+        length += 1;
+    }
+    let long_count = length.div_ceil(MAX_LONG_ENTRY_LENGTH);
+    if length < long_count * MAX_LONG_ENTRY_LENGTH {
+        padded[length] = 0;
+    }
+    let chunks = padded.as_chunks::<MAX_LONG_ENTRY_LENGTH>().0;
+    (0..long_count)
+        .rev()
+        .map(|i| {
+            let chunk = &chunks[i];
+            DirectoryEntry {
+                long: LongNameDirectoryEntry {
+                    ord: ord(i, long_count),
+                    name1: chunk[..5].try_into().unwrap(),
+                    attr: ATTR_LONG_NAME,
+                    type_: 0,
+                    chksum: checksum,
+                    name2: chunk[5..11].try_into().unwrap(),
+                    fst_clus_lo: 0,
+                    name3: chunk[11..].try_into().unwrap(),
+                },
+            }
+        })
+        .collect()
+}
+
+/// Returns whether the name can only be stored with long name entries. Short names are uppercase,
+/// so lowercase letters need a long name to be preserved.
+pub fn needs_long_name(name: &str) -> bool {
+    to_short_name(name).is_none() || name.bytes().any(|byte| byte.is_ascii_lowercase())
+}
+
+/// Checks the name survives the trimming done when reading long names back.
+pub fn is_valid_long_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.starts_with(' ')
+        && !name.ends_with([' ', '.'])
+        && name.encode_utf16().count() <= MAX_LONG_RESULT_LENGTH
+        && name.encode_utf16().all(is_valid_long_char)
+}
+
+/// Derives a short alias like `LONGNA~1TXT` for a long name, with the lowest numeric tail that isn't
+/// taken yet.
+pub fn short_alias(name: &str, taken: impl Fn(&[u8; 11]) -> bool) -> Option<[u8; 11]> {
+    let (main_part, extension) = name
+        .rsplit_once('.')
+        .filter(|(main_part, _)| !main_part.is_empty())
+        .unwrap_or((name, ""));
+    let main_part = alias_basis(main_part, 8);
+    let extension = alias_basis(extension, 3);
+    for n in 1..1_000_000 {
+        let tail = format!("~{n}");
+        let kept = main_part.len().min(8 - tail.len());
+        let mut alias = [b' '; 11];
+        alias[..kept].copy_from_slice(&main_part[..kept]);
+        alias[kept..kept + tail.len()].copy_from_slice(tail.as_bytes());
+        alias[8..8 + extension.len()].copy_from_slice(&extension);
+        if !taken(&alias) {
+            return Some(alias);
+        }
+    }
+    None
+}
+
+fn alias_basis(part: &str, max_length: usize) -> Vec<u8> {
+    part.chars()
+        .filter(|c| *c != ' ' && *c != '.')
+        .map(|c| match u8::try_from(c) {
+            Ok(byte) if byte.is_ascii() && is_valid_short_char(byte.to_ascii_uppercase()) => {
+                byte.to_ascii_uppercase()
+            }
+            _ => b'_',
+        })
+        .take(max_length)
+        .collect()
+}
+
+fn is_long(entry: &DirectoryEntry) -> bool {
+    unsafe { entry.long.attr & ATTR_LONG_NAME_MASK == ATTR_LONG_NAME }
+}
+
 fn try_as_first_long(entry: &DirectoryEntry) -> Option<usize> {
     let long = unsafe { &entry.long };
-    if long.attr & ATTR_LONG_NAME_MASK != ATTR_LONG_NAME {
+    // Deleted long entries are skipped one by one, like deleted short entries.
+    if !is_long(entry) || long.ord == DELETED || long.ord & LAST_LONG_ENTRY == 0 {
         return None;
     }
-    assert_ne!(long.ord & LAST_LONG_ENTRY, 0);
     let long_count = (long.ord ^ LAST_LONG_ENTRY) as usize;
-    assert!((1..=MAX_LONG_ENTRIES).contains(&long_count));
-    Some(long_count)
+    (1..=MAX_LONG_ENTRIES)
+        .contains(&long_count)
+        .then_some(long_count)
+}
+
+/// Reads the name from the long entries at the start, if they're followed by the short entry they
+/// belong to.
+fn read_long_name(entries: &[DirectoryEntry], long_count: usize) -> Option<String> {
+    let (longs, rest) = entries.split_at_checked(long_count)?;
+    let short = unsafe { &rest.first()?.short };
+    let checksum = compute_checksum(&short.name);
+    let mut name_buffer = [0; _];
+    let name = copy_long(longs, checksum, &mut name_buffer)?;
+    postprocess_long_name(name)
 }
 
 fn compute_checksum(short_name: &[u8; 11]) -> u8 {
@@ -122,15 +365,19 @@ fn copy_long<'a>(
     longs: &[DirectoryEntry],
     checksum: u8,
     buffer: &'a mut [u16; MAX_LONG_BUFFER_LENGTH],
-) -> &'a [u16] {
+) -> Option<&'a [u16]> {
     let name = &mut buffer[..longs.len() * MAX_LONG_ENTRY_LENGTH];
     for (i, (long, chunk)) in longs.iter().rev().zip(name.as_chunks_mut().0).enumerate() {
+        if !is_long(long) {
+            return None;
+        }
         let long = unsafe { &long.long };
-        assert_eq!(long.ord, ord(i, longs.len()));
-        assert_eq!(long.chksum, checksum);
+        if long.ord != ord(i, longs.len()) || long.chksum != checksum {
+            return None;
+        }
         copy_long_chunk(long, chunk);
     }
-    name
+    Some(name)
 }
 
 fn copy_long_chunk(long: &LongNameDirectoryEntry, chunk: &mut [u16; MAX_LONG_ENTRY_LENGTH]) {
@@ -145,27 +392,29 @@ fn copy_long_chunk(long: &LongNameDirectoryEntry, chunk: &mut [u16; MAX_LONG_ENT
     }
 }
 
-fn postprocess_long_name(mut name: &[u16]) -> String {
+fn postprocess_long_name(mut name: &[u16]) -> Option<String> {
     while let Some(&UTF16_SPACE) = name.first() {
         name = &name[1..];
     }
-    if let Some((null_i, _)) = name.iter().enumerate().find(|(_, cp)| **cp == 0) {
-        assert!(name[null_i + 1..].iter().all(|cp| *cp == 0xFFFF));
+    // Whatever follows the terminator is padding, even if it's not the 0xFFFF it should be.
+    if let Some(null_i) = name.iter().position(|cp| *cp == 0) {
         name = &name[..null_i];
     }
     while let Some(&UTF16_SPACE | &UTF16_PERIOD) = name.last() {
         name = &name[..name.len() - 1];
     }
-    for cp in name {
-        assert!(is_valid_long_char(*cp));
+    if name.is_empty()
+        || name.len() > MAX_LONG_RESULT_LENGTH
+        || !name.iter().all(|cp| is_valid_long_char(*cp))
+    {
+        return None;
     }
-    assert!(name.len() <= MAX_LONG_RESULT_LENGTH);
-    String::from_utf16(name).unwrap()
+    String::from_utf16(name).ok()
 }
 
 pub fn to_short_name(s: &str) -> Option<[u8; 11]> {
     let (main_part, extension) = s.split_once('.').unwrap_or((s, ""));
-    if main_part.len() > 8 {
+    if main_part.is_empty() || main_part.len() > 8 {
         return None;
     }
     if extension.len() > 3 {
@@ -202,3 +451,68 @@ fn is_valid_long_char(cp: u16) -> bool {
 fn is_valid_short_char(byte: u8) -> bool {
     matches!(byte, b'A'..=b'Z' | b'0'..=b'9' | 128.. | b'$' | b'%' | b'\'' | b'-' | b'_' | b'@' | b'~' | b'`' | b'!' | b'(' | b')' | b'{' | b'}' | b'^' | b'#' | b'&' | b' ')
 }
+
+impl Timestamp {
+    pub fn from_unix(unix_time: Option<u64>) -> Timestamp {
+        let Some(unix_time) = unix_time else {
+            return Timestamp::from_parts(1980, 1, 1, 0, 0, 0);
+        };
+        let seconds = unix_time % 86400;
+        let (year, month, day) = civil_from_days((unix_time / 86400) as i64);
+        let year = year.clamp(1980, 2107) as u16;
+        let hour = (seconds / 3600) as u16;
+        let minute = (seconds / 60 % 60) as u16;
+        Timestamp::from_parts(year, month, day, hour, minute, (seconds % 60) as u16)
+    }
+
+    pub fn to_unix(self) -> u64 {
+        let year = 1980 + (self.date >> 9) as i64;
+        let month = ((self.date >> 5) & 0xF).clamp(1, 12);
+        let day = (self.date & 0x1F).max(1);
+        let days = days_from_civil(year, month, day) as u64;
+        let hour = (self.time >> 11) as u64;
+        let minute = ((self.time >> 5) & 0x3F) as u64;
+        let second = 2 * (self.time & 0x1F) as u64;
+        days * 86400 + hour * 3600 + minute * 60 + second
+    }
+
+    fn from_parts(
+        year: u16,
+        month: u16,
+        day: u16,
+        hour: u16,
+        minute: u16,
+        second: u16,
+    ) -> Timestamp {
+        Timestamp {
+            date: ((year - 1980) << 9) | (month << 5) | day,
+            time: (hour << 11) | (minute << 5) | (second / 2),
+        }
+    }
+}
+
+// Converts days since the Unix epoch to a proleptic Gregorian date, from Howard Hinnant's
+// chrono-compatible algorithms.
+fn civil_from_days(days: i64) -> (i64, u16, u16) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u16;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u16;
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+// The inverse of civil_from_days.
+fn days_from_civil(year: i64, month: u16, day: u16) -> i64 {
+    let year = year - (month <= 2) as i64;
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
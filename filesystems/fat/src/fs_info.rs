@@ -0,0 +1,36 @@
+/// The FAT32 FSInfo sector, which only caches hints that must be checked before being trusted.
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct FsInfo {
+    pub lead_sig: u32,
+    reserved1: [u8; 480],
+    pub struc_sig: u32,
+    pub free_count: u32,
+    pub nxt_free: u32,
+    reserved2: [u8; 12],
+    pub trail_sig: u32,
+}
+
+const _: () = assert!(size_of::<FsInfo>() == 512);
+
+pub const UNKNOWN: u32 = 0xFFFF_FFFF;
+
+const LEAD_SIG: u32 = 0x4161_5252;
+const STRUC_SIG: u32 = 0x6141_7272;
+const TRAIL_SIG: u32 = 0xAA55_0000;
+
+impl FsInfo {
+    pub fn from_bytes(bytes: [u8; 512]) -> FsInfo {
+        // SAFETY: FSInfo is plain old data.
+        unsafe { core::mem::transmute(bytes) }
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 512] {
+        // SAFETY: FSInfo is plain old data.
+        unsafe { &*(self as *const FsInfo as *const [u8; 512]) }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.lead_sig == LEAD_SIG && self.struc_sig == STRUC_SIG && self.trail_sig == TRAIL_SIG
+    }
+}
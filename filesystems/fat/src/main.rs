@@ -7,29 +7,79 @@ extern crate alloc;
 
 mod bpb;
 mod directory;
+mod fs_info;
 
 use crate::Type::*;
 use crate::bpb::Bpb;
-use crate::directory::{DirectoryEntry, coalesce_long_names, to_short_name};
+use crate::directory::{
+    ATTR_ARCHIVE, DOT, DOT_DOT, DirectoryEntry, ShortNameDirectoryEntry, Timestamp,
+    coalesce_long_names, is_valid_long_name, long_name_entries, needs_long_name, short_alias,
+    to_short_name,
+};
+use crate::fs_info::FsInfo;
 use alloc::borrow::Cow;
 use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
 use alloc::vec::Vec;
 use core::marker::ConstParamTy;
 use core::ops::Range;
 use deravel_kernel_api::*;
 use log::*;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 enum Directory {
     Normal { cluster: u32 },
     RootDirectoryRegion,
 }
 
 struct Fat<const TYPE: Type> {
-    drive: Capability<Drive>,
+    drive: CachedDrive,
     bpb: Bpb,
-    fat: &'static PageAligned<[u8]>,
-    rdr: &'static [DirectoryEntry],
+    fat_mapping: Capability<DriveMapping>,
+    fat: &'static mut PageAligned<[u8]>,
+    rdr_mapping: Option<Capability<DriveMapping>>,
+    rdr: &'static mut [DirectoryEntry],
+    fs_info: Option<FsInfo>,
+    next_free: u32,
+    // Indexed by handles. Closed slots are reused with the next generation, so handles to closed
+    // files can't refer to the new one.
+    open_files: Vec<OpenFileSlot>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Handle {
+    index: usize,
+    generation: u32,
+}
+
+#[derive(Default)]
+struct OpenFileSlot {
+    generation: u32,
+    file: Option<OpenFile>,
+}
+
+struct OpenFile {
+    file: Located,
+    cursor: Option<Cursor>,
+}
+
+/// The last cluster accessed through a handle, so sequential reads don't walk the chain from the
+/// start every time.
+#[derive(Clone, Copy)]
+struct Cursor {
+    first: u32,
+    index: usize,
+    cluster: u32,
+}
+
+/// A file found in a directory, with the indices of its long name entries and its short entry.
+#[derive(Clone)]
+struct Located {
+    dir: Directory,
+    entries: Range<usize>,
+    short: ShortNameDirectoryEntry,
+    long_name: Option<String>,
 }
 
 #[derive(Clone, ConstParamTy, Copy, Debug, Eq, PartialEq)]
@@ -41,106 +91,712 @@ enum Type {
 
 const DISK_SECTOR_SIZE: usize = 512;
 
+// 1 MiB of cache, and 32 KiB read ahead, which covers the next clusters when they're contiguous.
+const CACHE_SECTORS: usize = 2048;
+const READ_AHEAD_SECTORS: u64 = 64;
+
 impl<const TYPE: Type> Fat<TYPE> {
-    fn traverse_path(&self, mut dir: Directory, mut path: &str) -> (u32, u32) {
-        'segments: loop {
-            let (path_seg, path_tail) = match path.split_once('/') {
-                Some((path_seg, path_tail)) => (path_seg, Some(path_tail)),
-                None => (path, None),
-            };
-            let short_needle = to_short_name(path_seg);
-            for (de, long_name) in coalesce_long_names(&self.read_directory(dir)) {
-                if de.name[0] == 0xE5 {
-                    continue;
-                }
-                if de.name[0] == 0x00 {
-                    break;
-                }
-                let name_matches = if let Some(long_name) = long_name {
-                    long_name == path_seg
-                } else {
-                    short_needle == Some(de.name)
-                };
-                if name_matches {
-                    let de_cluster = de.fst_clus();
-                    let Some(path_tail) = path_tail else {
-                        return (de_cluster, de.file_size);
-                    };
-                    dir = Directory::Normal {
-                        cluster: de_cluster,
-                    };
-                    path = path_tail;
-                    continue 'segments;
-                }
+    fn traverse_path(&self, dir: Directory, path: &str) -> Result<Located, FsError> {
+        let (dir, name) = self.traverse_parent(dir, path)?;
+        self.find_entry(dir, name)?.ok_or(FsError::NotFound)
+    }
+
+    /// Finds the directory at the path, where an empty path is the directory itself.
+    fn traverse_directory(&self, dir: Directory, path: &str) -> Result<Directory, FsError> {
+        if path.is_empty() {
+            return Ok(dir);
+        }
+        let subdirectory = self.traverse_path(dir, path)?.short;
+        if !subdirectory.is_directory() {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(self.subdirectory(&subdirectory))
+    }
+
+    fn traverse_file(&self, dir: Directory, path: &str) -> Result<Located, FsError> {
+        let file = self.traverse_path(dir, path)?;
+        if file.short.is_directory() {
+            return Err(FsError::IsADirectory);
+        }
+        Ok(file)
+    }
+
+    /// Finds the directory containing the last path segment, which is returned as well.
+    fn traverse_parent<'a>(
+        &self,
+        mut dir: Directory,
+        path: &'a str,
+    ) -> Result<(Directory, &'a str), FsError> {
+        let Some((parent, name)) = path.rsplit_once('/') else {
+            return Ok((dir, path));
+        };
+        for path_seg in parent.split('/') {
+            let de = self
+                .find_entry(dir, path_seg)?
+                .ok_or(FsError::NotFound)?
+                .short;
+            if !de.is_directory() {
+                return Err(FsError::NotADirectory);
             }
-            panic!("file not found")
+            dir = self.subdirectory(&de);
         }
+        Ok((dir, name))
     }
 
-    fn read_directory(&self, dir: Directory) -> Cow<'_, [DirectoryEntry]> {
-        match dir {
-            Directory::Normal { cluster } => Cow::Owned(self.read_normal_directory(cluster)),
-            Directory::RootDirectoryRegion => Cow::Borrowed(self.read_root_directory_region()),
+    fn find_entry(&self, dir: Directory, name: &str) -> Result<Option<Located>, FsError> {
+        let short_needle = to_short_name(name);
+        for (entries, de, long_name) in coalesce_long_names(&self.read_directory(dir)?) {
+            if de.is_deleted() {
+                continue;
+            }
+            if de.is_end_of_directory() {
+                break;
+            }
+            let name_matches = long_name
+                .as_ref()
+                .is_some_and(|long_name| long_name.eq_ignore_ascii_case(name))
+                || short_needle == Some(de.name);
+            if name_matches {
+                return Ok(Some(Located {
+                    dir,
+                    entries,
+                    short: *de,
+                    long_name,
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    fn subdirectory(&self, de: &ShortNameDirectoryEntry) -> Directory {
+        // The .. entries of top-level directories point at the root with cluster 0.
+        match de.fst_clus() {
+            0 => self.root_directory(),
+            cluster => Directory::Normal { cluster },
+        }
+    }
+
+    fn root_directory(&self) -> Directory {
+        match TYPE {
+            Fat12 | Fat16 => Directory::RootDirectoryRegion,
+            Fat32 => Directory::Normal {
+                cluster: self.bpb.as_extended_32().root_clus,
+            },
+        }
+    }
+
+    fn list_directory(&self, dir: Directory) -> Result<Vec<FileInfo>, FsError> {
+        let mut files = Vec::new();
+        for (_, de, long_name) in coalesce_long_names(&self.read_directory(dir)?) {
+            if de.is_end_of_directory() {
+                break;
+            }
+            if de.is_deleted() || de.is_volume_label() || de.is_dot() {
+                continue;
+            }
+            files.push(file_info(de, long_name));
         }
+        Ok(files)
     }
 
-    fn read_normal_directory(&self, cluster: u32) -> Vec<DirectoryEntry> {
-        directory_bytes_to_entries(self.read_file(cluster, None))
+    fn read_directory(&self, dir: Directory) -> Result<Cow<'_, [DirectoryEntry]>, FsError> {
+        Ok(match dir {
+            Directory::Normal { cluster } => Cow::Owned(self.read_normal_directory(cluster)?),
+            Directory::RootDirectoryRegion => Cow::Borrowed(self.read_root_directory_region()),
+        })
+    }
+
+    fn read_normal_directory(&self, cluster: u32) -> Result<Vec<DirectoryEntry>, FsError> {
+        Ok(directory_bytes_to_entries(
+            self.read_cluster_chain(cluster)?,
+        ))
     }
 
     fn read_root_directory_region(&self) -> &[DirectoryEntry] {
         self.rdr
     }
 
-    fn read_file(&self, cluster: u32, size: Option<usize>) -> Vec<u8> {
+    fn read_cluster_chain(&self, cluster: u32) -> Result<Vec<u8>, FsError> {
         let mut data = Vec::new();
-        if let Some(size) = size {
-            data.reserve_exact(size.next_multiple_of(DISK_SECTOR_SIZE));
-        }
-        'walk: for cluster in self.walk_clusters(cluster) {
-            for sector in self.sectors_of_cluster(cluster) {
+        for cluster in self.walk_clusters(cluster) {
+            for sector in self.sectors_of_cluster(cluster?) {
                 for disk_sector in self.drive_sectors_of_sector(sector) {
                     data.extend_from_slice(&self.drive.read(disk_sector));
-                    if let Some(size) = size
-                        && data.len() >= size
-                    {
-                        break 'walk;
-                    }
                 }
             }
         }
-        if let Some(size) = size {
-            data.resize(size, 0);
+        Ok(data)
+    }
+
+    fn read_file_into(
+        &self,
+        file: &ShortNameDirectoryEntry,
+        buf: &mut [u8],
+    ) -> Result<(), FsError> {
+        let mut clusters = self.walk_clusters(file.fst_clus());
+        for chunk in buf.chunks_mut(self.cluster_size()) {
+            // A chain shorter than the file size says is as corrupted as a broken one.
+            let cluster = clusters.next().ok_or(FsError::Corrupted)??;
+            // Whole files skip the cache, so reads into lent buffers need no copy.
+            self.drive
+                .read_uncached(self.first_disk_sector(cluster), chunk);
+        }
+        Ok(())
+    }
+
+    /// Writes the data at the offset and ends the file right after it, allocating or freeing
+    /// clusters as needed.
+    fn write_file_at(
+        &mut self,
+        mut file: Located,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<(), FsError> {
+        let size = offset + data.len();
+        let file_size = u32::try_from(size).map_err(|_| FsError::NoSpace)?;
+        let first = self.resize_chain(file.short.fst_clus(), size.div_ceil(self.cluster_size()))?;
+        self.write_clusters(first, offset, data)?;
+        file.short.set_fst_clus(first);
+        file.short.file_size = file_size;
+        file.short.touch(self.now());
+        self.store_short_entry(&file)
+    }
+
+    /// Truncates or extends the file, filling the new part with zeroes.
+    fn set_file_size(&mut self, file: &mut Located, size: usize) -> Result<(), FsError> {
+        let old_size = file.short.file_size as usize;
+        let file_size = u32::try_from(size).map_err(|_| FsError::NoSpace)?;
+        let first = self.resize_chain(file.short.fst_clus(), size.div_ceil(self.cluster_size()))?;
+        if size > old_size {
+            self.write_clusters(first, old_size, &vec![0; size - old_size])?;
+        }
+        file.short.set_fst_clus(first);
+        file.short.file_size = file_size;
+        file.short.touch(self.now());
+        self.store_short_entry(file)
+    }
+
+    fn store_short_entry(&mut self, file: &Located) -> Result<(), FsError> {
+        let short = DirectoryEntry::short(file.short);
+        self.write_directory_entries(file.dir, file.entries.end - 1, &[short])
+    }
+
+    /// Returns the file behind the handle, with its short entry read again as the file could have
+    /// been changed through a path since.
+    fn open_file(&self, handle: Handle) -> Result<Located, FsError> {
+        let open_file = self.open_file_slot(handle).ok_or(FsError::NotFound)?;
+        let mut file = open_file.file.clone();
+        let entry = self.read_directory_entry(file.dir, file.entries.end - 1)?;
+        let short = entry.as_short();
+        if short.is_deleted() || short.is_end_of_directory() || short.name != file.short.name {
+            return Err(FsError::NotFound);
+        }
+        file.short = *short;
+        Ok(file)
+    }
+
+    fn open_file_slot(&self, handle: Handle) -> Option<&OpenFile> {
+        let slot = &self.open_files[handle.index];
+        if slot.generation != handle.generation {
+            return None;
         }
-        data
+        slot.file.as_ref()
     }
 
-    fn walk_clusters(&self, cluster: u32) -> impl Iterator<Item = u32> {
-        core::iter::successors(Some(cluster), move |&cluster| {
-            let fat_entry = self.read_fat_entry(cluster);
-            match (TYPE, fat_entry & ((!0) >> 4)) {
-                (Fat12, next_cluster @ 0x002..)
-                | (Fat16, next_cluster @ 0x0002..)
-                | (Fat32, next_cluster @ 0x000_0002..)
-                    if next_cluster <= self.max_cluster() =>
-                {
-                    Some(next_cluster)
+    fn open_file_slot_mut(&mut self, handle: Handle) -> Option<&mut OpenFile> {
+        let slot = &mut self.open_files[handle.index];
+        if slot.generation != handle.generation {
+            return None;
+        }
+        slot.file.as_mut()
+    }
+
+    /// Finds the cluster at the index in the chain, starting from the handle's cursor if it's not
+    /// past it.
+    fn seek_cluster(&self, handle: Handle, first: u32, index: usize) -> Result<u32, FsError> {
+        let (start_index, start_cluster) = match self.open_file_slot(handle).unwrap().cursor {
+            Some(cursor) if cursor.first == first && cursor.index <= index => {
+                (cursor.index, cursor.cluster)
+            }
+            _ => (0, first),
+        };
+        self.walk_clusters(start_cluster)
+            .nth(index - start_index)
+            .ok_or(FsError::Corrupted)?
+    }
+
+    fn read_file_range(
+        &mut self,
+        handle: Handle,
+        file: &Located,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<(), FsError> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let first = file.short.fst_clus();
+        let cluster_size = self.cluster_size();
+        let mut index = offset / cluster_size;
+        let mut cluster = self.seek_cluster(handle, first, index)?;
+        let mut position = offset;
+        let mut filled = 0;
+        loop {
+            let within_cluster = position % cluster_size;
+            let length = (cluster_size - within_cluster).min(buf.len() - filled);
+            self.read_cluster_range(cluster, within_cluster, &mut buf[filled..][..length]);
+            filled += length;
+            position += length;
+            if filled == buf.len() {
+                break;
+            }
+            cluster = self
+                .walk_clusters(cluster)
+                .nth(1)
+                .ok_or(FsError::Corrupted)??;
+            index += 1;
+        }
+        self.open_file_slot_mut(handle).unwrap().cursor = Some(Cursor {
+            first,
+            index,
+            cluster,
+        });
+        Ok(())
+    }
+
+    fn read_cluster_range(&self, cluster: u32, offset: usize, buf: &mut [u8]) {
+        let disk_sector = self.first_disk_sector(cluster) + (offset / DISK_SECTOR_SIZE) as u64;
+        let unaligned = offset % DISK_SECTOR_SIZE;
+        if unaligned == 0 {
+            self.drive.read_into(disk_sector, buf);
+        } else {
+            let mut sectors = vec![0; unaligned + buf.len()];
+            self.drive.read_into(disk_sector, &mut sectors);
+            buf.copy_from_slice(&sectors[unaligned..]);
+        }
+    }
+
+    fn create_file(&mut self, dir: Directory, name: &str, data: &[u8]) -> Result<(), FsError> {
+        let file_size = u32::try_from(data.len()).map_err(|_| FsError::NoSpace)?;
+        let mut entries = self.new_entries(dir, name, ATTR_ARCHIVE)?;
+        let first = self.resize_chain(0, data.len().div_ceil(self.cluster_size()))?;
+        let short = entries.last_mut().unwrap().as_short_mut();
+        short.set_fst_clus(first);
+        short.file_size = file_size;
+        let result = self
+            .write_clusters(first, 0, data)
+            .and_then(|()| self.insert_entries(dir, &entries));
+        if let Err(err) = result {
+            self.discard_chain(first);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    fn create_directory(&mut self, parent: Directory, name: &str) -> Result<(), FsError> {
+        let mut entries = self.new_entries(parent, name, ATTR_DIRECTORY)?;
+        let cluster = self.resize_chain(0, 1)?;
+        self.zero_cluster(cluster);
+        let short = entries.last_mut().unwrap().as_short_mut();
+        short.set_fst_clus(cluster);
+        let mut dot = ShortNameDirectoryEntry::new(DOT, ATTR_DIRECTORY, self.now());
+        dot.set_fst_clus(cluster);
+        let mut dot_dot = ShortNameDirectoryEntry::new(DOT_DOT, ATTR_DIRECTORY, self.now());
+        dot_dot.set_fst_clus(self.cluster_for_dot_dot(parent));
+        let dots = [DirectoryEntry::short(dot), DirectoryEntry::short(dot_dot)];
+        let result = self
+            .write_directory_entries(Directory::Normal { cluster }, 0, &dots)
+            .and_then(|()| self.insert_entries(parent, &entries));
+        if let Err(err) = result {
+            self.discard_chain(cluster);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Frees the chain of a file that couldn't be created. Failing to only leaks the clusters, so
+    /// the caller reports what stopped the creation instead.
+    fn discard_chain(&mut self, first: u32) {
+        if let Err(err) = self.resize_chain(first, 0) {
+            warn!("leaking the chain at cluster {first}: {err}");
+        }
+    }
+
+    /// Moves the file to a new name, possibly in another directory, keeping its cluster chain.
+    fn move_entries(
+        &mut self,
+        file: Located,
+        parent: Directory,
+        name: &str,
+    ) -> Result<(), FsError> {
+        let mut entries = self.new_entries(parent, name, file.short.attr)?;
+        let short = entries.last_mut().unwrap().as_short_mut();
+        let alias = short.name;
+        *short = file.short;
+        short.name = alias;
+        // The new entries are written before the old ones are deleted, so the file isn't lost if the
+        // directory can't grow.
+        let index = self.insert_entries(parent, &entries)?;
+        self.delete_entries(&file)?;
+        // Handles find their file by its entries, so they have to follow it to the new ones.
+        for open_file in self
+            .open_files
+            .iter_mut()
+            .filter_map(|slot| slot.file.as_mut())
+        {
+            if (open_file.file.dir, &open_file.file.entries) == (file.dir, &file.entries) {
+                open_file.file.dir = parent;
+                open_file.file.entries = index..index + entries.len();
+                open_file.file.short.name = alias;
+            }
+        }
+        if file.short.is_directory() && file.dir != parent {
+            let cluster = file.short.fst_clus();
+            let mut dot_dot = *self.read_normal_directory(cluster)?[1].as_short();
+            dot_dot.set_fst_clus(self.cluster_for_dot_dot(parent));
+            let dot_dot = DirectoryEntry::short(dot_dot);
+            self.write_directory_entries(Directory::Normal { cluster }, 1, &[dot_dot])?;
+        }
+        Ok(())
+    }
+
+    fn insert_entries(
+        &mut self,
+        dir: Directory,
+        entries: &[DirectoryEntry],
+    ) -> Result<usize, FsError> {
+        let index = self.allocate_directory_entries(dir, entries.len())?;
+        self.write_directory_entries(dir, index, entries)?;
+        Ok(index)
+    }
+
+    fn is_empty_directory(&self, dir: Directory) -> Result<bool, FsError> {
+        Ok(coalesce_long_names(&self.read_directory(dir)?)
+            .take_while(|(_, de, _)| !de.is_end_of_directory())
+            .all(|(_, de, _)| de.is_deleted() || de.is_dot()))
+    }
+
+    /// Checks whether the directory is the ancestor or the same as the other one, by following the
+    /// .. entries up to the root.
+    fn is_ancestor(&self, ancestor: Directory, mut dir: Directory) -> Result<bool, FsError> {
+        loop {
+            if dir == ancestor {
+                return Ok(true);
+            }
+            let Directory::Normal { cluster } = dir else {
+                return Ok(false);
+            };
+            if dir == self.root_directory() {
+                return Ok(false);
+            }
+            dir = self.subdirectory(self.read_normal_directory(cluster)?[1].as_short());
+        }
+    }
+
+    // The .. entries point at the root directory with cluster 0, even on FAT32 where it has one.
+    fn cluster_for_dot_dot(&self, parent: Directory) -> u32 {
+        match parent {
+            Directory::Normal { cluster } if parent != self.root_directory() => cluster,
+            _ => 0,
+        }
+    }
+
+    fn delete_entries(&mut self, file: &Located) -> Result<(), FsError> {
+        let mut entries = self.read_directory(file.dir)?[file.entries.clone()].to_vec();
+        for de in &mut entries {
+            de.mark_deleted();
+        }
+        self.write_directory_entries(file.dir, file.entries.start, &entries)
+    }
+
+    /// Builds the short entry for a new file, preceded by long name entries if the name doesn't fit
+    /// in a short one.
+    fn new_entries(
+        &self,
+        dir: Directory,
+        name: &str,
+        attr: u8,
+    ) -> Result<Vec<DirectoryEntry>, FsError> {
+        if !needs_long_name(name) {
+            let short =
+                ShortNameDirectoryEntry::new(to_short_name(name).unwrap(), attr, self.now());
+            return Ok(vec![DirectoryEntry::short(short)]);
+        }
+        if !is_valid_long_name(name) {
+            return Err(FsError::InvalidName);
+        }
+        let existing = self.read_directory(dir)?;
+        let taken = |alias: &[u8; 11]| {
+            coalesce_long_names(&existing)
+                .take_while(|(_, de, _)| !de.is_end_of_directory())
+                .any(|(_, de, _)| !de.is_deleted() && de.name == *alias)
+        };
+        let alias = short_alias(name, taken).ok_or(FsError::NoSpace)?;
+        let mut entries = long_name_entries(name, &alias);
+        entries.push(DirectoryEntry::short(ShortNameDirectoryEntry::new(
+            alias,
+            attr,
+            self.now(),
+        )));
+        Ok(entries)
+    }
+
+    /// Finds consecutive free entries in the directory, growing it if it is not the fixed-size root
+    /// directory region.
+    fn allocate_directory_entries(
+        &mut self,
+        dir: Directory,
+        count: usize,
+    ) -> Result<usize, FsError> {
+        let entries = self.read_directory(dir)?;
+        let mut free_run = 0;
+        for (index, de) in entries.iter().enumerate() {
+            if !de.is_free() {
+                free_run = 0;
+                continue;
+            }
+            free_run += 1;
+            if free_run == count {
+                return Ok(index + 1 - count);
+            }
+        }
+        let Directory::Normal { cluster } = dir else {
+            return Err(FsError::NoSpace);
+        };
+        // Free entries at the end are continued by the ones in the new clusters.
+        let start = entries.len() - free_run;
+        let entries_per_cluster = self.cluster_size() / size_of::<DirectoryEntry>();
+        let extra_clusters = (count - free_run).div_ceil(entries_per_cluster);
+        let chain_length = self.walk_clusters(cluster).count();
+        self.resize_chain(cluster, chain_length + extra_clusters)?;
+        for cluster in self.walk_clusters(cluster).skip(chain_length) {
+            self.zero_cluster(cluster?);
+        }
+        Ok(start)
+    }
+
+    fn zero_cluster(&self, cluster: u32) {
+        self.drive.write_from(
+            self.first_disk_sector(cluster),
+            &vec![0; self.cluster_size()],
+        );
+    }
+
+    fn write_directory_entries(
+        &mut self,
+        dir: Directory,
+        first: usize,
+        entries: &[DirectoryEntry],
+    ) -> Result<(), FsError> {
+        let cluster = match dir {
+            Directory::Normal { cluster } => cluster,
+            Directory::RootDirectoryRegion => {
+                self.rdr[first..][..entries.len()].copy_from_slice(entries);
+                return Ok(());
+            }
+        };
+        let clusters: Vec<u32> = self.walk_clusters(cluster).collect::<Result<_, _>>()?;
+        for (index, de) in (first..).zip(entries) {
+            let (disk_sector, within_sector) = self.directory_entry_location(&clusters, index);
+            let mut sector = self.drive.read(disk_sector);
+            sector[within_sector..][..size_of::<DirectoryEntry>()].copy_from_slice(de.as_bytes());
+            self.drive.write(disk_sector, &sector);
+        }
+        Ok(())
+    }
+
+    fn read_directory_entry(
+        &self,
+        dir: Directory,
+        index: usize,
+    ) -> Result<DirectoryEntry, FsError> {
+        let cluster = match dir {
+            Directory::Normal { cluster } => cluster,
+            Directory::RootDirectoryRegion => return Ok(self.rdr[index]),
+        };
+        let clusters: Vec<u32> = self.walk_clusters(cluster).collect::<Result<_, _>>()?;
+        let (disk_sector, within_sector) = self.directory_entry_location(&clusters, index);
+        let sector = self.drive.read(disk_sector);
+        Ok(DirectoryEntry::from_bytes(
+            sector[within_sector..][..32].try_into().unwrap(),
+        ))
+    }
+
+    /// Returns the disk sector holding the directory entry, and the entry's offset within it.
+    fn directory_entry_location(&self, clusters: &[u32], index: usize) -> (u64, usize) {
+        let offset = index * size_of::<DirectoryEntry>();
+        let cluster = clusters[offset / self.cluster_size()];
+        let within_cluster = offset % self.cluster_size();
+        let disk_sector =
+            self.first_disk_sector(cluster) + (within_cluster / DISK_SECTOR_SIZE) as u64;
+        (disk_sector, within_cluster % DISK_SECTOR_SIZE)
+    }
+
+    fn write_clusters(&self, first: u32, offset: usize, data: &[u8]) -> Result<(), FsError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let cluster_size = self.cluster_size();
+        let clusters: Vec<u32> = self.walk_clusters(first).collect::<Result<_, _>>()?;
+        let end = offset + data.len();
+        let mut position = offset;
+        while position < end {
+            let cluster = clusters[position / cluster_size];
+            let within_cluster = position % cluster_size;
+            let length = (cluster_size - within_cluster).min(end - position);
+            let chunk = &data[position - offset..][..length];
+            // Drive writes start at a sector boundary, so the start of a partial sector is read back.
+            let unaligned = within_cluster % DISK_SECTOR_SIZE;
+            let disk_sector =
+                self.first_disk_sector(cluster) + (within_cluster / DISK_SECTOR_SIZE) as u64;
+            if unaligned == 0 {
+                self.drive.write_from(disk_sector, chunk);
+            } else {
+                let mut buf = self.drive.read(disk_sector);
+                buf.truncate(unaligned);
+                buf.extend_from_slice(chunk);
+                self.drive.write_from(disk_sector, &buf);
+            }
+            position += length;
+        }
+        Ok(())
+    }
+
+    /// Makes the chain starting at the cluster exactly `length` clusters long, and returns its first
+    /// cluster, which is 0 for an empty chain. Either all clusters are allocated, or none are.
+    fn resize_chain(&mut self, first: u32, length: usize) -> Result<u32, FsError> {
+        let mut chain: Vec<u32> = match first {
+            0 => Vec::new(),
+            first => self.walk_clusters(first).collect::<Result<_, _>>()?,
+        };
+        let original_length = chain.len();
+        for &cluster in chain.iter().skip(length) {
+            self.write_fat_entry(cluster, 0);
+            self.adjust_free_count(1);
+        }
+        if length < original_length {
+            // Cursors could point at the freed clusters, which might get reused by another file.
+            for open_file in self
+                .open_files
+                .iter_mut()
+                .filter_map(|slot| slot.file.as_mut())
+            {
+                open_file.cursor = None;
+            }
+        }
+        chain.truncate(length);
+        while chain.len() < length {
+            let cluster = match self.allocate_cluster() {
+                Ok(cluster) => cluster,
+                Err(err) => {
+                    // New clusters are linked after the original ones, so truncating the chain
+                    // back frees them, unless there was no chain to link them to.
+                    if first == 0 {
+                        for &cluster in &chain {
+                            self.write_fat_entry(cluster, 0);
+                            self.adjust_free_count(1);
+                        }
+                    } else if let Err(rollback_err) = self.resize_chain(first, original_length) {
+                        warn!("leaking clusters appended to cluster {first}: {rollback_err}");
+                    }
+                    return Err(err);
                 }
+            };
+            if let Some(&last) = chain.last() {
+                self.write_fat_entry(last, cluster);
+            }
+            chain.push(cluster);
+        }
+        if let Some(&last) = chain.last() {
+            self.write_fat_entry(last, self.end_of_chain());
+        }
+        Ok(chain.first().copied().unwrap_or(0))
+    }
+
+    fn allocate_cluster(&mut self) -> Result<u32, FsError> {
+        let count = self.count_of_clusters();
+        for offset in 0..count {
+            let cluster = 2 + (self.next_free - 2 + offset) % count;
+            if self.read_fat_entry(cluster) & ((!0) >> 4) == 0 {
+                self.write_fat_entry(cluster, self.end_of_chain());
+                self.adjust_free_count(-1);
+                self.next_free = 2 + (cluster - 1) % count;
+                return Ok(cluster);
+            }
+        }
+        Err(FsError::NoSpace)
+    }
+
+    fn adjust_free_count(&mut self, delta: i32) {
+        if let Some(fs_info) = &mut self.fs_info
+            && fs_info.free_count != fs_info::UNKNOWN
+        {
+            fs_info.free_count = fs_info.free_count.wrapping_add_signed(delta);
+        }
+    }
+
+    /// Writes everything changed by a request back to the drive.
+    fn flush(&mut self) {
+        if let Some(fs_info) = &mut self.fs_info {
+            fs_info.nxt_free = self.next_free;
+            let fs_info = *fs_info;
+            self.drive
+                .write(self.fs_info_disk_sector(), fs_info.as_bytes());
+        }
+        // Clusters are marked as allocated before anything points at them, so an interrupted flush
+        // can only leak them.
+        self.fat_mapping.sync();
+        self.drive.flush();
+        if let Some(rdr_mapping) = self.rdr_mapping {
+            rdr_mapping.sync();
+        }
+    }
+
+    fn read_fs_info(&self) -> Option<FsInfo> {
+        if TYPE != Fat32 {
+            return None;
+        }
+        let bytes = self.drive.read(self.fs_info_disk_sector());
+        let fs_info = FsInfo::from_bytes(*Box::try_from(bytes).unwrap());
+        fs_info.is_valid().then_some(fs_info)
+    }
+
+    fn fs_info_disk_sector(&self) -> u64 {
+        let sector = self.bpb.as_extended_32().fs_info as u32;
+        self.drive_sectors_of_sector(sector).next().unwrap()
+    }
+
+    fn now(&self) -> Timestamp {
+        Timestamp::from_unix(unix_time())
+    }
+
+    /// Follows the chain from the cluster. Free or bad clusters in it, and chains longer than the
+    /// volume, which can only loop, mean the volume is corrupted.
+    fn walk_clusters(&self, cluster: u32) -> impl Iterator<Item = Result<u32, FsError>> {
+        let mut next = Some(cluster);
+        let mut remaining = self.count_of_clusters();
+        core::iter::from_fn(move || {
+            let cluster = next.take()?;
+            if !(2..=self.max_cluster()).contains(&cluster) || remaining == 0 {
+                return Some(Err(FsError::Corrupted));
+            }
+            remaining -= 1;
+            next = match (TYPE, self.read_fat_entry(cluster) & ((!0) >> 4)) {
                 (Fat12, 0xFF8..=0xFFF)
                 | (Fat16, 0xFFF8..=0xFFFF)
                 | (Fat32, 0xFFF_FFF8..=0xFFF_FFFF) => None,
-                _ => unimplemented!("{:?} {:#x}", TYPE, fat_entry),
-            }
+                (_, next_cluster) => Some(next_cluster),
+            };
+            Some(Ok(cluster))
         })
     }
 
     fn read_fat_entry(&self, cluster: u32) -> u32 {
+        let fat = self.fat_copy(self.fat_copies().start);
+        let cluster = cluster as usize;
         match TYPE {
             Fat12 => {
-                let global_byte_index = cluster + cluster / 2;
-                let bytes: &[u8; 2] = self.fat.0[global_byte_index as usize..][..2]
-                    .try_into()
-                    .unwrap();
+                let bytes: &[u8; 2] = fat[cluster + cluster / 2..][..2].try_into().unwrap();
                 let value = u16::from_le_bytes(*bytes) as u32;
                 if cluster.is_multiple_of(2) {
                     value & 0x0FFF
@@ -148,17 +804,67 @@ impl<const TYPE: Type> Fat<TYPE> {
                     value >> 4
                 }
             }
-            Fat16 => self.fat_16()[cluster as usize] as u32,
-            Fat32 => self.fat_32()[cluster as usize],
+            Fat16 => u16::from_le_bytes(fat[2 * cluster..][..2].try_into().unwrap()) as u32,
+            Fat32 => u32::from_le_bytes(fat[4 * cluster..][..4].try_into().unwrap()),
         }
     }
 
-    fn fat_16(&self) -> &'static [u16] {
-        unsafe { &*PageAligned::cast(self.fat) }
+    fn write_fat_entry(&mut self, cluster: u32, value: u32) {
+        let fat_size = self.fat_size_bytes();
+        let cluster = cluster as usize;
+        for copy in self.fat_copies() {
+            let fat = &mut self.fat.0[copy * fat_size..][..fat_size];
+            match TYPE {
+                Fat12 => {
+                    let bytes: &mut [u8; 2] =
+                        (&mut fat[cluster + cluster / 2..][..2]).try_into().unwrap();
+                    let old = u16::from_le_bytes(*bytes);
+                    let new = if cluster.is_multiple_of(2) {
+                        (old & 0xF000) | value as u16
+                    } else {
+                        (old & 0x000F) | ((value as u16) << 4)
+                    };
+                    *bytes = new.to_le_bytes();
+                }
+                Fat16 => fat[2 * cluster..][..2].copy_from_slice(&(value as u16).to_le_bytes()),
+                Fat32 => {
+                    // The high 4 bits are reserved and have to be preserved.
+                    let bytes: &mut [u8; 4] = (&mut fat[4 * cluster..][..4]).try_into().unwrap();
+                    let old = u32::from_le_bytes(*bytes);
+                    *bytes = ((old & 0xF000_0000) | value).to_le_bytes();
+                }
+            }
+        }
     }
 
-    fn fat_32(&self) -> &'static [u32] {
-        unsafe { &*PageAligned::cast(self.fat) }
+    fn fat_copy(&self, copy: usize) -> &[u8] {
+        let fat_size = self.fat_size_bytes();
+        &self.fat.0[copy * fat_size..][..fat_size]
+    }
+
+    /// Returns the FATs that are kept up to date. FAT32 can disable mirroring, so only the active
+    /// FAT is used.
+    fn fat_copies(&self) -> Range<usize> {
+        if TYPE == Fat32 {
+            let ext_flags = self.bpb.as_extended_32().ext_flags;
+            if ext_flags & 0x80 != 0 {
+                let active = (ext_flags & 0xF) as usize;
+                return active..active + 1;
+            }
+        }
+        0..self.bpb.num_fats as usize
+    }
+
+    fn fat_size_bytes(&self) -> usize {
+        self.bpb.fat_size(TYPE) as usize * self.bpb.byts_per_sec as usize
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match TYPE {
+            Fat12 => 0xFFF,
+            Fat16 => 0xFFFF,
+            Fat32 => 0xFFF_FFFF,
+        }
     }
 
     fn sectors_of_cluster(&self, cluster: u32) -> impl Iterator<Item = u32> {
@@ -172,24 +878,17 @@ impl<const TYPE: Type> Fat<TYPE> {
         (0..ratio).map(move |i| ratio * sector as u64 + i)
     }
 
-    fn fat_sectors(&self) -> Range<u32> {
-        let start = self.bpb.rsvd_sec_cnt as u32;
-        let single_size = match TYPE {
-            Fat12 | Fat16 => self.bpb.fat_sz_16 as u32,
-            Fat32 => self.bpb.as_extended_32().fat_sz_32,
-        };
-        let total_size = self.bpb.num_fats as u32 * single_size;
-        start..start + total_size
+    fn first_disk_sector(&self, cluster: u32) -> u64 {
+        let first_sector = self.sectors_of_cluster(cluster).next().unwrap();
+        self.drive_sectors_of_sector(first_sector).next().unwrap()
     }
 
-    fn root_directory_sectors(&self) -> Range<u32> {
-        let start = self.fat_sectors().end;
-        let size = self.bpb.root_ent_cnt as u32 * 32 / self.bpb.byts_per_sec as u32;
-        start..start + size
+    fn cluster_size(&self) -> usize {
+        self.bpb.sec_per_clus as usize * self.bpb.byts_per_sec as usize
     }
 
     fn data_sectors(&self) -> Range<u32> {
-        let start = self.root_directory_sectors().end;
+        let start = self.bpb.root_directory_sectors(TYPE).end;
         let end = self.total_sectors_count();
         start..end
     }
@@ -228,26 +927,165 @@ impl<const TYPE: Type> Fat<TYPE> {
 }
 
 impl<const TYPE: Type> FilesystemServer<Directory> for Fat<TYPE> {
-    fn read(&mut self, _: &mut Ctx<Self>, dir: Directory, path: &str) -> Vec<u8> {
-        let (file, file_size) = self.traverse_path(dir, path);
-        self.read_file(file, Some(file_size as usize))
+    fn read(&mut self, _: &mut Ctx<Self>, dir: Directory, path: &str) -> Result<Vec<u8>, FsError> {
+        let file = self.traverse_file(dir, path)?.short;
+        let mut data = vec![0; file.file_size as usize];
+        self.read_file_into(&file, &mut data)?;
+        Ok(data)
     }
 
     fn read_large(
         &mut self,
-        ctx: &mut Ctx<Self>,
+        _: &mut Ctx<Self>,
+        dir: Directory,
+        path: &str,
+    ) -> Result<Capability<SharedMemory>, FsError> {
+        let file = self.traverse_file(dir, path)?.short;
+        let (shared, shared_cap) =
+            alloc_shared(file.file_size as usize).ok_or(FsError::OutOfMemory)?;
+        if let Err(err) = self.read_file_into(&file, unsafe { &mut (*shared).0 }) {
+            unsafe { free_shared(shared) };
+            release(shared_cap);
+            return Err(err);
+        }
+        Ok(shared_cap)
+    }
+
+    fn read_into(
+        &mut self,
+        _: &mut Ctx<Self>,
+        dir: Directory,
+        path: &str,
+        buf: &mut [u8],
+    ) -> Result<usize, FsError> {
+        let file = self.traverse_file(dir, path)?.short;
+        let len = buf.len().min(file.file_size as usize);
+        self.read_file_into(&file, &mut buf[..len])?;
+        Ok(len)
+    }
+
+    fn write(
+        &mut self,
+        _: &mut Ctx<Self>,
+        dir: Directory,
+        path: &str,
+        data: &[u8],
+    ) -> Result<(), FsError> {
+        let (dir, name) = self.traverse_parent(dir, path)?;
+        let result = match self.find_entry(dir, name)? {
+            Some(file) if file.short.is_directory() => Err(FsError::IsADirectory),
+            Some(file) => self.write_file_at(file, 0, data),
+            None => self.create_file(dir, name, data),
+        };
+        self.flush();
+        result
+    }
+
+    fn append(
+        &mut self,
+        _: &mut Ctx<Self>,
+        dir: Directory,
+        path: &str,
+        data: &[u8],
+    ) -> Result<(), FsError> {
+        let file = self.traverse_file(dir, path)?;
+        let offset = file.short.file_size as usize;
+        let result = self.write_file_at(file, offset, data);
+        self.flush();
+        result
+    }
+
+    fn remove(&mut self, _: &mut Ctx<Self>, dir: Directory, path: &str) -> Result<(), FsError> {
+        let file = self.traverse_file(dir, path)?;
+        self.resize_chain(file.short.fst_clus(), 0)?;
+        let result = self.delete_entries(&file);
+        self.flush();
+        result
+    }
+
+    fn list(
+        &mut self,
+        _: &mut Ctx<Self>,
         dir: Directory,
         path: &str,
-    ) -> Capability<SharedMemory> {
-        let (file, file_size) = self.traverse_path(dir, path);
-        let data = self.read_file(file, Some(file_size as usize));
-        let (shared, shared_cap) = alloc_shared(file_size as usize);
-        unsafe { &mut (*shared).0 }.copy_from_slice(&data);
-        ctx.forward_to_sender(shared_cap)
+    ) -> Result<Vec<FileInfo>, FsError> {
+        let dir = self.traverse_directory(dir, path)?;
+        self.list_directory(dir)
+    }
+
+    fn stat(&mut self, _: &mut Ctx<Self>, dir: Directory, path: &str) -> Result<FileInfo, FsError> {
+        let file = self.traverse_path(dir, path)?;
+        Ok(file_info(&file.short, file.long_name))
+    }
+
+    fn mkdir(&mut self, _: &mut Ctx<Self>, dir: Directory, path: &str) -> Result<(), FsError> {
+        let (parent, name) = self.traverse_parent(dir, path)?;
+        if self.find_entry(parent, name)?.is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+        let result = self.create_directory(parent, name);
+        self.flush();
+        result
     }
 
-    fn write(&mut self, _: &mut Ctx<Self>, _dir: Directory, _path: &str, _data: &[u8]) {
-        unimplemented!()
+    fn rmdir(&mut self, _: &mut Ctx<Self>, dir: Directory, path: &str) -> Result<(), FsError> {
+        let directory = self.traverse_path(dir, path)?;
+        if !directory.short.is_directory() {
+            return Err(FsError::NotADirectory);
+        }
+        if !self.is_empty_directory(self.subdirectory(&directory.short))? {
+            return Err(FsError::DirectoryNotEmpty);
+        }
+        self.resize_chain(directory.short.fst_clus(), 0)?;
+        let result = self.delete_entries(&directory);
+        self.flush();
+        result
+    }
+
+    fn rename(
+        &mut self,
+        _: &mut Ctx<Self>,
+        dir: Directory,
+        from: &str,
+        to: &str,
+    ) -> Result<(), FsError> {
+        let file = self.traverse_path(dir, from)?;
+        let (parent, name) = self.traverse_parent(dir, to)?;
+        // Renaming a file to a name differing only in case finds the file itself.
+        if let Some(existing) = self.find_entry(parent, name)?
+            && (existing.dir, existing.entries) != (file.dir, file.entries.clone())
+        {
+            return Err(FsError::AlreadyExists);
+        }
+        if file.short.is_directory() && self.is_ancestor(self.subdirectory(&file.short), parent)? {
+            return Err(FsError::InvalidMove);
+        }
+        let result = self.move_entries(file, parent, name);
+        self.flush();
+        result
+    }
+
+    fn open(
+        &mut self,
+        ctx: &mut Ctx<Self>,
+        dir: Directory,
+        path: &str,
+    ) -> Result<Capability<File>, FsError> {
+        let file = self.traverse_file(dir, path)?;
+        let index = match self.open_files.iter().position(|slot| slot.file.is_none()) {
+            Some(index) => index,
+            None => {
+                self.open_files.push(OpenFileSlot::default());
+                self.open_files.len() - 1
+            }
+        };
+        let slot = &mut self.open_files[index];
+        slot.file = Some(OpenFile { file, cursor: None });
+        let handle = Handle {
+            index,
+            generation: slot.generation,
+        };
+        Ok(ctx.grant(handle))
     }
 
     fn subcapability(
@@ -255,10 +1093,79 @@ impl<const TYPE: Type> FilesystemServer<Directory> for Fat<TYPE> {
         ctx: &mut Ctx<Self>,
         dir: Directory,
         path: &str,
-    ) -> Capability<Filesystem> {
-        ctx.grant_to_sender(Directory::Normal {
-            cluster: self.traverse_path(dir, path).0,
-        })
+    ) -> Result<Capability<Filesystem>, FsError> {
+        let subdirectory = self.traverse_path(dir, path)?.short;
+        if !subdirectory.is_directory() {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(ctx.grant(self.subdirectory(&subdirectory)))
+    }
+}
+
+impl<const TYPE: Type> FileServer<Handle> for Fat<TYPE> {
+    fn read_at(
+        &mut self,
+        _: &mut Ctx<Self>,
+        handle: Handle,
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>, FsError> {
+        let file = self.open_file(handle)?;
+        let size = file.short.file_size as u64;
+        let offset = offset.min(size);
+        let len = len.min(size - offset);
+        let mut data = vec![0; len as usize];
+        self.read_file_range(handle, &file, offset as usize, &mut data)?;
+        Ok(data)
+    }
+
+    fn write_at(
+        &mut self,
+        _: &mut Ctx<Self>,
+        handle: Handle,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<(), FsError> {
+        let mut file = self.open_file(handle)?;
+        let offset = usize::try_from(offset).map_err(|_| FsError::NoSpace)?;
+        // Checked once here, so the writes below can add the data length to the offset freely.
+        let end = offset.checked_add(data.len()).ok_or(FsError::NoSpace)?;
+        let size = file.short.file_size as usize;
+        let result = if end < size {
+            // Writes inside the file keep its size, so the chain stays the same.
+            self.write_clusters(file.short.fst_clus(), offset, data)
+                .and_then(|()| {
+                    file.short.touch(self.now());
+                    self.store_short_entry(&file)
+                })
+        } else if offset > size {
+            self.set_file_size(&mut file, offset)
+                .and_then(|()| self.write_file_at(file, offset, data))
+        } else {
+            self.write_file_at(file, offset, data)
+        };
+        self.flush();
+        result
+    }
+
+    fn truncate(&mut self, _: &mut Ctx<Self>, handle: Handle, size: u64) -> Result<(), FsError> {
+        let mut file = self.open_file(handle)?;
+        let size = usize::try_from(size).map_err(|_| FsError::NoSpace)?;
+        let result = self.set_file_size(&mut file, size);
+        self.flush();
+        result
+    }
+
+    fn size(&mut self, _: &mut Ctx<Self>, handle: Handle) -> Result<u64, FsError> {
+        Ok(self.open_file(handle)?.short.file_size as u64)
+    }
+
+    fn close(&mut self, _: &mut Ctx<Self>, handle: Handle) {
+        let slot = &mut self.open_files[handle.index];
+        if slot.generation == handle.generation {
+            slot.file = None;
+            slot.generation = slot.generation.wrapping_add(1);
+        }
     }
 }
 
@@ -275,35 +1182,36 @@ fn main(args: FatFsArgs) {
 }
 
 fn run<const TYPE: Type>(drive: Capability<Drive>, bpb: Bpb) {
+    let fat_mapping = map_sectors(drive, &bpb, bpb.fat_sectors(TYPE));
+    let fat = unsafe { &mut *map_shared(fat_mapping.memory()) };
+
+    let (rdr_mapping, rdr) = if TYPE == Fat12 || TYPE == Fat16 {
+        let rdr_mapping = map_sectors(drive, &bpb, bpb.root_directory_sectors(TYPE));
+        let rdr = map_shared(rdr_mapping.memory());
+        // The mapping is rounded up to whole pages, which must not be treated as entries.
+        let rdr = unsafe { &mut *PageAligned::cast_mut(rdr) };
+        let rdr = &mut rdr[..bpb.root_ent_cnt as usize];
+        (Some(rdr_mapping), rdr)
+    } else {
+        (None, &mut [][..])
+    };
+
     let mut server = Fat::<{ TYPE }> {
-        drive,
+        drive: CachedDrive::new(drive, CACHE_SECTORS, READ_AHEAD_SECTORS),
         bpb,
-        fat: &PageAligned([]),
-        rdr: &[],
+        fat_mapping,
+        fat,
+        rdr_mapping,
+        rdr,
+        fs_info: None,
+        next_free: 2,
+        open_files: Vec::new(),
     };
-
-    let disk_sector_offset = (server.fat_sectors().start as u64 * server.bpb.byts_per_sec as u64)
-        .div_exact(DISK_SECTOR_SIZE as u64)
-        .unwrap();
-    let disk_sector_count = ((server.fat_sectors().end - server.fat_sectors().start) as u64
-        * server.bpb.byts_per_sec as u64)
-        .div_exact(DISK_SECTOR_SIZE as u64)
-        .unwrap();
-    let fat = map_shared(drive.read_mapped(disk_sector_offset, disk_sector_count));
-    server.fat = unsafe { &*fat };
-
-    if TYPE == Fat12 || TYPE == Fat16 {
-        let disk_sector_offset = (server.root_directory_sectors().start as u64
-            * server.bpb.byts_per_sec as u64)
-            .div_exact(DISK_SECTOR_SIZE as u64)
-            .unwrap();
-        let disk_sector_count = ((server.root_directory_sectors().end
-            - server.root_directory_sectors().start) as u64
-            * server.bpb.byts_per_sec as u64)
-            .div_exact(DISK_SECTOR_SIZE as u64)
-            .unwrap();
-        let rdr = map_shared(drive.read_mapped(disk_sector_offset, disk_sector_count));
-        server.rdr = unsafe { &*PageAligned::cast(rdr) };
+    server.fs_info = server.read_fs_info();
+    if let Some(nxt_free) = server.fs_info.map(|fs_info| fs_info.nxt_free)
+        && (2..=server.max_cluster()).contains(&nxt_free)
+    {
+        server.next_free = nxt_free;
     }
 
     if let Some(volume_label) = server.volume_label() {
@@ -312,17 +1220,37 @@ fn run<const TYPE: Type>(drive: Capability<Drive>, bpb: Bpb) {
         info!("mounting unnamed FAT volume");
     }
 
-    let root_directory = match TYPE {
-        Fat12 | Fat16 => Directory::RootDirectoryRegion,
-        Fat32 => Directory::Normal {
-            cluster: server.bpb.as_extended_32().root_clus,
-        },
-    };
-
+    let root_directory = server.root_directory();
     let mut dispatch = Dispatch::new_object(server, root_directory);
     dispatch.run();
 }
 
+fn map_sectors(
+    drive: Capability<Drive>,
+    bpb: &Bpb,
+    sectors: Range<u32>,
+) -> Capability<DriveMapping> {
+    let to_disk_sectors = |sectors: u32| {
+        (sectors as u64 * bpb.byts_per_sec as u64)
+            .div_exact(DISK_SECTOR_SIZE as u64)
+            .unwrap()
+    };
+    drive.map(
+        to_disk_sectors(sectors.start),
+        to_disk_sectors(sectors.end - sectors.start),
+    )
+}
+
+fn file_info(de: &ShortNameDirectoryEntry, long_name: Option<String>) -> FileInfo {
+    FileInfo {
+        name: long_name.unwrap_or_else(|| de.short_name()),
+        size: de.file_size as u64,
+        attributes: de.attr,
+        created: de.created().to_unix(),
+        modified: de.modified().to_unix(),
+    }
+}
+
 fn directory_bytes_to_entries(bytes: Vec<u8>) -> Vec<DirectoryEntry> {
     let (ptr, length, capacity) = bytes.into_raw_parts();
     assert_eq!(length % size_of::<DirectoryEntry>(), 0);
@@ -1,9 +1,10 @@
 use deravel_codegen::RustTypeCtx::*;
 use deravel_codegen::parse::parse_drvli;
 use deravel_codegen::{
-    Drvli, Interface, InterfaceDetails, camel_case, rust_escape_name, split_syscall_arg,
-    split_syscall_ret,
+    Drvli, Interface, InterfaceDetails, Method, Type, camel_case, rust_escape_name,
+    split_syscall_arg, split_syscall_ret,
 };
+use std::borrow::Cow;
 use std::fmt::Write;
 
 fn main() {
@@ -22,6 +23,8 @@ fn main() {
             generate_spawner_impl(interface, &mut out);
         }
     }
+    generate_reply_values(&drvli, &mut out);
+    generate_call_failure_conversions(&drvli, &mut out);
     generate_syscalls(&drvli, &mut out);
     let out_path = format!("{}/drvli.rs", std::env::var("OUT_DIR").unwrap());
     std::fs::write(out_path, out).unwrap();
@@ -37,28 +40,70 @@ fn generate_client_impl(interface: &Interface, out: &mut String) {
     .unwrap();
     for (method_id, method) in interface.methods.iter().enumerate() {
         let name = &method.name;
-        write!(out, "    fn {name}(self").unwrap();
-        for (arg_name, arg_type) in &method.args {
-            let arg_type = arg_type.rust(Arg);
-            write!(out, ", {arg_name}: {arg_type}").unwrap();
+        let result_error = match &method.return_type {
+            Some(Type::Result(_, error)) => Some(error.rust(NormalRet)),
+            _ => None,
+        };
+        if result_error.is_none() {
+            // The infallible variant is kept for servers that can't fail, like the kernel.
+            write!(out, "    fn {name}(self").unwrap();
+            write_args(&method.args, out);
+            writeln!(out, "){} {{", return_arrow(method)).unwrap();
+            write!(out, "        match self.try_{name}(").unwrap();
+            for (arg_name, _) in &method.args {
+                write!(out, "{arg_name}, ").unwrap();
+            }
+            writeln!(out, ") {{").unwrap();
+            writeln!(out, "            Ok(result) => result,").unwrap();
+            writeln!(
+                out,
+                "            Err(failure) => panic!(\"{} server {{failure}} during {name}\"),",
+                interface.name
+            )
+            .unwrap();
+            writeln!(out, "        }}").unwrap();
+            writeln!(out, "    }}").unwrap();
+            write!(out, "    fn try_{name}(self").unwrap();
+        } else {
+            write!(out, "    fn {name}(self").unwrap();
         }
-        write!(out, ")").unwrap();
-        if let Some(return_type) = &method.return_type {
-            let return_type = return_type.rust(NormalRet);
-            write!(out, " -> {return_type}").unwrap();
+        write_args(&method.args, out);
+        writeln!(out, ") -> {} {{", fallible_return_type(method)).unwrap();
+        generate_transfers(method.args.iter().map(|(name, type_)| (*name, type_)), out);
+        write!(out, "        let data = postcard::to_allocvec(&(").unwrap();
+        for (arg_name, _) in method.args.iter().filter(|(_, type_)| !type_.is_loan()) {
+            write!(out, "{arg_name}, ",).unwrap();
         }
-        writeln!(out, " {{").unwrap();
-        writeln!(out, "        let mut data_buf = [0u8; 4096];").unwrap();
-        write!(out, "        let data_len = postcard::to_slice(&(").unwrap();
-        for (arg_name, _) in &method.args {
+        writeln!(out, ")).unwrap();").unwrap();
+        generate_loans(&method.args, out);
+        writeln!(
+            out,
+            "        let result = call(self.as_raw(), {method_id}, &data)?;"
+        )
+        .unwrap();
+        writeln!(out, "        {}", decode_reply(&result_error, "&result")).unwrap();
+        writeln!(out, "    }}").unwrap();
+        if !has_async_variant(method) {
+            continue;
+        }
+        write!(out, "    fn {name}_async(self").unwrap();
+        write_args(&method.args, out);
+        writeln!(out, ") -> Call<{}> {{", fallible_return_type(method)).unwrap();
+        generate_transfers(method.args.iter().map(|(name, type_)| (*name, type_)), out);
+        write!(out, "        let data = postcard::to_allocvec(&(").unwrap();
+        for (arg_name, _) in method.args.iter().filter(|(_, type_)| !type_.is_loan()) {
             write!(out, "{arg_name}, ",).unwrap();
         }
-        writeln!(out, "), &mut data_buf).unwrap().len();").unwrap();
-        writeln!(out, "        let mut buf = [0u8; 4096];").unwrap();
-        writeln!( out, "        let result_len = unsafe {{ syscall::ipc_call(self.as_raw(), {method_id}, data_buf.as_ptr(), data_len, buf.as_mut_ptr(), buf.len()) }};").unwrap();
+        writeln!(out, ")).unwrap();").unwrap();
+        writeln!(out, "        let call = unsafe {{ syscall::ipc_send(self.as_raw(), {method_id}, data.as_ptr(), data.len()) }};").unwrap();
+        let on_failure = match result_error {
+            Some(_) => "|failure| Err(failure.into())",
+            None => "Err",
+        };
         writeln!(
             out,
-            "        postcard::from_bytes(&buf[..result_len]).unwrap()"
+            "        Call::new(call, |reply| {}, {on_failure})",
+            decode_reply(&result_error, "reply")
         )
         .unwrap();
         writeln!(out, "    }}").unwrap();
@@ -92,17 +137,20 @@ fn generate_client_trait(interface: &Interface, out: &mut String) {
     writeln!(out, "pub trait {name_camel}Client {{").unwrap();
     for method in &interface.methods {
         let method_name = &method.name;
-        write!(out, "    fn {method_name}(self").unwrap();
-        for (arg_name, arg_type) in &method.args {
-            let arg_type = arg_type.rust(Arg);
-            write!(out, ", {arg_name}: {arg_type}").unwrap();
+        if !matches!(method.return_type, Some(Type::Result(..))) {
+            write!(out, "    fn try_{method_name}(self").unwrap();
+            write_args(&method.args, out);
+            writeln!(out, ") -> {};", fallible_return_type(method)).unwrap();
         }
-        write!(out, ")").unwrap();
-        if let Some(return_type) = &method.return_type {
-            let return_type = return_type.rust(NormalRet);
-            write!(out, " -> {return_type}").unwrap();
+        write!(out, "    fn {method_name}(self").unwrap();
+        write_args(&method.args, out);
+        writeln!(out, "){};", return_arrow(method)).unwrap();
+        if !has_async_variant(method) {
+            continue;
         }
-        writeln!(out, ";").unwrap();
+        write!(out, "    fn {method_name}_async(self").unwrap();
+        write_args(&method.args, out);
+        writeln!(out, ") -> Call<{}>;", fallible_return_type(method)).unwrap();
     }
     for stream in &interface.streams {
         let stream_name = &stream.name;
@@ -116,6 +164,43 @@ fn generate_client_trait(interface: &Interface, out: &mut String) {
     writeln!(out, "}}").unwrap();
 }
 
+// Structs and errors are passed by value in replies, without staging any transfers for the
+// capabilities in them, like in replies sent right away.
+// Structs can't hold capabilities and errors have no fields, so they have nothing to transfer.
+fn generate_reply_values(drvli: &Drvli, out: &mut String) {
+    let names = drvli.structs.iter().map(|struct_| struct_.name);
+    let names = names.chain(drvli.errors.iter().map(|error| error.name));
+    for name in names {
+        let name_camel = camel_case(name);
+        writeln!(out, "impl ReplyValue for {name_camel} {{").unwrap();
+        writeln!(
+            out,
+            "    fn stage_transfers(self, _: &mut Vec<usize>) -> Self {{ self }}"
+        )
+        .unwrap();
+        writeln!(out, "}}").unwrap();
+    }
+}
+
+fn generate_call_failure_conversions(drvli: &Drvli, out: &mut String) {
+    for error in &drvli.errors {
+        let name_camel = camel_case(error.name);
+        writeln!(out, "impl From<CallFailure> for {name_camel} {{").unwrap();
+        writeln!(out, "    fn from(failure: CallFailure) -> Self {{").unwrap();
+        writeln!(out, "        match failure {{").unwrap();
+        for variant in ["Disconnected", "TimedOut", "InvalidReply"] {
+            writeln!(
+                out,
+                "            CallFailure::{variant} => {name_camel}::{variant},"
+            )
+            .unwrap();
+        }
+        writeln!(out, "        }}").unwrap();
+        writeln!(out, "    }}").unwrap();
+        writeln!(out, "}}").unwrap();
+    }
+}
+
 fn generate_server_trait(interface: &Interface, out: &mut String) {
     let name_camel = camel_case(interface.name);
     writeln!(out, "pub trait {name_camel}Server<O = ()> {{").unwrap();
@@ -158,23 +243,29 @@ fn generate_server_handler_impl(interface: &Interface, out: &mut String) {
     .unwrap();
     writeln!(
         out,
-        "    fn call_method(&mut self, _ctx: &mut Ctx<Self>, method: usize, _args: &[u8], _object: O, _sender: ProcessId) -> ([u8; 4096], usize) {{"
+        "    fn call_method(&mut self, _ctx: &mut Ctx<Self>, method: usize, _args: &[u8], _object: O, _sender: ProcessId) -> Vec<u8> {{"
     )
     .unwrap();
     writeln!(out, "        match method {{").unwrap();
     for (method_index, method) in interface.methods.iter().enumerate() {
         let method_name = &method.name;
         writeln!(out, "            {method_index} => {{").unwrap();
+        let serialized_args: Vec<_> = method
+            .args
+            .iter()
+            .filter(|(_, type_)| !type_.is_loan())
+            .collect();
         write!(out, "                let (").unwrap();
-        for (arg_name, _) in &method.args {
+        for (arg_name, _) in &serialized_args {
             write!(out, "{arg_name},").unwrap();
         }
         write!(out, "): (").unwrap();
-        for (_, arg_type) in &method.args {
+        for (_, arg_type) in &serialized_args {
             let arg_type = arg_type.rust(NormalRet);
             write!(out, "{arg_type},").unwrap();
         }
         writeln!(out, ") = postcard::from_bytes(_args).unwrap();").unwrap();
+        generate_loan_slices(&method.args, out);
         write!(
             out,
             "                let result = self.{method_name}(_ctx, _object, "
@@ -185,9 +276,29 @@ fn generate_server_handler_impl(interface: &Interface, out: &mut String) {
             write!(out, "{borrow}{arg_name},").unwrap();
         }
         writeln!(out, ");").unwrap();
-        writeln!(out, "                let mut result_buf = [0u8; 4096];").unwrap();
-        writeln!(out, "                let result_len = postcard::to_slice(&result, &mut result_buf).unwrap().len();").unwrap();
-        writeln!(out, "                (result_buf, result_len)").unwrap();
+        let transfer_result = match &method.return_type {
+            Some(type_) if type_.is_typed_capability() => Some("transfer(result, &mut transfers)"),
+            Some(Type::Result(ok, _)) if ok.is_typed_capability() => {
+                Some("result.map(|result| transfer(result, &mut transfers))")
+            }
+            _ => None,
+        };
+        if let Some(transfer_result) = transfer_result {
+            // A deferred reply is sent later, so the placeholder must not stage any transfers.
+            writeln!(
+                out,
+                "                if _ctx.is_deferred() {{ return Vec::new(); }}"
+            )
+            .unwrap();
+            writeln!(out, "                let mut transfers = Vec::new();").unwrap();
+            writeln!(out, "                let result = {transfer_result};").unwrap();
+            writeln!(out, "                unsafe {{ syscall::ipc_transfer(transfers.as_ptr(), transfers.len()) }};").unwrap();
+        }
+        writeln!(
+            out,
+            "                postcard::to_allocvec(&result).unwrap()"
+        )
+        .unwrap();
         writeln!(out, "            }}").unwrap();
     }
     for (stream_index, stream) in interface.streams.iter().enumerate() {
@@ -196,12 +307,21 @@ fn generate_server_handler_impl(interface: &Interface, out: &mut String) {
         writeln!(out, "            {pseudo_method_index} => {{").unwrap();
         writeln!(
             out,
-            "                let result = self.{stream_name}(_ctx, _object);"
+            "                let (memory, size) = self.{stream_name}(_ctx, _object);"
+        )
+        .unwrap();
+        writeln!(out, "                let mut transfers = Vec::new();").unwrap();
+        writeln!(
+            out,
+            "                let result = (transfer(memory, &mut transfers), size);"
+        )
+        .unwrap();
+        writeln!(out, "                unsafe {{ syscall::ipc_transfer(transfers.as_ptr(), transfers.len()) }};").unwrap();
+        writeln!(
+            out,
+            "                postcard::to_allocvec(&result).unwrap()"
         )
         .unwrap();
-        writeln!(out, "                let mut result_buf = [0u8; 4096];").unwrap();
-        writeln!(out, "                let result_len = postcard::to_slice(&result, &mut result_buf).unwrap().len();").unwrap();
-        writeln!(out, "                (result_buf, result_len)").unwrap();
         writeln!(out, "            }}").unwrap();
     }
     writeln!(
@@ -214,6 +334,131 @@ fn generate_server_handler_impl(interface: &Interface, out: &mut String) {
     writeln!(out, "}}").unwrap();
 }
 
+fn has_async_variant(method: &Method) -> bool {
+    // Loans only last for the duration of a blocking call, so they can't outlive it in a future.
+    method.return_type != Some(Type::Never) && !method.args.iter().any(|(_, type_)| type_.is_loan())
+}
+
+fn return_arrow(method: &Method) -> String {
+    match &method.return_type {
+        Some(return_type) => format!(" -> {}", return_type.rust(NormalRet)),
+        None => String::new(),
+    }
+}
+
+fn plain_return_type(method: &Method) -> Cow<'static, str> {
+    match &method.return_type {
+        Some(return_type) => return_type.rust(NormalRet),
+        None => "()".into(),
+    }
+}
+
+// Methods returning a result report call failures through its error, others are wrapped in one.
+fn fallible_return_type(method: &Method) -> Cow<'static, str> {
+    match &method.return_type {
+        Some(return_type @ Type::Result(..)) => return_type.rust(NormalRet),
+        _ => format!("Result<{}, CallFailure>", plain_return_type(method)).into(),
+    }
+}
+
+// Decodes a reply, turning one that doesn't decode into an error instead of panicking.
+fn decode_reply(result_error: &Option<Cow<str>>, reply: &str) -> String {
+    match result_error {
+        Some(error) => {
+            format!("postcard::from_bytes({reply}).unwrap_or(Err({error}::InvalidReply))")
+        }
+        None => format!("postcard::from_bytes({reply}).map_err(|_| CallFailure::InvalidReply)"),
+    }
+}
+
+fn write_args(args: &[(&str, Type)], out: &mut String) {
+    for (arg_name, arg_type) in args {
+        let arg_type = arg_type.rust(Arg);
+        write!(out, ", {arg_name}: {arg_type}").unwrap();
+    }
+}
+
+fn generate_transfers<'a>(args: impl Iterator<Item = (&'a str, &'a Type<'a>)>, out: &mut String) {
+    let capabilities: Vec<_> = args
+        .filter(|(_, arg_type)| arg_type.contains_capability())
+        .collect();
+    if capabilities.is_empty() {
+        return;
+    }
+    writeln!(out, "        let mut transfers = Vec::new();").unwrap();
+    for (arg_name, arg_type) in capabilities {
+        if arg_type.is_typed_capability() {
+            writeln!(
+                out,
+                "        let {arg_name} = transfer({arg_name}, &mut transfers);"
+            )
+            .unwrap();
+        } else {
+            // Capabilities nested in options and the like are staged the same way as in replies.
+            writeln!(
+                out,
+                "        let {arg_name} = {arg_name}.stage_transfers(&mut transfers);"
+            )
+            .unwrap();
+        }
+    }
+    writeln!(
+        out,
+        "        unsafe {{ syscall::ipc_transfer(transfers.as_ptr(), transfers.len()) }};"
+    )
+    .unwrap();
+}
+
+fn generate_loans(args: &[(&str, Type)], out: &mut String) {
+    for (arg_name, arg_type) in args {
+        let writable = match arg_type {
+            Type::Loan => 0,
+            Type::MutLoan => 1,
+            _ => continue,
+        };
+        writeln!(
+            out,
+            "        unsafe {{ syscall::ipc_loan({arg_name}.as_ptr() as *mut u8, {arg_name}.len(), {writable}) }};"
+        )
+        .unwrap();
+    }
+}
+
+fn generate_loan_slices(args: &[(&str, Type)], out: &mut String) {
+    let loans: Vec<_> = args.iter().filter(|(_, type_)| type_.is_loan()).collect();
+    if loans.is_empty() {
+        return;
+    }
+    let loan_count = loans.len();
+    writeln!(
+        out,
+        "                let mut loans = [0usize; {}];",
+        2 * loan_count
+    )
+    .unwrap();
+    writeln!(out, "                let loan_count = unsafe {{ syscall::ipc_loans(_ctx.token(), loans.as_mut_ptr(), loans.len()) }};").unwrap();
+    // Clients lending the wrong number of buffers get an empty reply, which fails to decode on their
+    // side instead of taking the server down.
+    writeln!(
+        out,
+        "                if loan_count != {loan_count} {{ return Vec::new(); }}"
+    )
+    .unwrap();
+    for (loan_index, (arg_name, arg_type)) in loans.into_iter().enumerate() {
+        let from_raw_parts = match arg_type {
+            Type::MutLoan => "from_raw_parts_mut",
+            _ => "from_raw_parts",
+        };
+        writeln!(
+            out,
+            "                let {arg_name} = unsafe {{ core::slice::{from_raw_parts}(loans[{}] as *mut u8, loans[{}]) }};",
+            2 * loan_index,
+            2 * loan_index + 1
+        )
+        .unwrap();
+    }
+}
+
 fn generate_syscalls(drvli: &Drvli, out: &mut String) {
     writeln!(out, "pub mod syscall {{").unwrap();
     writeln!(out, "    #![allow(clippy::missing_safety_doc)]").unwrap();
@@ -322,7 +567,10 @@ fn generate_spawner_impl(interface: &Interface, out: &mut String) {
         "impl {name_camel}SpawnerClient for Capability<{name_camel}Spawner> {{"
     )
     .unwrap();
-    let InterfaceDetails::App { args, implements } = &interface.details else {
+    let InterfaceDetails::App {
+        args, implements, ..
+    } = &interface.details
+    else {
         unreachable!()
     };
     write!(out, "    fn spawn(self").unwrap();
@@ -333,34 +581,45 @@ fn generate_spawner_impl(interface: &Interface, out: &mut String) {
     write!(out, ")").unwrap();
     if let Some(implements) = implements {
         let implements = camel_case(implements);
-        write!(out, " -> Capability<{implements}>").unwrap();
+        write!(
+            out,
+            " -> Option<(Capability<{implements}>, Capability<ProcessHandle>)>"
+        )
+        .unwrap();
     } else {
-        write!(out, " -> Capability<{name_camel}>").unwrap();
+        write!(
+            out,
+            " -> Option<(Capability<{name_camel}>, Capability<ProcessHandle>)>"
+        )
+        .unwrap();
     }
     writeln!(out, " {{").unwrap();
-    writeln!(out, "        let mut data_buf = [0u8; 4096];").unwrap();
+    generate_transfers(args.iter().map(|(name, type_)| (*name, type_)), out);
     writeln!(
         out,
-        "        let data_len = postcard::to_slice(&{name_camel}Args {{"
+        "        let data = postcard::to_allocvec(&{name_camel}Args {{"
     )
     .unwrap();
     for (arg_name, _) in args {
         writeln!(out, "            {arg_name},",).unwrap();
     }
-    writeln!(out, "        }}, &mut data_buf).unwrap().len();").unwrap();
-    writeln!(out, "        let mut buf = [0u8; 4096];").unwrap();
-    writeln!(out, "        let result_len = unsafe {{ syscall::ipc_call(self.as_raw(), 0, data_buf.as_ptr(), data_len, buf.as_mut_ptr(), buf.len()) }};").unwrap();
+    writeln!(out, "        }}).unwrap();").unwrap();
     writeln!(
         out,
-        "        postcard::from_bytes(&buf[..result_len]).unwrap()"
+        "        let result = call(self.as_raw(), 0, &data).ok()?;"
     )
     .unwrap();
+    // The kernel replies with None when it has no process ID left for the new process.
+    writeln!(out, "        postcard::from_bytes(&result).ok().flatten()").unwrap();
     writeln!(out, "    }}").unwrap();
     writeln!(out, "}}").unwrap();
 }
 
 fn generate_spawner_trait(interface: &Interface, out: &mut String) {
-    let InterfaceDetails::App { args, implements } = &interface.details else {
+    let InterfaceDetails::App {
+        args, implements, ..
+    } = &interface.details
+    else {
         unreachable!()
     };
     let name_camel = camel_case(interface.name);
@@ -373,9 +632,17 @@ fn generate_spawner_trait(interface: &Interface, out: &mut String) {
     write!(out, ")").unwrap();
     if let Some(implements) = implements {
         let implements = camel_case(implements);
-        write!(out, " -> Capability<{implements}>").unwrap();
+        write!(
+            out,
+            " -> Option<(Capability<{implements}>, Capability<ProcessHandle>)>"
+        )
+        .unwrap();
     } else {
-        write!(out, " -> Capability<{name_camel}>").unwrap();
+        write!(
+            out,
+            " -> Option<(Capability<{name_camel}>, Capability<ProcessHandle>)>"
+        )
+        .unwrap();
     }
     writeln!(out, ";").unwrap();
     writeln!(out, "}}").unwrap();
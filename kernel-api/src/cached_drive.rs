@@ -0,0 +1,216 @@
+use crate::{Drive, DriveClient};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use deravel_types::Capability;
+
+const SECTOR_SIZE: usize = 512;
+
+/// A drive with a write-back cache of recently used sectors in front of it. Reads that miss the
+/// cache also fetch the following sectors, and writes only reach the drive when flushed.
+pub struct CachedDrive {
+    drive: Capability<Drive>,
+    capacity: u64,
+    read_ahead: u64,
+    max_blocks: usize,
+    cache: RefCell<Cache>,
+}
+
+#[derive(Default)]
+struct Cache {
+    blocks: BTreeMap<u64, Block>,
+    // Sectors ordered from the least recently used, keyed by the tick they were last used at.
+    lru: BTreeMap<u64, u64>,
+    tick: u64,
+}
+
+struct Block {
+    data: Box<[u8; SECTOR_SIZE]>,
+    dirty: bool,
+    last_used: u64,
+}
+
+impl CachedDrive {
+    pub fn new(drive: Capability<Drive>, max_blocks: usize, read_ahead: u64) -> CachedDrive {
+        assert!(max_blocks > 0);
+        CachedDrive {
+            drive,
+            capacity: drive.capacity(),
+            read_ahead,
+            max_blocks,
+            cache: RefCell::new(Cache::default()),
+        }
+    }
+
+    pub fn read(&self, sector: u64) -> Vec<u8> {
+        let mut buf = vec![0; SECTOR_SIZE];
+        self.read_into(sector, &mut buf);
+        buf
+    }
+
+    pub fn read_into(&self, first_sector: u64, buf: &mut [u8]) {
+        let sector_count = buf.len().div_ceil(SECTOR_SIZE) as u64;
+        let mut sector = first_sector;
+        while sector < first_sector + sector_count {
+            let chunk = &mut buf[(sector - first_sector) as usize * SECTOR_SIZE..];
+            if let Some(block) = self.cache.borrow_mut().touch(sector) {
+                let length = chunk.len().min(SECTOR_SIZE);
+                chunk[..length].copy_from_slice(&block.data[..length]);
+                sector += 1;
+                continue;
+            }
+            // Copied from what was read, as the cache may be too full of modified sectors to keep
+            // the fetched ones.
+            let fetched = self.fetch(sector, first_sector + sector_count);
+            let fetched = fetched.as_flattened();
+            let length = chunk.len().min(fetched.len());
+            chunk[..length].copy_from_slice(&fetched[..length]);
+            sector += length.div_ceil(SECTOR_SIZE) as u64;
+        }
+    }
+
+    /// Reads straight into the buffer without going through the cache, for large reads that
+    /// shouldn't pay for a copy. Modified sectors are still taken from the cache.
+    pub fn read_uncached(&self, first_sector: u64, buf: &mut [u8]) {
+        self.drive.read_into(first_sector, buf);
+        let sector_count = buf.len().div_ceil(SECTOR_SIZE) as u64;
+        let cache = self.cache.borrow();
+        let blocks = cache
+            .blocks
+            .range(first_sector..first_sector + sector_count);
+        for (&sector, block) in blocks.filter(|(_, block)| block.dirty) {
+            let chunk = &mut buf[(sector - first_sector) as usize * SECTOR_SIZE..];
+            let length = chunk.len().min(SECTOR_SIZE);
+            chunk[..length].copy_from_slice(&block.data[..length]);
+        }
+    }
+
+    pub fn write(&self, sector: u64, data: &[u8]) {
+        self.write_from(sector, data);
+    }
+
+    /// Writes the buffer starting at the sector. Like with the drive, the rest of a partially
+    /// written last sector is preserved.
+    pub fn write_from(&self, first_sector: u64, buf: &[u8]) {
+        for (sector, chunk) in (first_sector..).zip(buf.chunks(SECTOR_SIZE)) {
+            let mut data = [0; SECTOR_SIZE];
+            if chunk.len() < SECTOR_SIZE && !self.cache.borrow().blocks.contains_key(&sector) {
+                data = self.fetch(sector, sector + 1)[0];
+            }
+            let mut cache = self.cache.borrow_mut();
+            let block = cache.touch_or_insert(sector, || Box::new(data));
+            block.data[..chunk.len()].copy_from_slice(chunk);
+            block.dirty = true;
+            drop(cache);
+            self.evict_excess();
+        }
+    }
+
+    /// Writes every modified sector to the drive, merging neighbouring ones into single requests.
+    pub fn flush(&self) {
+        let mut cache = self.cache.borrow_mut();
+        let mut run_start = 0;
+        let mut run = Vec::new();
+        for (&sector, block) in cache.blocks.iter_mut().filter(|(_, block)| block.dirty) {
+            if !run.is_empty() && run_start + (run.len() / SECTOR_SIZE) as u64 != sector {
+                self.drive.write_from(run_start, &run);
+                run.clear();
+            }
+            if run.is_empty() {
+                run_start = sector;
+            }
+            run.extend_from_slice(&*block.data);
+            block.dirty = false;
+        }
+        if !run.is_empty() {
+            self.drive.write_from(run_start, &run);
+        }
+    }
+
+    // Reads the sector along with the ones after it, up to the end of the request and the
+    // read-ahead window, stopping early at sectors that are already cached. Returns the sectors
+    // read, starting with the one asked for.
+    fn fetch(&self, sector: u64, request_end: u64) -> Vec<[u8; SECTOR_SIZE]> {
+        // Reading more than the cache holds would evict the sectors read first.
+        let end = request_end
+            .max(sector + 1 + self.read_ahead)
+            .min(sector + self.max_blocks as u64)
+            .min(self.capacity);
+        let cache = self.cache.borrow();
+        let end = (sector + 1..end)
+            .find(|sector| cache.blocks.contains_key(sector))
+            .unwrap_or(end);
+        drop(cache);
+        let mut sectors = vec![[0; SECTOR_SIZE]; (end - sector) as usize];
+        self.drive.read_into(sector, sectors.as_flattened_mut());
+        let mut cache = self.cache.borrow_mut();
+        for (sector, data) in (sector..).zip(&sectors) {
+            cache.insert(sector, Box::new(*data));
+        }
+        drop(cache);
+        self.evict_excess();
+        sectors
+    }
+
+    // Only clean sectors are evicted, so that modified ones reach the drive in a single flush the
+    // caller orders against its other writes. Until then, the cache can grow past its limit.
+    fn evict_excess(&self) {
+        let mut cache = self.cache.borrow_mut();
+        let excess = cache.blocks.len().saturating_sub(self.max_blocks);
+        let evicted: Vec<_> = cache
+            .lru
+            .iter()
+            .filter(|(_, sector)| !cache.blocks[sector].dirty)
+            .map(|(&tick, &sector)| (tick, sector))
+            .take(excess)
+            .collect();
+        for (tick, sector) in evicted {
+            cache.lru.remove(&tick);
+            cache.blocks.remove(&sector);
+        }
+    }
+}
+
+impl Cache {
+    fn insert(&mut self, sector: u64, data: Box<[u8; SECTOR_SIZE]>) {
+        self.tick += 1;
+        let block = Block {
+            data,
+            dirty: false,
+            last_used: self.tick,
+        };
+        if let Some(old) = self.blocks.insert(sector, block) {
+            self.lru.remove(&old.last_used);
+        }
+        self.lru.insert(self.tick, sector);
+    }
+
+    fn touch_or_insert(
+        &mut self,
+        sector: u64,
+        data: impl FnOnce() -> Box<[u8; SECTOR_SIZE]>,
+    ) -> &mut Block {
+        self.tick += 1;
+        let tick = self.tick;
+        let block = self.blocks.entry(sector).or_insert_with(|| Block {
+            data: data(),
+            dirty: false,
+            last_used: tick,
+        });
+        self.lru.remove(&block.last_used);
+        self.lru.insert(tick, sector);
+        block.last_used = tick;
+        block
+    }
+
+    fn touch(&mut self, sector: u64) -> Option<&mut Block> {
+        let block = self.blocks.get_mut(&sector)?;
+        self.tick += 1;
+        self.lru.remove(&block.last_used);
+        self.lru.insert(self.tick, sector);
+        block.last_used = self.tick;
+        Some(block)
+    }
+}
@@ -1,13 +1,21 @@
-use crate::current_pid;
-use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::{current_pid, syscall};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use deravel_types::{
-    Actor, Capability, CapabilityCertificate, CapabilityCertificateValue, Interface, RawCapability,
-    get_capability_certificate_page,
+    ALL_RIGHTS, Actor, CAPABILITIES_PER_PAGE, Capability, CapabilityCertificate,
+    CapabilityCertificateValue, Interface, RawCapability, get_capability_certificate_page,
 };
 use log::*;
 
 pub(crate) static CAPABILITIES_ALLOCATED: AtomicUsize = AtomicUsize::new(1);
 
+// Revoked slots are reused round-robin, and only once no fresh ones are left, so that stale copies
+// of a revoked capability are unlikely to refer to a new one.
+static CAPABILITIES_FREE: [AtomicU64; CAPABILITIES_PER_PAGE / 64] =
+    [const { AtomicU64::new(0) }; _];
+
+static NEXT_FREE_CAPABILITY: AtomicUsize = AtomicUsize::new(0);
+
 pub(crate) fn grant_unhandled<T: Interface>(grantee: impl Into<Actor>) -> Capability<T> {
     let grantee = grantee.into();
     let certificate = allocate_certificate();
@@ -22,10 +30,20 @@ pub(crate) fn grant_unhandled<T: Interface>(grantee: impl Into<Actor>) -> Capabi
 }
 
 pub fn forward<T: Interface>(cap: Capability<T>, forwardee: impl Into<Actor>) -> Capability<T> {
+    forward_with_rights(cap, forwardee, ALL_RIGHTS)
+}
+
+/// Forwards a capability that only allows the methods and streams set in `rights`, using the
+/// constants generated on the interface type, such as `Filesystem::READ`.
+pub fn forward_with_rights<T: Interface>(
+    cap: Capability<T>,
+    forwardee: impl Into<Actor>,
+    rights: u16,
+) -> Capability<T> {
     let forwardee = forwardee.into();
     let certificate = allocate_certificate();
     certificate.store(
-        CapabilityCertificateValue::forwarded(forwardee, cap.as_raw()),
+        CapabilityCertificateValue::forwarded_with_rights(forwardee, cap.as_raw(), rights),
         Ordering::Relaxed,
     );
     let forwarded = unsafe { Capability::new(RawCapability::from_ref(certificate)) };
@@ -34,11 +52,42 @@ pub fn forward<T: Interface>(cap: Capability<T>, forwardee: impl Into<Actor>) ->
     forwarded
 }
 
+/// Stages a capability to be forwarded to the receiver of the next IPC call or reply, returning
+/// the slot it will be forwarded through.
+pub(crate) fn transfer<T>(cap: Capability<T>, transfers: &mut Vec<usize>) -> Capability<T> {
+    let slot = allocate_certificate();
+    transfers.push(cap.as_usize());
+    transfers.push(slot as *const CapabilityCertificate as usize);
+    unsafe { Capability::new(RawCapability::from_ref(slot)) }
+}
+
+/// Takes back a capability certified by this process, along with everything forwarded from it.
+pub fn revoke<T: Interface>(cap: Capability<T>) {
+    assert_eq!(cap.certifier(), Actor::Userspace(current_pid()));
+    unsafe { syscall::revoke(cap.as_raw()) }
+    let index = cap.local_index();
+    CAPABILITIES_FREE[index / 64].fetch_or(1 << (index % 64), Ordering::Relaxed);
+    let t_name = T::NAME;
+    trace!("revoked {cap:?} {t_name}");
+}
+
 fn allocate_certificate() -> &'static CapabilityCertificate {
-    let index = CAPABILITIES_ALLOCATED.fetch_add(1, Ordering::Relaxed);
-    assert!(
-        index < 4096 / size_of::<CapabilityCertificateValue>(),
-        "out of capability certificate slots"
-    );
+    let index = if CAPABILITIES_ALLOCATED.load(Ordering::Relaxed) < CAPABILITIES_PER_PAGE {
+        CAPABILITIES_ALLOCATED.fetch_add(1, Ordering::Relaxed)
+    } else {
+        allocate_free_certificate().expect("out of capability certificate slots")
+    };
     &get_capability_certificate_page(current_pid().into())[index]
 }
+
+fn allocate_free_certificate() -> Option<usize> {
+    let start = NEXT_FREE_CAPABILITY.load(Ordering::Relaxed);
+    let index = (start..CAPABILITIES_PER_PAGE)
+        .chain(0..start)
+        .find(|index| {
+            CAPABILITIES_FREE[index / 64].load(Ordering::Relaxed) & 1 << (index % 64) != 0
+        })?;
+    CAPABILITIES_FREE[index / 64].fetch_and(!(1 << (index % 64)), Ordering::Relaxed);
+    NEXT_FREE_CAPABILITY.store((index + 1) % CAPABILITIES_PER_PAGE, Ordering::Relaxed);
+    Some(index)
+}
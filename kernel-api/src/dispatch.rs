@@ -1,11 +1,17 @@
-use crate::capability::CAPABILITIES_ALLOCATED;
-use crate::{forward, grant_unhandled, syscall, yield_};
+use crate::capability::{CAPABILITIES_ALLOCATED, transfer};
+use crate::executor::{Task, poll_tasks};
+use crate::{current_pid, grant_unhandled, syscall, unmap_stream, wait};
 use alloc::boxed::Box;
+use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
 use core::marker::PhantomData;
 use core::sync::atomic::Ordering;
-use deravel_types::{Actor, Capability, Interface, ProcessId, RawCapability, RingBuffer};
+use deravel_types::{
+    Capability, ExitReason, Interface, PAGE_SIZE, ProcessId, RawCapability, RingBuffer,
+    UntypedRingBuffer,
+};
+use serde::Serialize;
 
 pub trait Handler<T, O: Copy> {
     fn call_method(
@@ -15,7 +21,7 @@ pub trait Handler<T, O: Copy> {
         args: &[u8],
         object: O,
         sender: ProcessId,
-    ) -> ([u8; 4096], usize);
+    ) -> Vec<u8>;
 }
 
 pub trait Observer<T, O: Copy> {
@@ -29,26 +35,56 @@ pub trait RawHandler<S: ?Sized> {
         method: usize,
         args: &[u8],
         sender: ProcessId,
-    ) -> (([u8; 4096], usize), Vec<HandlerEntry<S>>);
+        token: usize,
+        new_tasks: &mut Vec<Task>,
+    ) -> (Option<Vec<u8>>, Vec<HandlerEntry<S>>);
 }
 
 pub trait RawObserver<S: ?Sized> {
-    fn observe(&mut self, server: &mut S) -> Vec<HandlerEntry<S>>;
+    fn observe(
+        &mut self,
+        server: &mut S,
+        new_tasks: &mut Vec<Task>,
+        unobserved: &mut Vec<usize>,
+    ) -> ObserverResult<S>;
+
+    fn ring(&self) -> &'static UntypedRingBuffer;
 }
 
 pub struct Ctx<'a, S: ?Sized> {
     sender: ProcessId,
+    token: usize,
+    deferred: bool,
     new_handlers: &'a mut Vec<HandlerEntry<S>>,
+    new_tasks: &'a mut Vec<Task>,
 }
 
 pub struct OCtx<'a, S: ?Sized> {
     new_handlers: &'a mut Vec<HandlerEntry<S>>,
+    new_observers: &'a mut Vec<Box<dyn RawObserver<S>>>,
+    new_tasks: &'a mut Vec<Task>,
+    unobserved: &'a mut Vec<usize>,
+}
+
+/// Reply to a call that outlived its handler, sent once the server has the result. Dropping it
+/// fails the call as if the server had finished.
+#[must_use]
+pub struct Deferred<T> {
+    token: usize,
+    _phantom: PhantomData<T>,
+}
+
+/// Values a deferred call can be answered with, which stage the capabilities in them to be
+/// transferred to the caller.
+pub trait ReplyValue: Serialize {
+    fn stage_transfers(self, transfers: &mut Vec<usize>) -> Self;
 }
 
 pub struct Dispatch<S> {
     pub server: S,
     handlers: Vec<Option<Box<dyn RawHandler<S>>>>,
     observers: Vec<Box<dyn RawObserver<S>>>,
+    tasks: Vec<Task>,
 }
 
 pub struct HandlerEntry<S: ?Sized> {
@@ -56,6 +92,8 @@ pub struct HandlerEntry<S: ?Sized> {
     handler: Box<dyn RawHandler<S>>,
 }
 
+type ObserverResult<S> = (Vec<HandlerEntry<S>>, Vec<Box<dyn RawObserver<S>>>);
+
 struct TypedHandler<T, O>(O, PhantomData<T>);
 
 struct TypedObserver<T: 'static, O>(O, &'static RingBuffer<T>);
@@ -67,39 +105,57 @@ impl<S: ?Sized + Handler<T, O>, T, O: Copy> RawHandler<S> for TypedHandler<T, O>
         method: usize,
         args: &[u8],
         sender: ProcessId,
-    ) -> (([u8; 4096], usize), Vec<HandlerEntry<S>>) {
+        token: usize,
+        new_tasks: &mut Vec<Task>,
+    ) -> (Option<Vec<u8>>, Vec<HandlerEntry<S>>) {
         let mut new_handlers = Vec::new();
         let mut ctx = Ctx {
             sender,
+            token,
+            deferred: false,
             new_handlers: &mut new_handlers,
+            new_tasks,
         };
         let result = server.call_method(&mut ctx, method, args, self.0, sender);
+        let result = if ctx.deferred { None } else { Some(result) };
         (result, new_handlers)
     }
 }
 
 impl<S: ?Sized + Observer<T, O>, T: Copy, O: Copy> RawObserver<S> for TypedObserver<T, O> {
-    fn observe(&mut self, server: &mut S) -> Vec<HandlerEntry<S>> {
+    fn observe(
+        &mut self,
+        server: &mut S,
+        new_tasks: &mut Vec<Task>,
+        unobserved: &mut Vec<usize>,
+    ) -> ObserverResult<S> {
         let mut new_handlers = Vec::new();
-        while let Some(value) = self.1.poll() {
+        let mut new_observers = Vec::new();
+        while !unobserved.contains(&ring_address(self.1.untype()))
+            && let Some(value) = self.1.poll()
+        {
             let iteration_ctx = OCtx {
                 new_handlers: &mut new_handlers,
+                new_observers: &mut new_observers,
+                new_tasks,
+                unobserved,
             };
             server.observe(iteration_ctx, value, self.0);
         }
-        new_handlers
+        (new_handlers, new_observers)
+    }
+
+    fn ring(&self) -> &'static UntypedRingBuffer {
+        self.1.untype()
     }
 }
 
 impl<S: ?Sized> Ctx<'_, S> {
-    pub fn grant_to_sender<T: Interface + 'static, O: Copy + 'static>(
-        &mut self,
-        object: O,
-    ) -> Capability<T>
+    pub fn grant<T: Interface + 'static, O: Copy + 'static>(&mut self, object: O) -> Capability<T>
     where
         S: Handler<T, O>,
     {
-        let cap = grant_unhandled(self.sender);
+        let cap = grant_unhandled(current_pid());
         self.new_handlers.push(HandlerEntry {
             local_index: cap.local_index(),
             handler: Box::new(TypedHandler(object, PhantomData)),
@@ -107,26 +163,143 @@ impl<S: ?Sized> Ctx<'_, S> {
         cap
     }
 
-    pub fn forward_to_sender<T: Interface>(&mut self, cap: Capability<T>) -> Capability<T> {
-        forward(cap, self.sender)
+    pub fn sender(&self) -> ProcessId {
+        self.sender
+    }
+
+    /// Leaves the call unanswered when the handler returns, the value it returns is discarded.
+    pub fn defer<T>(&mut self) -> Deferred<T> {
+        self.deferred = true;
+        Deferred {
+            token: self.token,
+            _phantom: PhantomData,
+        }
+    }
+
+    pub(crate) fn token(&self) -> usize {
+        self.token
+    }
+
+    pub(crate) fn is_deferred(&self) -> bool {
+        self.deferred
+    }
+
+    pub fn spawn(&mut self, task: impl Future<Output = ()> + 'static) {
+        self.new_tasks.push(Box::pin(task));
     }
 }
 
 impl<S: ?Sized> OCtx<'_, S> {
-    pub fn grant_to_kernel<T: Interface + 'static, O: Copy + 'static>(
-        &mut self,
-        object: O,
-    ) -> Capability<T>
+    pub fn grant<T: Interface + 'static, O: Copy + 'static>(&mut self, object: O) -> Capability<T>
     where
         S: Handler<T, O>,
     {
-        let cap = grant_unhandled(Actor::Kernel);
+        let cap = grant_unhandled(current_pid());
         self.new_handlers.push(HandlerEntry {
             local_index: cap.local_index(),
             handler: Box::new(TypedHandler(object, PhantomData)),
         });
         cap
     }
+
+    pub fn observe<T: Copy + 'static, O: Copy + 'static>(
+        &mut self,
+        object: O,
+        ring: &'static RingBuffer<T>,
+    ) where
+        S: Observer<T, O>,
+    {
+        self.new_observers
+            .push(Box::new(TypedObserver(object, ring)));
+    }
+
+    pub fn spawn(&mut self, task: impl Future<Output = ()> + 'static) {
+        self.new_tasks.push(Box::pin(task));
+    }
+
+    /// Stops observing the stream and unmaps it. No more values are delivered from it, but the
+    /// unmapping waits until every observer has run.
+    pub fn unobserve<T>(&mut self, ring: &'static RingBuffer<T>) {
+        self.unobserved.push(ring_address(ring.untype()));
+    }
+}
+
+impl<T: ReplyValue> Deferred<T> {
+    /// Whether the caller stopped waiting because its deadline passed, the reply will be dropped.
+    pub fn is_cancelled(&self) -> bool {
+        unsafe { syscall::ipc_cancelled(self.token) != 0 }
+    }
+
+    pub fn reply(self, value: T) {
+        let token = self.token;
+        core::mem::forget(self);
+        let mut transfers = Vec::new();
+        let value = value.stage_transfers(&mut transfers);
+        unsafe { syscall::ipc_transfer(transfers.as_ptr(), transfers.len()) };
+        let result = postcard::to_allocvec(&value).unwrap();
+        unsafe { syscall::ipc_reply(token, result.as_ptr(), result.len()) }
+    }
+}
+
+impl<T> Drop for Deferred<T> {
+    fn drop(&mut self) {
+        unsafe { syscall::ipc_abandon(self.token) }
+    }
+}
+
+macro_rules! impl_plain_reply_value {
+    ($($type:ty),*) => {
+        $(impl ReplyValue for $type {
+            fn stage_transfers(self, _: &mut Vec<usize>) -> Self {
+                self
+            }
+        })*
+    };
+}
+
+impl_plain_reply_value!(
+    (),
+    bool,
+    u8,
+    u16,
+    u32,
+    u64,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    isize,
+    String,
+    ProcessId,
+    ExitReason,
+    RawCapability
+);
+
+impl<T> ReplyValue for Capability<T> {
+    fn stage_transfers(self, transfers: &mut Vec<usize>) -> Self {
+        transfer(self, transfers)
+    }
+}
+
+impl<T: ReplyValue> ReplyValue for Option<T> {
+    fn stage_transfers(self, transfers: &mut Vec<usize>) -> Self {
+        self.map(|value| value.stage_transfers(transfers))
+    }
+}
+
+impl<T: ReplyValue, E: ReplyValue> ReplyValue for Result<T, E> {
+    fn stage_transfers(self, transfers: &mut Vec<usize>) -> Self {
+        self.map(|value| value.stage_transfers(transfers))
+    }
+}
+
+impl<T: ReplyValue> ReplyValue for Vec<T> {
+    fn stage_transfers(self, transfers: &mut Vec<usize>) -> Self {
+        self.into_iter()
+            .map(|value| value.stage_transfers(transfers))
+            .collect()
+    }
 }
 
 impl<S> Dispatch<S> {
@@ -141,6 +314,7 @@ impl<S> Dispatch<S> {
             server,
             handlers,
             observers: Vec::new(),
+            tasks: Vec::new(),
         }
     }
 
@@ -155,6 +329,7 @@ impl<S> Dispatch<S> {
             server,
             handlers,
             observers: Vec::new(),
+            tasks: Vec::new(),
         }
     }
 
@@ -168,24 +343,35 @@ impl<S> Dispatch<S> {
         self.observers.push(Box::new(TypedObserver(object, ring)));
     }
 
+    pub fn spawn(&mut self, task: impl Future<Output = ()> + 'static) {
+        self.tasks.push(Box::pin(task));
+    }
+
     pub fn run(&mut self) -> ! {
         loop {
             self.run_calls();
             self.run_observables();
-            yield_();
+            poll_tasks(&mut self.tasks);
+            let rings: Vec<_> = self.observers.iter().map(|o| o.ring()).collect();
+            wait(&rings, None);
         }
     }
 
     fn run_calls(&mut self) {
+        let mut buf = vec![0; PAGE_SIZE];
         loop {
-            let mut buf = [0u8; 4096];
-            let (cap, method, args_len, sender) =
+            let (cap, method, args_len, sender, token) =
                 unsafe { syscall::ipc_receive(buf.as_mut_ptr(), buf.len()) };
             let (Some(cap), Some(sender)) = (cap, sender) else {
+                if args_len > buf.len() {
+                    buf.resize(args_len, 0);
+                    continue;
+                }
                 break;
             };
-            let result = self.run_call(cap, method, &buf[..args_len], sender);
-            unsafe { syscall::ipc_reply(result.0.as_ptr(), result.1) }
+            if let Some(result) = self.run_call(cap, method, &buf[..args_len], sender, token) {
+                unsafe { syscall::ipc_reply(token, result.as_ptr(), result.len()) }
+            }
         }
     }
 
@@ -195,14 +381,22 @@ impl<S> Dispatch<S> {
         method: usize,
         args: &[u8],
         sender: ProcessId,
-    ) -> ([u8; 4096], usize) {
+        token: usize,
+    ) -> Option<Vec<u8>> {
         let Some(handler) = self.handlers[cap.local_index()].as_mut() else {
             panic!(
                 "dispatch on unhandled {cap:?}, method {method} {} from {sender:?}",
                 str::from_utf8(args).unwrap()
             )
         };
-        let (result, new_handlers) = handler.call_method(&mut self.server, method, args, sender);
+        let (result, new_handlers) = handler.call_method(
+            &mut self.server,
+            method,
+            args,
+            sender,
+            token,
+            &mut self.tasks,
+        );
         self.handlers
             .resize_with(CAPABILITIES_ALLOCATED.load(Ordering::Relaxed), || None);
         for new_handler in new_handlers {
@@ -213,9 +407,25 @@ impl<S> Dispatch<S> {
 
     fn run_observables(&mut self) {
         let mut new_handlers = Vec::new();
+        let mut new_observers = Vec::new();
+        let mut unobserved = Vec::new();
         for observable in &mut self.observers {
-            let iteration_new_handlers = observable.observe(&mut self.server);
+            let (iteration_new_handlers, iteration_new_observers) =
+                observable.observe(&mut self.server, &mut self.tasks, &mut unobserved);
             new_handlers.extend(iteration_new_handlers);
+            new_observers.extend(iteration_new_observers);
+        }
+        self.observers.extend(new_observers);
+        if !unobserved.is_empty() {
+            self.observers.retain(|observer| {
+                let ring = observer.ring();
+                if unobserved.contains(&ring_address(ring)) {
+                    unsafe { unmap_stream(ring) };
+                    false
+                } else {
+                    true
+                }
+            });
         }
         self.handlers
             .resize_with(CAPABILITIES_ALLOCATED.load(Ordering::Relaxed), || None);
@@ -224,3 +434,7 @@ impl<S> Dispatch<S> {
         }
     }
 }
+
+fn ring_address(ring: &UntypedRingBuffer) -> usize {
+    (ring as *const UntypedRingBuffer).addr()
+}
@@ -6,7 +6,10 @@
 #![allow(clippy::too_many_arguments)]
 
 use crate::abi::*;
-use crate::{Ctx, Handler, RingBuffer};
+use crate::capability::transfer;
+use crate::executor::Call;
+use crate::ipc::{CallFailure, call};
+use crate::{Ctx, Handler, ReplyValue, RingBuffer};
 use alloc::string::String;
 use alloc::vec::Vec;
 use core::marker::PhantomData;
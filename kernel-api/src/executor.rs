@@ -0,0 +1,84 @@
+use crate::ipc::CallFailure;
+use crate::{syscall, wait};
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use deravel_types::{IPC_DISCONNECTED, IPC_PENDING, PAGE_SIZE};
+
+pub type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+/// Reply to a call started with `ipc_send`, resolved once the server replies. Dropping it earlier
+/// withdraws the call, and the server's reply is discarded.
+#[must_use]
+pub struct Call<T> {
+    id: usize,
+    decode: fn(&[u8]) -> T,
+    on_failure: fn(CallFailure) -> T,
+    resolved: bool,
+}
+
+impl<T> Call<T> {
+    #[doc(hidden)]
+    pub fn new(id: usize, decode: fn(&[u8]) -> T, on_failure: fn(CallFailure) -> T) -> Call<T> {
+        Call {
+            id,
+            decode,
+            on_failure,
+            resolved: false,
+        }
+    }
+}
+
+impl<T> Future for Call<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, _: &mut Context) -> Poll<T> {
+        let mut buf = vec![0; PAGE_SIZE];
+        loop {
+            let result_len =
+                unsafe { syscall::ipc_poll_reply(self.id, buf.as_mut_ptr(), buf.len()) };
+            // The kernel forgets the call once it hands out the reply.
+            match result_len {
+                IPC_PENDING => return Poll::Pending,
+                IPC_DISCONNECTED => {
+                    self.resolved = true;
+                    return Poll::Ready((self.on_failure)(CallFailure::Disconnected));
+                }
+                _ if result_len > buf.len() => buf.resize(result_len, 0),
+                _ => {
+                    self.resolved = true;
+                    return Poll::Ready((self.decode)(&buf[..result_len]));
+                }
+            }
+        }
+    }
+}
+
+impl<T> Drop for Call<T> {
+    fn drop(&mut self) {
+        if !self.resolved {
+            unsafe { syscall::ipc_discard(self.id) }
+        }
+    }
+}
+
+/// Runs the future to completion, sleeping until a reply arrives whenever it can't progress.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = core::pin::pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        wait(&[], None);
+    }
+}
+
+// Wakers are not tracked, every wakeup of the process polls all tasks. Processes only have a
+// handful of calls in flight, so this is cheaper than bookkeeping which call belongs to which task.
+pub(crate) fn poll_tasks(tasks: &mut Vec<Task>) {
+    let mut cx = Context::from_waker(Waker::noop());
+    tasks.retain_mut(|task| task.as_mut().poll(&mut cx).is_pending());
+}
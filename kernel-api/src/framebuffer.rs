@@ -1,4 +1,4 @@
-use crate::{alloc_shared, map_shared};
+use crate::{alloc_shared, free_shared, map_shared};
 use deravel_types::{Capability, PageAligned, SharedMemory};
 
 pub struct Framebuffer {
@@ -9,7 +9,7 @@ pub struct Framebuffer {
 
 impl Framebuffer {
     pub fn alloc(width: usize, height: usize) -> (Framebuffer, Capability<SharedMemory>) {
-        let (ptr, cap) = alloc_shared(4 * width * height);
+        let (ptr, cap) = alloc_shared(4 * width * height).expect("out of memory for framebuffer");
         let ptr = unsafe { &mut *PageAligned::cast_mut(ptr) };
         (Framebuffer { ptr, width, height }, cap)
     }
@@ -19,6 +19,12 @@ impl Framebuffer {
         Framebuffer { ptr, width, height }
     }
 
+    /// Frees a framebuffer created with `alloc`.
+    pub fn free(self) {
+        let memory = core::ptr::from_raw_parts_mut(self.ptr.as_mut_ptr(), size_of_val(self.ptr));
+        unsafe { free_shared(memory) }
+    }
+
     #[track_caller]
     pub fn set_pixel(&mut self, x: usize, y: usize, r: u8, g: u8, b: u8, a: u8) {
         assert!(x < self.width);
@@ -0,0 +1,68 @@
+use crate::{common_inputs, syscall};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use deravel_types::{IPC_DISCONNECTED, IPC_TIMED_OUT, PAGE_SIZE, RawCapability};
+
+static CALL_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Why a call got no usable reply.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CallFailure {
+    Disconnected,
+    TimedOut,
+    InvalidReply,
+}
+
+impl core::fmt::Display for CallFailure {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            CallFailure::Disconnected => write!(f, "disconnected"),
+            CallFailure::TimedOut => write!(f, "timed out"),
+            CallFailure::InvalidReply => write!(f, "sent an invalid reply"),
+        }
+    }
+}
+
+/// Runs `f`, making every blocking call inside it fail with a timeout if the server doesn't reply
+/// within `timeout` seconds from now. Nested timeouts can only shorten the deadline.
+pub fn with_timeout<R>(timeout: f64, f: impl FnOnce() -> R) -> R {
+    let frequency = common_inputs().riscv_timebase_frequency.unwrap() as f64;
+    let deadline = riscv::register::time::read64() + (timeout * frequency) as u64;
+    let previous = CALL_DEADLINE.fetch_min(deadline, Ordering::Relaxed);
+    let result = f();
+    CALL_DEADLINE.store(previous, Ordering::Relaxed);
+    result
+}
+
+/// Calls a method and returns the serialized reply, unless the server finished or the deadline set
+/// by `with_timeout` passed before it replied. Replies that don't fit in the initial buffer are
+/// taken with a second syscall.
+pub(crate) fn call(cap: RawCapability, method: usize, args: &[u8]) -> Result<Vec<u8>, CallFailure> {
+    let deadline = CALL_DEADLINE.load(Ordering::Relaxed);
+    if deadline != u64::MAX {
+        unsafe { syscall::ipc_deadline(deadline) };
+    }
+    let mut result = vec![0; PAGE_SIZE];
+    let result_len = unsafe {
+        syscall::ipc_call(
+            cap,
+            method,
+            args.as_ptr(),
+            args.len(),
+            result.as_mut_ptr(),
+            result.len(),
+        )
+    };
+    match result_len {
+        IPC_DISCONNECTED => return Err(CallFailure::Disconnected),
+        IPC_TIMED_OUT => return Err(CallFailure::TimedOut),
+        _ => {}
+    }
+    if result_len > result.len() {
+        result.resize(result_len, 0);
+        unsafe { syscall::ipc_take_reply(result.as_mut_ptr(), result.len()) };
+    }
+    result.truncate(result_len);
+    Ok(result)
+}
@@ -1,22 +1,31 @@
 #![allow(clippy::too_many_arguments)]
 #![feature(decl_macro)]
+#![feature(never_type)]
 #![feature(ptr_metadata)]
 #![no_std]
 
 extern crate alloc;
 
+mod cached_drive;
 mod capability;
 mod dispatch;
 pub mod drvli;
+mod executor;
 mod framebuffer;
+mod ipc;
 
+pub use cached_drive::CachedDrive;
 pub use capability::*;
 pub use deravel_types::*;
 pub use dispatch::*;
 pub use drvli::*;
+pub use executor::{Call, block_on};
 pub use framebuffer::Framebuffer;
+pub use ipc::{CallFailure, with_timeout};
 
+use alloc::format;
 use alloc::string::String;
+use alloc::vec::Vec;
 use core::alloc::{GlobalAlloc, Layout};
 use core::fmt::Write;
 use core::sync::atomic::{AtomicUsize, Ordering};
@@ -66,6 +75,8 @@ impl ProcessTag for FakeProcess {
     type Export = ();
     type Spawner = ();
     const NAME: &'static str = "";
+    const PRIORITY: Priority = Priority::Normal;
+    const MEMORY_LIMIT: usize = 0;
 }
 
 impl ProcessArgs for FakeProcessArgs {
@@ -77,8 +88,9 @@ impl ProcessArgs for FakeProcessArgs {
 impl Write for Stdio {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         let stdio = stdio();
+        // Like getchar, exits once there's no console left to print to.
         for byte in s.bytes() {
-            stdio.putchar(byte);
+            stdio.try_putchar(byte).unwrap_or_else(|_| exit());
         }
         Ok(())
     }
@@ -116,10 +128,29 @@ impl Log for KernelLogger {
     fn flush(&self) {}
 }
 
-pub fn alloc_shared(size: usize) -> (*mut PageAligned<[u8]>, Capability<SharedMemory>) {
+/// Returns `None` if the system is out of memory.
+pub fn alloc_shared(size: usize) -> Option<(*mut PageAligned<[u8]>, Capability<SharedMemory>)> {
     // TODO: This API should warn size gets rounded up to page size?
     let (ptr, cap) = unsafe { syscall::alloc_shared(size) };
-    (core::ptr::from_raw_parts_mut(ptr, size), cap)
+    Some((core::ptr::from_raw_parts_mut(ptr, size), cap?))
+}
+
+/// Reserves an address range without committing any memory to it, so it can be committed piece by
+/// piece later. Returns `None` if the address space is exhausted.
+pub fn reserve(size: usize) -> Option<*mut u8> {
+    let ptr = unsafe { syscall::reserve(size) };
+    (!ptr.is_null()).then_some(ptr)
+}
+
+/// Commits the pages of a reserved range, which get zeroed on first access. Returns false if that
+/// would exceed the process' memory limit.
+///
+/// # Safety
+///
+/// The range must lie within a single range returned by `reserve`.
+#[must_use]
+pub unsafe fn commit(ptr: *mut u8, size: usize) -> bool {
+    unsafe { syscall::commit(ptr, size) != 0 }
 }
 
 pub fn current_pid() -> ProcessId {
@@ -130,8 +161,18 @@ pub fn exit() -> ! {
     unsafe { syscall::exit() }
 }
 
+/// Exits if the console is gone, as there's nothing left to read.
 pub fn getchar() -> u8 {
-    stdio().getchar()
+    stdio().try_getchar().unwrap_or_else(|_| exit())
+}
+
+/// Blocks until the process exits and returns why it did.
+pub fn join(process: Capability<ProcessHandle>) -> ExitReason {
+    let exited = process.exited();
+    while exited.poll().is_none() {
+        wait(&[exited.untype()], None);
+    }
+    process.exit_reason().unwrap()
 }
 
 pub fn map_shared(cap: Capability<SharedMemory>) -> *mut PageAligned<[u8]> {
@@ -139,6 +180,39 @@ pub fn map_shared(cap: Capability<SharedMemory>) -> *mut PageAligned<[u8]> {
     core::ptr::from_raw_parts_mut(pointer, size)
 }
 
+/// Frees memory allocated with `alloc_shared`. Processes mapping it keep it until they unmap it.
+///
+/// # Safety
+///
+/// The memory must not be accessed afterwards.
+pub unsafe fn free_shared(memory: *mut PageAligned<[u8]>) {
+    unsafe { syscall::free(memory as *mut u8) }
+}
+
+/// Unmaps memory mapped with `map_shared`. It is freed once no process maps it and no process holds
+/// a capability to it.
+///
+/// # Safety
+///
+/// The memory must not be accessed afterwards.
+pub unsafe fn unmap_shared(memory: *mut PageAligned<[u8]>) {
+    unsafe { syscall::unmap_shared(memory as *mut u8) }
+}
+
+/// Unmaps a stream returned by a capability method.
+///
+/// # Safety
+///
+/// The stream must not be accessed afterwards.
+pub unsafe fn unmap_stream(ring: &UntypedRingBuffer) {
+    unsafe { syscall::unmap_stream(ring as *const UntypedRingBuffer as *mut u8) }
+}
+
+/// Gives up a capability, along with every capability forwarded from it.
+pub fn release<T>(cap: Capability<T>) {
+    unsafe { syscall::release(cap.as_raw()) }
+}
+
 pub fn putchar(ch: u8) {
     stdio().putchar(ch)
 }
@@ -151,6 +225,30 @@ pub fn system_time() -> f64 {
     riscv::register::time::read() as f64 / common_inputs().riscv_timebase_frequency.unwrap() as f64
 }
 
+/// Returns the seconds since the Unix epoch, unless the kernel doesn't know the wall clock time.
+pub fn unix_time() -> Option<u64> {
+    let inputs = common_inputs();
+    let frequency = inputs.riscv_timebase_frequency? as u64;
+    Some(inputs.unix_time_at_zero? + riscv::register::time::read64() / frequency)
+}
+
+/// Blocks until an IPC message or reply arrives, one of the rings has unread elements, or the timeout
+/// (in seconds) expires.
+pub fn wait(rings: &[&UntypedRingBuffer], timeout: Option<f64>) {
+    let rings: Vec<usize> = rings
+        .iter()
+        .map(|ring| ring.0.read.0.as_ptr().addr())
+        .collect();
+    let deadline = match timeout {
+        Some(timeout) => {
+            let frequency = common_inputs().riscv_timebase_frequency.unwrap() as f64;
+            riscv::register::time::read64() + (timeout * frequency) as u64
+        }
+        None => u64::MAX,
+    };
+    unsafe { syscall::wait(rings.as_ptr(), rings.len(), deadline) }
+}
+
 pub fn yield_() {
     unsafe { syscall::yield_() }
 }
@@ -182,5 +280,6 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
     let location = info.location().unwrap();
     let message = info.message();
     error!("user application panicked at {location}: {message}");
-    exit()
+    let reason = format!("{location}: {message}");
+    unsafe { syscall::abort(reason.as_ptr(), reason.len()) }
 }
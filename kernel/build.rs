@@ -70,23 +70,41 @@ fn generate_handler_impl(interface: &Interface, out: &mut String) {
     .unwrap();
     writeln!(
         out,
-        "    fn call_method(&self, method: usize, _args: &[u8], _sender: ProcessId) -> Vec<u8> {{"
+        "    fn call_method(&self, method: usize, _args: &[u8], _sender: ProcessId, _loans: &mut [Loan]) -> Option<Vec<u8>> {{"
     )
     .unwrap();
     writeln!(out, "        match method {{").unwrap();
     for (method_index, method) in interface.methods.iter().enumerate() {
         let method_name = &method.name;
         writeln!(out, "            {method_index} => {{").unwrap();
+        let serialized_args: Vec<_> = method
+            .args
+            .iter()
+            .filter(|(_, type_)| !type_.is_loan())
+            .collect();
         write!(out, "                let (").unwrap();
-        for (arg_name, _) in &method.args {
+        for (arg_name, _) in &serialized_args {
             write!(out, "{arg_name},").unwrap();
         }
         write!(out, "): (").unwrap();
-        for (_, arg_type) in &method.args {
+        for (_, arg_type) in &serialized_args {
             let arg_type = arg_type.rust(NormalRet);
             write!(out, "{arg_type},").unwrap();
         }
-        writeln!(out, ") = postcard::from_bytes(_args).unwrap();").unwrap();
+        writeln!(out, ") = postcard::from_bytes(_args).ok()?;").unwrap();
+        writeln!(out, "                let mut _loans = _loans.iter_mut();").unwrap();
+        for (arg_name, arg_type) in &method.args {
+            let as_slice = match arg_type {
+                Type::Loan => "as_slice",
+                Type::MutLoan => "as_mut_slice",
+                _ => continue,
+            };
+            writeln!(
+                out,
+                "                let {arg_name} = _loans.next()?.{as_slice}();"
+            )
+            .unwrap();
+        }
         write!(
             out,
             "                let _result = self.{method_name}(_sender, "
@@ -98,19 +116,20 @@ fn generate_handler_impl(interface: &Interface, out: &mut String) {
         }
         writeln!(out, ");").unwrap();
         if method.return_type != Some(Type::Never) {
-            writeln!(out, "                let mut buf = vec![0; 4096];").unwrap();
-            writeln!(out, "                let buf_len = postcard::to_slice(&_result, &mut buf).unwrap().len();").unwrap();
-            writeln!(out, "                buf.resize(buf_len, 0);").unwrap();
-            writeln!(out, "                buf").unwrap();
+            writeln!(
+                out,
+                "                Some(postcard::to_allocvec(&_result).unwrap())"
+            )
+            .unwrap();
         }
         writeln!(out, "            }}").unwrap();
     }
-    writeln!(out, "            _ => unreachable!(),").unwrap();
+    writeln!(out, "            _ => None,").unwrap();
     writeln!(out, "        }}").unwrap();
     writeln!(out, "    }}").unwrap();
     writeln!(
         out,
-        "    fn map_stream(&self, stream: usize) -> &'static UntypedRingBuffer {{"
+        "    fn map_stream(&self, stream: usize) -> Option<&'static UntypedRingBuffer> {{"
     )
     .unwrap();
     writeln!(out, "        match stream {{").unwrap();
@@ -118,23 +137,37 @@ fn generate_handler_impl(interface: &Interface, out: &mut String) {
         let stream_name = &stream.name;
         writeln!(
             out,
-            "            {stream_index} => self.{stream_name}().untype(),"
+            "            {stream_index} => Some(self.{stream_name}().untype()),"
         )
         .unwrap();
     }
-    writeln!(out, "            _ => unreachable!(),").unwrap();
+    writeln!(out, "            _ => None,").unwrap();
     writeln!(out, "        }}").unwrap();
     writeln!(out, "    }}").unwrap();
     writeln!(
         out,
-        "    fn shared_memory_map(&self, _: usize, _: &mut PageTable, _: &mut Vec<(Range<usize>, &'static (dyn VirtualMemoryRawMapping + Sync))>) {{"
+        "    fn shared_memory_map(&self, _: ProcessId, _: usize, _: &mut PageTable) {{"
     )
     .unwrap();
     writeln!(out, "        unreachable!()").unwrap();
     writeln!(out, "    }}").unwrap();
-    writeln!(out, "    fn shared_memory_size(&self) -> usize {{").unwrap();
+    writeln!(
+        out,
+        "    fn shared_memory_unmap(&self, _: ProcessId, _: usize, _: &mut PageTable) {{"
+    )
+    .unwrap();
     writeln!(out, "        unreachable!()").unwrap();
     writeln!(out, "    }}").unwrap();
+    writeln!(
+        out,
+        "    fn shared_memory_fault(&self, _: usize, _: usize, _: &mut PageTable) -> bool {{"
+    )
+    .unwrap();
+    writeln!(out, "        unreachable!()").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "    fn shared_memory_size(&self) -> Option<usize> {{").unwrap();
+    writeln!(out, "        None").unwrap();
+    writeln!(out, "    }}").unwrap();
     writeln!(out, "}}").unwrap();
 }
 
@@ -199,12 +232,19 @@ fn generate_syscall_dispatch(drvli: &Drvli, out: &mut String) {
                 Type::SharedMemory => format!(
                     "unsafe {{ Capability::new(RawCapability::try_from(user.registers.a{used_arg_registers} as *const CapabilityCertificate)?) }}"
                 ),
+                Type::U8 => format!("user.registers.a{used_arg_registers} as u8"),
                 Type::U64 => format!("user.registers.a{used_arg_registers} as u64"),
                 Type::Usize => format!("user.registers.a{used_arg_registers}"),
-                Type::Array(inner) | Type::ConstArray(inner) if **inner == Type::U8 => {
+                Type::Array(inner) | Type::ConstArray(inner)
+                    if matches!(**inner, Type::U8 | Type::Usize) =>
+                {
                     let ap = format!("user.registers.a{used_arg_registers}");
                     let as_ = format!("user.registers.a{}", used_arg_registers + 1);
-                    format!("UserPtr::from_slice({ap} as *mut u8, {as_})?")
+                    let inner = inner.rust(SyscallKernelArg);
+                    let write = matches!(arg_type, Type::Array(_));
+                    format!(
+                        "{{ let ptr = UserPtr::from_slice({ap} as *mut {inner}, {as_})?; user.process().fault_in(ptr.range()?, {write})?; ptr }}"
+                    )
                 }
                 Type::Ptr(inner) if **inner == Type::U8 => {
                     format!("UserPtr::from_ptr(user.registers.a{used_arg_registers} as *mut u8)?")
@@ -0,0 +1,180 @@
+use crate::heap::granularity::{PageGranular, page_granular_vec};
+use crate::page::{Page, PageFlags, PageTable, phys_to_virt, virt_to_phys};
+use crate::util::untyped_box::UntypedBox;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::alloc::{AllocError, Layout};
+use core::ops::Range;
+use deravel_types::PAGE_SIZE;
+
+/// Process memory that only gets its own physical pages once touched. Committed pages start out
+/// either as zeroes, or as read-only pages shared with other processes that get copied on the first
+/// write. Reserved pages only hold the address range until they are committed.
+pub struct AnonymousRegion {
+    pages: Vec<AnonymousPage>,
+    flags: PageFlags,
+}
+
+pub enum AnonymousPage {
+    Reserved,
+    Zero,
+    Shared(usize),
+    Private(Box<Page>),
+}
+
+impl AnonymousRegion {
+    pub fn new(pages: Vec<AnonymousPage>, flags: PageFlags) -> AnonymousRegion {
+        AnonymousRegion { pages, flags }
+    }
+
+    pub fn zeroed(size: usize, flags: PageFlags) -> AnonymousRegion {
+        assert!(size.is_multiple_of(PAGE_SIZE));
+        let pages = (0..size / PAGE_SIZE).map(|_| AnonymousPage::Zero).collect();
+        AnonymousRegion::new(pages, flags)
+    }
+
+    pub fn reserved(size: usize, flags: PageFlags) -> AnonymousRegion {
+        assert!(size.is_multiple_of(PAGE_SIZE));
+        let pages = (0..size / PAGE_SIZE)
+            .map(|_| AnonymousPage::Reserved)
+            .collect();
+        AnonymousRegion::new(pages, flags)
+    }
+
+    pub fn byte_size(&self) -> usize {
+        self.pages.len() * PAGE_SIZE
+    }
+
+    pub fn layout(&self) -> Layout {
+        Layout::from_size_align(self.byte_size(), PAGE_SIZE).unwrap()
+    }
+
+    pub fn committed_bytes(&self) -> usize {
+        self.byte_size() - self.uncommitted_bytes(0..self.pages.len())
+    }
+
+    pub fn uncommitted_bytes(&self, pages: Range<usize>) -> usize {
+        let reserved = self.pages[pages]
+            .iter()
+            .filter(|page| matches!(page, AnonymousPage::Reserved))
+            .count();
+        reserved * PAGE_SIZE
+    }
+
+    pub fn commit(&mut self, pages: Range<usize>) {
+        for page in &mut self.pages[pages] {
+            if let AnonymousPage::Reserved = page {
+                *page = AnonymousPage::Zero;
+            }
+        }
+    }
+
+    pub fn map(&self, virt: usize, page_table: &mut PageTable) {
+        for (page_index, page) in self.pages.iter().enumerate() {
+            let virt = virt + page_index * PAGE_SIZE;
+            match page {
+                AnonymousPage::Reserved | AnonymousPage::Zero => {}
+                AnonymousPage::Shared(phys) => {
+                    page_table.map(virt, *phys, PAGE_SIZE, self.shared_flags())
+                }
+                AnonymousPage::Private(page) => {
+                    page_table.map(virt, page_phys(page), PAGE_SIZE, self.flags)
+                }
+            }
+        }
+    }
+
+    pub fn unmap(&self, virt: usize, page_table: &mut PageTable) {
+        for (page_index, page) in self.pages.iter().enumerate() {
+            let virt = virt + page_index * PAGE_SIZE;
+            match page {
+                AnonymousPage::Reserved | AnonymousPage::Zero => {}
+                AnonymousPage::Shared(phys) => page_table.unmap(virt, *phys, PAGE_SIZE),
+                AnonymousPage::Private(page) => page_table.unmap(virt, page_phys(page), PAGE_SIZE),
+            }
+        }
+    }
+
+    /// Gives the page its own physical memory, returning false if it already had one or wasn't
+    /// committed, so the fault was caused by an access the region doesn't allow.
+    pub fn handle_fault(
+        &mut self,
+        virt: usize,
+        page_index: usize,
+        page_table: &mut PageTable,
+    ) -> Result<bool, AllocError> {
+        let virt = virt + page_index * PAGE_SIZE;
+        let private = match &self.pages[page_index] {
+            AnonymousPage::Zero => Box::<Page>::try_new_zeroed()?,
+            AnonymousPage::Shared(phys)
+                if page_table
+                    .translate(virt)
+                    .is_some_and(|(_, flags)| flags.is_copy_on_write()) =>
+            {
+                let mut private = Box::<Page>::try_new_uninit()?;
+                page_table.unmap(virt, *phys, PAGE_SIZE);
+                let shared = phys_to_virt(*phys) as *const Page;
+                unsafe { private.as_mut_ptr().copy_from_nonoverlapping(shared, 1) };
+                private
+            }
+            AnonymousPage::Reserved | AnonymousPage::Shared(_) | AnonymousPage::Private(_) => {
+                return Ok(false);
+            }
+        };
+        let private = unsafe { private.assume_init() };
+        page_table.map(virt, page_phys(&private), PAGE_SIZE, self.flags);
+        self.pages[page_index] = AnonymousPage::Private(private);
+        Ok(true)
+    }
+
+    /// Handles the fault ahead of time, for memory the kernel is about to access on behalf of the
+    /// process.
+    pub fn fault_in(
+        &mut self,
+        virt: usize,
+        page_index: usize,
+        write: bool,
+        page_table: &mut PageTable,
+    ) -> Result<bool, AllocError> {
+        match self.pages[page_index] {
+            AnonymousPage::Zero => self.handle_fault(virt, page_index, page_table),
+            AnonymousPage::Shared(_) if write => self.handle_fault(virt, page_index, page_table),
+            AnonymousPage::Reserved | AnonymousPage::Shared(_) | AnonymousPage::Private(_) => {
+                Ok(false)
+            }
+        }
+    }
+
+    /// Copies the region into physically contiguous memory, for the rare uses that need it. All its
+    /// pages must be committed.
+    pub fn into_contiguous(self) -> Arc<UntypedBox<PageGranular>> {
+        assert_eq!(self.committed_bytes(), self.byte_size());
+        let mut contiguous = page_granular_vec![0u8; self.byte_size()];
+        for (page_index, page) in self.pages.iter().enumerate() {
+            let content = match page {
+                AnonymousPage::Reserved | AnonymousPage::Zero => continue,
+                AnonymousPage::Shared(phys) => unsafe { &*(phys_to_virt(*phys) as *const Page) },
+                AnonymousPage::Private(page) => page,
+            };
+            contiguous[page_index * PAGE_SIZE..][..PAGE_SIZE].copy_from_slice(&content.0);
+        }
+        Arc::new(UntypedBox::new(contiguous.into_boxed_slice()))
+    }
+
+    pub fn flags(&self) -> PageFlags {
+        self.flags
+    }
+
+    fn shared_flags(&self) -> PageFlags {
+        if self.flags.is_writable() {
+            self.flags.copy_on_write()
+        } else {
+            self.flags
+        }
+    }
+}
+
+fn page_phys(page: &Page) -> usize {
+    virt_to_phys(page as *const Page) as usize
+}
@@ -1,6 +1,8 @@
+use crate::hart::{hart_id, secondary_main};
 use crate::page::{PageFlags, PageTableEntry, satp, sign_extend};
 use crate::process::{Process, schedule_userspace};
 use crate::stack::{KernelStack, UserCtx, UserStoredCtx};
+use crate::sync::unlock_kernel;
 use crate::{main, on_kernel_trap, on_user_trap};
 use alloc::boxed::Box;
 use core::arch::{asm, naked_asm};
@@ -50,6 +52,7 @@ pub struct RiscvRegisters {
 
 // I don't think there's a better way to type-check this.
 const _: extern "C" fn(u64, *const u8) -> ! = main;
+const _: extern "C" fn(usize) -> ! = secondary_main;
 const _: extern "C" fn(&mut UserCtx) -> ! = on_user_trap;
 const _: extern "riscv-interrupt-s" fn() = on_kernel_trap;
 
@@ -168,6 +171,24 @@ unsafe extern "C" fn _start() -> ! {
     )
 }
 
+/// Entry point of secondary harts started through SBI HSM, which get the physical address of their
+/// `SecondaryBoot` as the opaque argument. Unlike the boot hart, they find the kernel already
+/// relocated and the boot page table ready.
+#[unsafe(naked)]
+pub unsafe extern "C" fn secondary_start() -> ! {
+    naked_asm!(
+        ".align 2",
+        "ld t0, 0(a1)",  // satp
+        "ld sp, 8(a1)",  // stack_top
+        "ld t1, 16(a1)", // entry
+        // The lower half identity mapping keeps the instruction pointer valid until the jump to
+        // the already relocated higher half entry.
+        "csrw satp, t0",
+        "sfence.vma",
+        "jr t1",
+    )
+}
+
 pub fn initialize_early_trap() {
     enable_kernel_trap();
 }
@@ -175,12 +196,14 @@ pub fn initialize_early_trap() {
 pub fn initialize_interrupts() {
     let mut sie = riscv::register::sie::read();
     sie.set_sext(true);
+    sie.set_ssoft(true);
     sie.set_stimer(true);
     unsafe { riscv::register::sie::write(sie) }
 }
 
 pub fn initial_switch_to_userspace() -> ! {
     let user = &mut Box::leak(KernelStack::new()).ctx;
+    user.hart_id = hart_id();
     unsafe { riscv::register::sscratch::write(user as *mut _ as usize) }
 
     schedule_userspace(user)
@@ -189,6 +212,7 @@ pub fn initial_switch_to_userspace() -> ! {
 pub fn set_userspace_process(proc: &mut Process, user: &mut UserStoredCtx) {
     unsafe { riscv::register::satp::write(satp(&proc.page_table)) };
     riscv::asm::sfence_vma_all();
+    proc.running_on = Some(hart_id());
 
     unsafe { riscv::register::sepc::write(proc.pc) }
 
@@ -197,6 +221,7 @@ pub fn set_userspace_process(proc: &mut Process, user: &mut UserStoredCtx) {
 
 pub fn return_to_userspace(registers: &RiscvRegisters) -> ! {
     enable_user_trap();
+    unlock_kernel();
     unsafe {
         asm!(
             "ld ra, 8 * 0(t6)",
@@ -283,6 +308,8 @@ unsafe extern "C" fn user_trap() -> ! {
         "add ra, sp, 8 * 32",
         "csrw sscratch, ra",
 
+        "ld tp, 8 * 32(sp)", // hart_id
+
         "call {enable_kernel_trap}",
 
         "mv a0, sp",
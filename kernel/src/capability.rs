@@ -1,118 +1,375 @@
+use crate::loan::Loan;
 use crate::page::{PageTable, virt_to_phys};
-use crate::process::PROCESS_COUNT;
 use crate::sync::Mutex;
-use crate::virtual_memory::VirtualMemoryRawMapping;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec;
 use alloc::vec::Vec;
 use core::marker::PhantomData;
-use core::ops::Range;
+use core::ops::Deref;
 use core::sync::atomic::{AtomicUsize, Ordering};
 use deravel_types::*;
 
+/// Methods return None when the request doesn't fit the kind of handler. Kernel capabilities can be
+/// copied freely, so a stale one may refer to a slot since reused by an unrelated handler.
 pub trait Handler<T> {
-    fn call_method(&'static self, method: usize, args: &[u8], sender: ProcessId) -> Vec<u8>;
+    fn call_method(
+        &'static self,
+        method: usize,
+        args: &[u8],
+        sender: ProcessId,
+        loans: &mut [Loan],
+    ) -> Option<Vec<u8>>;
 
-    fn map_stream(&self, stream: usize) -> &'static UntypedRingBuffer;
+    fn map_stream(&self, stream: usize) -> Option<&'static UntypedRingBuffer>;
 
-    fn shared_memory_map(
+    fn shared_memory_map(&self, mapper: ProcessId, virt: usize, page_table: &mut PageTable);
+
+    fn shared_memory_unmap(&self, mapper: ProcessId, virt: usize, page_table: &mut PageTable);
+
+    /// Maps a page of a demand-paged mapping, returning false if it doesn't page in on access.
+    fn shared_memory_fault(
         &self,
         virt: usize,
+        page_index: usize,
         page_table: &mut PageTable,
-        vmms: &mut Vec<(Range<usize>, &'static (dyn VirtualMemoryRawMapping + Sync))>,
-    );
+    ) -> bool;
 
-    fn shared_memory_size(&self) -> usize;
+    fn shared_memory_size(&self) -> Option<usize>;
 }
 
 pub trait RawHandler {
-    fn call_method(&'static self, method: usize, args: &[u8], sender: ProcessId) -> Vec<u8>;
+    fn call_method(
+        &'static self,
+        method: usize,
+        args: &[u8],
+        sender: ProcessId,
+        loans: &mut [Loan],
+    ) -> Option<Vec<u8>>;
+
+    fn map_stream(&self, stream: usize) -> Option<&'static UntypedRingBuffer>;
+
+    fn shared_memory_map(&self, mapper: ProcessId, virt: usize, page_table: &mut PageTable);
 
-    fn map_stream(&self, stream: usize) -> &'static UntypedRingBuffer;
+    fn shared_memory_unmap(&self, mapper: ProcessId, virt: usize, page_table: &mut PageTable);
 
-    fn shared_memory_map(
+    fn shared_memory_fault(
         &self,
         virt: usize,
+        page_index: usize,
         page_table: &mut PageTable,
-        vmms: &mut Vec<(Range<usize>, &'static (dyn VirtualMemoryRawMapping + Sync))>,
-    );
+    ) -> bool;
 
-    fn shared_memory_size(&self) -> usize;
+    fn shared_memory_size(&self) -> Option<usize>;
 }
 
 #[repr(transparent)]
 struct TypedHandler<T, H: ?Sized>(PhantomData<T>, H);
 
-static CAPABILITY_PAGES: [CapabilityPage; PROCESS_COUNT + 1] =
-    [const { CapabilityPage([const { CapabilityCertificate::new() }; _]) }; _];
+/// Keeps the handler of a kernel capability alive, either as long as the capability is valid or as
+/// long as a process maps it.
+#[derive(Clone)]
+pub struct HandlerRef {
+    handler: &'static (dyn RawHandler + Sync),
+    _owner: Option<Arc<dyn RawHandler + Send + Sync>>,
+}
+
+static KERNEL_CAPABILITY_PAGE: CapabilityPage =
+    CapabilityPage([const { CapabilityCertificate::new() }; _]);
+
+static UNUSED_CAPABILITY_PAGE: CapabilityPage =
+    CapabilityPage([const { CapabilityCertificate::new() }; _]);
+
+static CAPABILITY_PAGES: Mutex<Vec<&'static CapabilityPage>> = Mutex::new(Vec::new());
 
 static ALLOCATED_COUNT: AtomicUsize = AtomicUsize::new(0);
 
-static HANDLERS: [Mutex<Option<&'static (dyn RawHandler + Sync)>>;
-    PAGE_SIZE / size_of::<CapabilityCertificateValue>()] = [const { Mutex::new(None) }; _];
+// Freed slots are reused oldest first, and only once no fresh ones are left, so that stale copies of
+// a released capability are unlikely to refer to a new handler.
+static FREE_HANDLER_SLOTS: Mutex<VecDeque<usize>> = Mutex::new(VecDeque::new());
+
+const HANDLER_SLOTS: usize = PAGE_SIZE / size_of::<CapabilityCertificateValue>();
+
+static HANDLERS: [Mutex<Option<HandlerRef>>; HANDLER_SLOTS] = [const { Mutex::new(None) }; _];
 
 impl<T, H: Handler<T> + ?Sized> RawHandler for TypedHandler<T, H> {
-    fn call_method(&'static self, method: usize, args: &[u8], sender: ProcessId) -> Vec<u8> {
-        self.1.call_method(method, args, sender)
+    fn call_method(
+        &'static self,
+        method: usize,
+        args: &[u8],
+        sender: ProcessId,
+        loans: &mut [Loan],
+    ) -> Option<Vec<u8>> {
+        self.1.call_method(method, args, sender, loans)
     }
 
-    fn map_stream(&self, stream: usize) -> &'static UntypedRingBuffer {
+    fn map_stream(&self, stream: usize) -> Option<&'static UntypedRingBuffer> {
         self.1.map_stream(stream)
     }
 
-    fn shared_memory_map(
+    fn shared_memory_map(&self, mapper: ProcessId, virt: usize, page_table: &mut PageTable) {
+        self.1.shared_memory_map(mapper, virt, page_table)
+    }
+
+    fn shared_memory_unmap(&self, mapper: ProcessId, virt: usize, page_table: &mut PageTable) {
+        self.1.shared_memory_unmap(mapper, virt, page_table)
+    }
+
+    fn shared_memory_fault(
         &self,
         virt: usize,
+        page_index: usize,
         page_table: &mut PageTable,
-        vmms: &mut Vec<(Range<usize>, &'static (dyn VirtualMemoryRawMapping + Sync))>,
-    ) {
-        self.1.shared_memory_map(virt, page_table, vmms)
+    ) -> bool {
+        self.1.shared_memory_fault(virt, page_index, page_table)
     }
 
-    fn shared_memory_size(&self) -> usize {
+    fn shared_memory_size(&self) -> Option<usize> {
         self.1.shared_memory_size()
     }
 }
 
-pub fn grant_kernel_capability<T: 'static + Sync>(
+impl<T, H: Handler<T> + ?Sized> Handler<T> for Arc<H> {
+    fn call_method(
+        &'static self,
+        method: usize,
+        args: &[u8],
+        sender: ProcessId,
+        loans: &mut [Loan],
+    ) -> Option<Vec<u8>> {
+        H::call_method(self, method, args, sender, loans)
+    }
+
+    fn map_stream(&self, stream: usize) -> Option<&'static UntypedRingBuffer> {
+        H::map_stream(self, stream)
+    }
+
+    fn shared_memory_map(&self, mapper: ProcessId, virt: usize, page_table: &mut PageTable) {
+        H::shared_memory_map(self, mapper, virt, page_table)
+    }
+
+    fn shared_memory_unmap(&self, mapper: ProcessId, virt: usize, page_table: &mut PageTable) {
+        H::shared_memory_unmap(self, mapper, virt, page_table)
+    }
+
+    fn shared_memory_fault(
+        &self,
+        virt: usize,
+        page_index: usize,
+        page_table: &mut PageTable,
+    ) -> bool {
+        H::shared_memory_fault(self, virt, page_index, page_table)
+    }
+
+    fn shared_memory_size(&self) -> Option<usize> {
+        H::shared_memory_size(self)
+    }
+}
+
+impl Deref for HandlerRef {
+    type Target = dyn RawHandler + Sync;
+
+    fn deref(&self) -> &Self::Target {
+        self.handler
+    }
+}
+
+/// Grants a capability to a handler that gets dropped once the capability is released and no
+/// process maps it anymore.
+pub fn grant_shared_kernel_capability<
+    T: Send + Sync + 'static,
+    H: Handler<T> + Send + Sync + 'static,
+>(
     grantee: ProcessId,
-    handler: &'static (impl Handler<T> + Sync),
+    handler: Arc<H>,
 ) -> Capability<T> {
-    let cap = reserve_kernel_capability(handler);
+    let owner = Arc::new(TypedHandler(PhantomData, handler));
+    let handler = HandlerRef {
+        // The owner is kept alive for as long as the reference is.
+        handler: unsafe { &*Arc::as_ptr(&owner) },
+        _owner: Some(owner),
+    };
+    grant_reserved(grantee, reserve_handler_slot(handler))
+}
+
+pub fn reserve_kernel_capability<T: 'static + Sync, H: Handler<T> + Sync>(
+    handler: &'static H,
+) -> Capability<T> {
+    reserve_handler_slot(HandlerRef {
+        handler: unsafe {
+            core::mem::transmute::<&'static H, &'static TypedHandler<T, H>>(handler)
+        },
+        _owner: None,
+    })
+}
+
+/// Drops the handler of a kernel capability that is no longer granted to anyone, so its slot can be
+/// reused. Processes mapping it keep the handler alive.
+pub fn release_kernel_capability(local_index: usize) {
+    KERNEL_CAPABILITY_PAGE.0[local_index].clear(Ordering::Relaxed);
+    let handler = HANDLERS[local_index].lock().take();
+    if handler.is_some() {
+        FREE_HANDLER_SLOTS.lock().push_back(local_index);
+    }
+}
+
+// The reference is only valid until the capability is released, so it must not be held across
+// anything that could release it.
+pub fn get_handler(local_index: usize) -> &'static (dyn RawHandler + Sync) {
+    HANDLERS[local_index].lock().as_ref().unwrap().handler
+}
+
+pub fn get_handler_ref(local_index: usize) -> HandlerRef {
+    HANDLERS[local_index].lock().clone().unwrap()
+}
+
+fn grant_reserved<T>(grantee: ProcessId, cap: Capability<T>) -> Capability<T> {
     // TODO: Race condition, PID 0 can use the capability.
-    kernel_capability_page().0[cap.local_index()].store(
+    KERNEL_CAPABILITY_PAGE.0[cap.local_index()].store(
         CapabilityCertificateValue::granted(grantee),
         Ordering::Relaxed,
     );
     cap
 }
 
-pub fn reserve_kernel_capability<T: 'static + Sync, H: Handler<T> + Sync>(
-    handler: &'static H,
-) -> Capability<T> {
-    let local_index = ALLOCATED_COUNT.fetch_add(1, Ordering::Relaxed);
-    *HANDLERS[local_index].lock() =
-        Some(unsafe { core::mem::transmute::<&'static H, &'static TypedHandler<T, H>>(handler) });
+fn reserve_handler_slot<T>(handler: HandlerRef) -> Capability<T> {
+    let mut free_slots = FREE_HANDLER_SLOTS.lock();
+    let local_index = if ALLOCATED_COUNT.load(Ordering::Relaxed) < HANDLER_SLOTS {
+        ALLOCATED_COUNT.fetch_add(1, Ordering::Relaxed)
+    } else {
+        free_slots
+            .pop_front()
+            .expect("out of kernel capability slots")
+    };
+    drop(free_slots);
+    *HANDLERS[local_index].lock() = Some(handler);
     unsafe { Capability::new(RawCapability::new(Actor::Kernel, local_index)) }
 }
 
-pub fn get_handler(local_index: usize) -> &'static (dyn RawHandler + Sync) {
-    *HANDLERS[local_index].lock().as_ref().unwrap()
+pub fn validate_capability(
+    cap: RawCapability,
+    claimer: ProcessId,
+) -> Result<RawCapability, CapabilityError> {
+    cap.validate_with(claimer, |certifier| &capability_page(certifier).0)
+}
+
+pub fn validate_capability_rights(
+    cap: RawCapability,
+    claimer: ProcessId,
+) -> Result<(RawCapability, u16), CapabilityError> {
+    cap.validate_rights_with(claimer, |certifier| &capability_page(certifier).0)
+}
+
+pub fn apply_transfers(
+    sender: ProcessId,
+    transfers: &[(RawCapability, RawCapability)],
+    receiver: Actor,
+) -> Result<(), CapabilityError> {
+    for (cap, _) in transfers {
+        validate_capability(*cap, sender)?;
+    }
+    for (cap, slot) in transfers {
+        capability_certificate(*slot).store(
+            CapabilityCertificateValue::forwarded(receiver, *cap),
+            Ordering::Relaxed,
+        );
+    }
+    Ok(())
 }
 
 pub fn capability_certificate(cap: RawCapability) -> &'static CapabilityCertificate {
-    match cap.certifier() {
-        Actor::Userspace(pid) => &capability_page(pid).0[cap.local_index()],
-        Actor::Kernel => &kernel_capability_page().0[cap.local_index()],
+    &capability_page(cap.certifier()).0[cap.local_index()]
+}
+
+pub fn allocate_capability_page(pid: ProcessId) {
+    let mut pages = CAPABILITY_PAGES.lock();
+    let index = pid.as_u16() as usize - 1;
+    if index == pages.len() {
+        pages.push(Box::leak(unsafe { Box::new_zeroed().assume_init() }));
+    }
+    assert!(
+        index < pages.len(),
+        "capability page allocated out of order"
+    );
+}
+
+/// Revokes every capability certified by or held by the process, along with everything forwarded
+/// from them, and drops the kernel handlers it was granted.
+pub fn release_capability_page(pid: ProcessId) {
+    let mut revoked = Vec::new();
+    let pages = CAPABILITY_PAGES.lock();
+    let certifiers = pages
+        .iter()
+        .enumerate()
+        .map(|(page_index, page)| (ProcessId::new(page_index as u16 + 1).into(), *page));
+    for (certifier, page) in certifiers.chain([(Actor::Kernel, &KERNEL_CAPABILITY_PAGE)]) {
+        for (local_index, certificate) in page.0.iter().enumerate() {
+            let refers_to_pid = match certificate.load(Ordering::Relaxed).unpack() {
+                CapabilityCertificateUnpacked::Empty => false,
+                CapabilityCertificateUnpacked::Granted { grantee } => {
+                    certifier == Actor::Userspace(pid) || grantee == Actor::Userspace(pid)
+                }
+                CapabilityCertificateUnpacked::Forwarded {
+                    forwardee, inner, ..
+                } => {
+                    certifier == Actor::Userspace(pid)
+                        || forwardee == Actor::Userspace(pid)
+                        || inner.certifier() == Actor::Userspace(pid)
+                }
+            };
+            if refers_to_pid {
+                revoked.push(RawCapability::new(certifier, local_index));
+            }
+        }
+    }
+    drop(pages);
+    for cap in revoked {
+        revoke_capability(cap);
+        if cap.certifier() == Actor::Kernel {
+            release_kernel_capability(cap.local_index());
+        }
     }
 }
 
-fn capability_page(pid: ProcessId) -> &'static CapabilityPage {
-    &CAPABILITY_PAGES[pid.as_u16() as usize]
+pub fn revoke_capability(cap: RawCapability) {
+    capability_certificate(cap).clear(Ordering::Relaxed);
+    let pages = CAPABILITY_PAGES.lock();
+    let mut revoked = vec![cap];
+    while let Some(cap) = revoked.pop() {
+        let certifiers = pages
+            .iter()
+            .enumerate()
+            .map(|(page_index, page)| (ProcessId::new(page_index as u16 + 1).into(), *page));
+        for (certifier, page) in certifiers.chain([(Actor::Kernel, &KERNEL_CAPABILITY_PAGE)]) {
+            for (local_index, certificate) in page.0.iter().enumerate() {
+                if let CapabilityCertificateUnpacked::Forwarded { inner, .. } =
+                    certificate.load(Ordering::Relaxed).unpack()
+                    && inner == cap
+                {
+                    certificate.clear(Ordering::Relaxed);
+                    revoked.push(RawCapability::new(certifier, local_index));
+                }
+            }
+        }
+    }
 }
 
-fn kernel_capability_page() -> &'static CapabilityPage {
-    &CAPABILITY_PAGES[0]
+pub fn capability_page_physical_address(certifier: Actor) -> Option<usize> {
+    let page = match certifier {
+        Actor::Userspace(pid) => *CAPABILITY_PAGES.lock().get(pid.as_u16() as usize - 1)?,
+        Actor::Kernel => &KERNEL_CAPABILITY_PAGE,
+    };
+    Some(virt_to_phys(page as *const _ as usize))
 }
 
-pub fn capability_pages_physical_address() -> usize {
-    virt_to_phys(&CAPABILITY_PAGES as *const _ as usize)
+fn capability_page(certifier: Actor) -> &'static CapabilityPage {
+    match certifier {
+        Actor::Userspace(pid) => CAPABILITY_PAGES
+            .lock()
+            .get(pid.as_u16() as usize - 1)
+            .copied()
+            .unwrap_or(&UNUSED_CAPABILITY_PAGE),
+        Actor::Kernel => &KERNEL_CAPABILITY_PAGE,
+    }
 }
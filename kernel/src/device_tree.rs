@@ -21,3 +21,10 @@ fn find_timebase_frequency(dt: &Fdt) -> Option<usize> {
         .property("timebase-frequency")?
         .as_usize()
 }
+
+pub fn boot_argument<'a>(dt: &Fdt<'a>, name: &str) -> Option<&'a str> {
+    dt.chosen()
+        .bootargs()?
+        .split_ascii_whitespace()
+        .find_map(|arg| arg.strip_prefix(name)?.strip_prefix('='))
+}
@@ -3,14 +3,12 @@
 #![allow(clippy::match_single_binding)]
 
 use crate::capability::Handler;
+use crate::loan::Loan;
 use crate::page::PageTable;
 use crate::stack::UserCtx;
 use crate::user::{UserPtr, UserSyscallError};
-use crate::virtual_memory::VirtualMemoryRawMapping;
 use alloc::string::String;
-use alloc::vec;
 use alloc::vec::Vec;
-use core::ops::Range;
 use deravel_types::abi::to_reg;
 use deravel_types::*;
 
@@ -1,8 +1,7 @@
-use crate::heap::granularity::page_granular_vec;
-use crate::page::{PageFlags, virt_to_phys};
+use crate::anonymous::{AnonymousPage, AnonymousRegion};
+use crate::page::{Page, PageFlags, virt_to_phys};
 use crate::process::Process;
-use crate::util::untyped_box::UntypedBox;
-use alloc::sync::Arc;
+use alloc::boxed::Box;
 use core::marker::PhantomData;
 use deravel_types::PAGE_SIZE;
 use deravel_types::memory::USER_ELF;
@@ -65,12 +64,15 @@ pub fn load_elf<T, U: AsRef<[u8]>>(elf_bytes: &'static Elf<T, U>, proc: &mut Pro
         let flags = paging_flags(&segment);
 
         if flags.is_writable() {
+            // Instances of the same program share the pages until they write to them.
+            assert!((data.as_ptr() as usize).is_multiple_of(PAGE_SIZE));
             let size = (segment.p_memsz as usize).next_multiple_of(PAGE_SIZE);
-
-            let mut pages = page_granular_vec![0u8; size];
-            pages[..segment.p_filesz as usize].copy_from_slice(data);
-            let pages = Arc::new(UntypedBox::new(pages.into_boxed_slice()));
-            proc.alloc_at(segment.p_vaddr as usize, pages, flags);
+            let pages = (0..size)
+                .step_by(PAGE_SIZE)
+                .map(|offset| writable_page(&segment, data, elf_bytes.1.as_ref(), offset))
+                .collect();
+            let region = AnonymousRegion::new(pages, flags);
+            proc.map_anonymous(segment.p_vaddr as usize, region);
         } else {
             assert!((data.as_ptr() as usize).is_multiple_of(PAGE_SIZE));
             assert!(elf_data_is_zero_padded(&segment, elf_bytes.1.as_ref()));
@@ -102,6 +104,30 @@ fn paging_flags(segment: &ProgramHeader) -> PageFlags {
     }
 }
 
+fn writable_page(
+    segment: &ProgramHeader,
+    data: &[u8],
+    elf_bytes: &[u8],
+    offset: usize,
+) -> AnonymousPage {
+    let file_size = segment.p_filesz as usize;
+    if offset >= file_size {
+        return AnonymousPage::Zero;
+    }
+    let page_start = segment.p_offset as usize + offset;
+    let padding = elf_bytes.get(page_start + file_size - offset..page_start + PAGE_SIZE);
+    if offset + PAGE_SIZE <= file_size
+        || padding.is_some_and(|padding| padding.iter().all(|&b| b == 0))
+    {
+        return AnonymousPage::Shared(virt_to_phys(data[offset..].as_ptr() as usize));
+    }
+    // The page is shared with whatever follows the segment in the file, so it needs its own copy
+    // with the rest zeroed.
+    let mut page = unsafe { Box::<Page>::new_zeroed().assume_init() };
+    page.0[..file_size - offset].copy_from_slice(&data[offset..]);
+    AnonymousPage::Private(page)
+}
+
 fn elf_data_is_zero_padded(segment: &ProgramHeader, elf_bytes: &[u8]) -> bool {
     let file_segment_start = segment.p_offset as usize;
     let file_segment_fake_end = file_segment_start + segment.p_filesz as usize;
@@ -0,0 +1,102 @@
+use crate::arch::{
+    initial_switch_to_userspace, initialize_early_trap, initialize_interrupts, secondary_start,
+};
+use crate::page::virt_to_phys;
+use crate::plic::initialize_plic_context;
+use crate::sbi;
+use crate::stack::KernelStack;
+use crate::sync::lock_kernel;
+use alloc::boxed::Box;
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use fdt::Fdt;
+use log::{info, warn};
+
+pub const MAX_HARTS: usize = usize::BITS as usize;
+
+const HSM_EXTENSION: usize = 0x48534D;
+
+/// Read by the secondary hart entry point while its MMU is still disabled.
+#[repr(C)]
+pub struct SecondaryBoot {
+    satp: usize,
+    stack_top: usize,
+    entry: usize,
+}
+
+// Bitmask of harts sleeping in wfi because they found nothing to run.
+static IDLE_HARTS: AtomicUsize = AtomicUsize::new(0);
+
+// The kernel doesn't use thread-local storage, so tp is free to hold the hart ID. Userspace can
+// overwrite it, so trap entry restores it from the hart's stored context.
+pub fn hart_id() -> usize {
+    let id;
+    unsafe { asm!("mv {}, tp", out(reg) id) };
+    id
+}
+
+pub fn set_hart_id(id: usize) {
+    unsafe { asm!("mv tp, {}", in(reg) id) };
+}
+
+/// Starts every other hart listed in the device tree. They wait for the kernel lock, so they only
+/// begin scheduling once the boot hart leaves the kernel.
+pub fn start_secondary_harts(dt: &Fdt) {
+    if !sbi::probe_extension(HSM_EXTENSION) {
+        return warn!("secondary harts not started as SBI HSM is unavailable");
+    }
+    // The boot page table is still active and identity maps the lower half, which the entry point
+    // needs to survive enabling the MMU.
+    let satp = riscv::register::satp::read().bits();
+    for cpu in dt.cpus() {
+        let id = cpu.ids().first();
+        let disabled =
+            cpu.property("status").and_then(|status| status.as_str()) == Some("disabled");
+        if id == hart_id() || disabled {
+            continue;
+        }
+        if id >= MAX_HARTS {
+            warn!("hart {id} not started as its ID is too large");
+            continue;
+        }
+        // The stack is only used until the hart first switches to userspace.
+        let stack = Box::leak(KernelStack::new());
+        let boot = Box::leak(Box::new(SecondaryBoot {
+            satp,
+            stack_top: &raw const stack.ctx as usize,
+            entry: secondary_main as *const () as usize,
+        }));
+        let start_addr = virt_to_phys(secondary_start as *const u8) as usize;
+        let opaque = virt_to_phys(boot as *const SecondaryBoot) as usize;
+        match sbi::hart_start(id, start_addr, opaque) {
+            Ok(()) => info!("started hart {id}"),
+            Err(err) => warn!("failed to start hart {id}, {err:?}"),
+        }
+    }
+}
+
+pub extern "C" fn secondary_main(hart_id: usize) -> ! {
+    set_hart_id(hart_id);
+    initialize_early_trap();
+    lock_kernel();
+    initialize_plic_context();
+    initialize_interrupts();
+    initial_switch_to_userspace()
+}
+
+pub fn set_idle(idle: bool) {
+    let hart = 1 << hart_id();
+    if idle {
+        IDLE_HARTS.fetch_or(hart, Ordering::Relaxed);
+    } else {
+        IDLE_HARTS.fetch_and(!hart, Ordering::Relaxed);
+    }
+}
+
+/// Sends an IPI to every idle hart, so they look for something to run before their deadline.
+pub fn wake_idle_harts() {
+    let idle = IDLE_HARTS.load(Ordering::Relaxed);
+    if idle != 0 {
+        sbi::send_ipi(idle, 0).unwrap();
+    }
+}
@@ -1,3 +1,4 @@
+use crate::plic::{plic_claim, plic_complete};
 use crate::sync::Mutex;
 use core::sync::atomic::{AtomicUsize, Ordering};
 
@@ -29,3 +30,19 @@ pub fn register_interrupt(
         handler,
     });
 }
+
+pub fn handle_external_interrupt() {
+    let irq = plic_claim();
+    if irq == 0 {
+        return;
+    }
+    for ie in &INTERRUPTS {
+        let ie = ie.lock();
+        if let Some(ie) = *ie
+            && ie.plic_number == irq
+        {
+            ie.handler.handle();
+        }
+    }
+    plic_complete(irq);
+}
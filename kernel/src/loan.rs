@@ -0,0 +1,97 @@
+use crate::heap::granularity::PageGranular;
+use crate::page::{PageFlags, virt_to_phys};
+use crate::process::Process;
+use crate::util::untyped_box::UntypedBox;
+use alloc::sync::Arc;
+use core::alloc::{AllocError, Layout};
+use core::ops::Range;
+use core::ptr::NonNull;
+use deravel_types::PAGE_SIZE;
+
+/// Range of a process allocation lent to the callee of a single blocking call.
+pub struct Loan {
+    backing: Arc<UntypedBox<PageGranular>>,
+    offset: usize,
+    len: usize,
+    writable: bool,
+}
+
+pub struct MappedLoan {
+    pub address: usize,
+    pub len: usize,
+    backing: Arc<UntypedBox<PageGranular>>,
+    // The page-aligned part of the backing allocation mapped at `base`.
+    pages: Range<usize>,
+    base: usize,
+}
+
+impl Loan {
+    pub fn new(
+        backing: Arc<UntypedBox<PageGranular>>,
+        offset: usize,
+        len: usize,
+        writable: bool,
+    ) -> Loan {
+        assert!(offset + len <= backing.byte_size());
+        Loan {
+            backing,
+            offset,
+            len,
+            writable,
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        let base = self.backing.as_untyped_ptr() as *const u8;
+        unsafe { core::slice::from_raw_parts(base.add(self.offset), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        assert!(self.writable, "loan is read-only");
+        let base = self.backing.as_untyped_ptr() as *mut u8;
+        unsafe { core::slice::from_raw_parts_mut(base.add(self.offset), self.len) }
+    }
+
+    pub fn map_into(self, proc: &mut Process) -> Result<MappedLoan, AllocError> {
+        if self.len == 0 {
+            return Ok(MappedLoan {
+                address: NonNull::<u8>::dangling().addr().get(),
+                len: 0,
+                backing: self.backing,
+                pages: 0..0,
+                base: 0,
+            });
+        }
+        let flags = if self.writable {
+            PageFlags::read_write().user()
+        } else {
+            PageFlags::readonly().user()
+        };
+        let pages = self.offset / PAGE_SIZE * PAGE_SIZE
+            ..(self.offset + self.len).next_multiple_of(PAGE_SIZE);
+        let layout = Layout::from_size_align(pages.len(), PAGE_SIZE).unwrap();
+        let base = proc.heap.alloc(layout)?;
+        let phys = virt_to_phys(self.backing.as_untyped_ptr().addr()) + pages.start;
+        proc.page_table.map(base, phys, pages.len(), flags);
+        Ok(MappedLoan {
+            address: base + self.offset - pages.start,
+            len: self.len,
+            backing: self.backing,
+            pages,
+            base,
+        })
+    }
+}
+
+impl MappedLoan {
+    /// Removes the loan from the callee's address space. The caller must flush its TLB.
+    pub fn unmap(self, proc: &mut Process) {
+        if self.pages.is_empty() {
+            return;
+        }
+        let phys = virt_to_phys(self.backing.as_untyped_ptr().addr()) + self.pages.start;
+        proc.page_table.unmap(self.base, phys, self.pages.len());
+        let layout = Layout::from_size_align(self.pages.len(), PAGE_SIZE).unwrap();
+        proc.heap.dealloc(self.base, layout);
+    }
+}
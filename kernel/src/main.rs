@@ -21,24 +21,29 @@
 
 extern crate alloc;
 
+mod anonymous;
 mod arch;
 mod capability;
 mod device_tree;
 mod drvli;
 mod elf;
+mod hart;
 mod heap;
 mod interrupt;
+mod loan;
 mod log;
 mod page;
 mod pci;
 mod plic;
 mod process;
+mod rtc;
 mod sbi;
 mod shared_memory;
 mod shutdown;
 mod stack;
 mod sync;
 mod syscall;
+mod timer;
 mod user;
 mod util;
 mod virtio;
@@ -52,38 +57,53 @@ use crate::capability::reserve_kernel_capability;
 use crate::device_tree::initialize_timebase_frequency;
 use crate::drvli::dispatch_syscall;
 use crate::elf::elf;
+use crate::hart::{set_hart_id, start_secondary_harts};
 use crate::heap::initialize_heap;
-use crate::interrupt::INTERRUPTS;
+use crate::interrupt::handle_external_interrupt;
 use crate::log::initialize_log;
 use crate::pci::initialize_all_pci;
-use crate::plic::{initialize_plic, plic_claim, plic_complete};
-use crate::process::{kill, kill_manual, reserve_process, schedule_userspace};
+use crate::plic::{initialize_plic, initialize_plic_context};
+use crate::process::scheduler::initialize_scheduler;
+use crate::process::{
+    ProcessState, kill, kill_manual, map_foreign_capability_page, reserve_process,
+    schedule_userspace,
+};
+use crate::rtc::initialize_rtc;
 use crate::sbi::{ResetReason, ResetType, log_sbi_metadata};
 use crate::shutdown::KernelShutdown;
 use crate::stack::UserCtx;
+use crate::sync::lock_kernel;
 use crate::syscall::SyscallAction;
+use crate::timer::initialize_time_slice;
 use ::log::*;
+use core::ops::DerefMut;
 use core::panic::PanicInfo;
-use deravel_types::memory::USER_STACK_GUARD;
+use deravel_types::memory::{USER_CAPABILITIES, USER_STACK_GUARD};
 use deravel_types::*;
 use fdt::Fdt;
 use riscv::interrupt::Trap;
 use riscv::interrupt::supervisor::{Exception, Interrupt};
 
-extern "C" fn main(_hart_id: u64, dt_ptr: *const u8) -> ! {
+extern "C" fn main(hart_id: u64, dt_ptr: *const u8) -> ! {
+    set_hart_id(hart_id as usize);
+    lock_kernel();
     initialize_log();
     initialize_early_trap();
     let dt = unsafe { Fdt::from_ptr(dt_ptr) }.unwrap();
     initialize_timebase_frequency(&dt);
+    initialize_time_slice(&dt);
+    initialize_rtc(&dt);
+    initialize_scheduler(&dt);
     log_sbi_metadata();
     initialize_heap(&dt, dt_ptr);
     let (virtio_blk, virtio_net, virtio_gpu, virtio_keyboard, virtio_mouse) =
         initialize_all_pci(&dt);
     initialize_plic(&dt);
+    initialize_plic_context();
     initialize_interrupts();
 
-    let fat = reserve_process(elf!(FatFs, "deravel-filesystem-fat"));
-    let windowing = reserve_process(elf!(Windowing, "windowing"));
+    let fat = reserve_process(elf!(FatFs, "deravel-filesystem-fat")).unwrap();
+    let windowing = reserve_process(elf!(Windowing, "windowing")).unwrap();
     windowing.spawn(WindowingArgs {
         display: reserve_kernel_capability(virtio_gpu),
         keyboard: reserve_kernel_capability(virtio_keyboard),
@@ -99,10 +119,16 @@ extern "C" fn main(_hart_id: u64, dt_ptr: *const u8) -> ! {
         drive: reserve_kernel_capability(virtio_blk),
     });
 
+    start_secondary_harts(&dt);
     initial_switch_to_userspace();
 }
 
 extern "C" fn on_user_trap(user: &mut UserCtx) -> ! {
+    lock_kernel();
+    // Another hart could have killed the process while it was running here.
+    if user.process().state == ProcessState::Finished {
+        schedule_userspace(&mut user.stored)
+    }
     match on_user_trap_impl(user) {
         Ok(()) => return_to_userspace(&user.registers),
         Err(SyscallAction::UserErr(err)) => {
@@ -135,19 +161,14 @@ fn on_user_trap_impl(user: &mut UserCtx) -> Result<(), SyscallAction> {
             }
         }
     } else if scause == Ok(Trap::Interrupt(Interrupt::SupervisorTimer)) {
-        sbi::set_timer(u64::MAX);
-        Ok(())
+        user.process().pc = user_pc;
+        Err(SyscallAction::Yield)
     } else if scause == Ok(Trap::Interrupt(Interrupt::SupervisorExternal)) {
-        let irq = plic_claim();
-        for ie in &INTERRUPTS {
-            let ie = ie.lock();
-            if let Some(ie) = *ie
-                && ie.plic_number == irq
-            {
-                ie.handler.handle();
-            }
-        }
-        plic_complete(irq);
+        handle_external_interrupt();
+        Ok(())
+    } else if scause == Ok(Trap::Interrupt(Interrupt::SupervisorSoft)) {
+        // A wakeup meant for when this hart was idle, which it no longer is.
+        unsafe { riscv::register::sip::clear_ssoft() };
         Ok(())
     } else if is_page_fault(scause) {
         handle_page_fault(user, stval)
@@ -161,17 +182,38 @@ fn handle_page_fault(user: &mut UserCtx, stval: usize) -> Result<(), SyscallActi
         kill!(user, "stack overflow")
     }
     let mut proc = user.process();
-    let Some(vmm) = proc
-        .virtual_memory_mappings
+    if USER_CAPABILITIES.contains(&stval) && !proc.page_table.is_mapped(stval) {
+        if map_foreign_capability_page(&mut proc.page_table, stval).is_err() {
+            kill!(user, proc, "forbidden access to {stval:#x}")
+        }
+        proc.flush_tlb();
+        return Ok(());
+    }
+    match proc.handle_anonymous_fault(stval) {
+        Ok(true) => {
+            proc.flush_tlb();
+            return Ok(());
+        }
+        Ok(false) => {}
+        Err(_) => kill!(user, proc, "out of memory on access to {stval:#x}"),
+    }
+    let mut guard = proc;
+    let proc = guard.deref_mut();
+    let Some(mapping) = proc
+        .shared_mappings
         .iter()
-        .find(|vmm| vmm.0.contains(&stval) && !proc.page_table.is_mapped(stval))
+        .find(|mapping| mapping.range.contains(&stval) && !proc.page_table.is_mapped(stval))
     else {
-        kill!(user, "forbidden access to {stval:#x}")
+        kill!(user, guard, "forbidden access to {stval:#x}")
     };
-    let page_index = (stval - vmm.0.start) / PAGE_SIZE;
-    vmm.1
-        .load_page(vmm.0.start, page_index, &mut proc.page_table);
-    riscv::asm::sfence_vma_all();
+    let page_index = (stval - mapping.range.start) / PAGE_SIZE;
+    if !mapping
+        .handler
+        .shared_memory_fault(mapping.range.start, page_index, &mut proc.page_table)
+    {
+        kill!(user, guard, "forbidden access to {stval:#x}")
+    }
+    proc.flush_tlb();
     Ok(())
 }
 
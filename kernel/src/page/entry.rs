@@ -1,6 +1,7 @@
 use crate::page::{Page, PageTable, phys_to_virt, virt_to_phys};
 use alloc::boxed::Box;
 use core::fmt::Write;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use deravel_types::PAGE_SIZE;
 
 // Does not include the V flag, as this one impacts the meaning of other bits.
@@ -16,7 +17,6 @@ pub enum PageTableEntryUnpacked<'a> {
     Invalid,
     Indirect(&'a PageTable),
     Leaf {
-        #[allow(dead_code)]
         flags: PageFlags,
         phys_ptr: *mut Page,
     },
@@ -27,7 +27,6 @@ pub enum PageTableEntryUnpackedMut<'a> {
     Invalid,
     Indirect(&'a mut PageTable),
     Leaf {
-        #[allow(dead_code)]
         flags: PageFlags,
         #[allow(dead_code)]
         phys_ptr: *mut Page,
@@ -39,6 +38,10 @@ const PAGE_R: usize = 1 << 1;
 const PAGE_W: usize = 1 << 2;
 const PAGE_X: usize = 1 << 3;
 const PAGE_U: usize = 1 << 4;
+const PAGE_D: usize = 1 << 7;
+// One of the bits reserved for supervisor software, ignored by the hardware.
+const PAGE_COW: usize = 1 << 8;
+const PAGE_FLAGS_MASK: usize = 0b11_1111_1110;
 
 impl PageFlags {
     pub fn readonly() -> PageFlags {
@@ -61,16 +64,31 @@ impl PageFlags {
         PageFlags(self.0 | PAGE_U)
     }
 
+    /// Read-only mapping of a page shared with other mappings, which gets copied on the first write.
+    pub fn copy_on_write(self) -> PageFlags {
+        PageFlags((self.0 & !PAGE_W) | PAGE_COW)
+    }
+
+    pub fn is_copy_on_write(&self) -> bool {
+        self.0 & PAGE_COW != 0
+    }
+
     pub fn is_writable(&self) -> bool {
         self.0 & PAGE_W != 0
     }
-}
 
-impl PageTableEntry {
-    pub fn invalid() -> PageTableEntry {
-        PageTableEntry(0)
+    pub fn is_user(&self) -> bool {
+        self.0 & PAGE_U != 0
+    }
+
+    /// Whether the page was written to since the D bit was last cleared. The hardware sets it on
+    /// the first write through the mapping.
+    pub fn is_dirty(&self) -> bool {
+        self.0 & PAGE_D != 0
     }
+}
 
+impl PageTableEntry {
     pub fn indirect(table: Box<PageTable>) -> PageTableEntry {
         PageTableEntry(((virt_to_phys(Box::into_raw(table)) as usize / PAGE_SIZE) << 10) | PAGE_V)
     }
@@ -86,7 +104,7 @@ impl PageTableEntry {
             let indirect = phys_to_virt(self.physical_page_pointer() as *const PageTable);
             PageTableEntryUnpacked::Indirect(unsafe { &*indirect })
         } else {
-            let flags = PageFlags(self.0 & 0b1111_1110);
+            let flags = PageFlags(self.0 & PAGE_FLAGS_MASK);
             let phys_ptr = self.physical_page_pointer() as *mut _;
             PageTableEntryUnpacked::Leaf { flags, phys_ptr }
         }
@@ -99,14 +117,25 @@ impl PageTableEntry {
             let indirect = phys_to_virt(self.physical_page_pointer() as *mut PageTable);
             PageTableEntryUnpackedMut::Indirect(unsafe { &mut *indirect })
         } else {
-            let flags = PageFlags(self.0 & 0b1111_1110);
+            let flags = PageFlags(self.0 & PAGE_FLAGS_MASK);
             let phys_ptr = self.physical_page_pointer() as *mut _;
             PageTableEntryUnpackedMut::Leaf { flags, phys_ptr }
         }
     }
 
+    pub fn is_dirty(&self) -> bool {
+        self.0 & PAGE_D != 0
+    }
+
+    pub fn clear_dirty(&mut self) {
+        self.0 &= !PAGE_D;
+    }
+
+    // Other harts can set the A and D bits while the entry is valid, so it is cleared with an
+    // atomic swap to not lose them.
     pub fn take(&mut self) -> PageTableEntry {
-        core::mem::replace(self, PageTableEntry::invalid())
+        let entry = unsafe { AtomicUsize::from_ptr(&mut self.0) };
+        PageTableEntry(entry.swap(0, Ordering::Relaxed))
     }
 
     fn physical_page_pointer(&self) -> usize {
@@ -156,6 +185,12 @@ impl core::fmt::Debug for PageFlags {
         if self.0 & PAGE_U != 0 {
             f.write_char('U')?;
         }
+        if self.0 & PAGE_D != 0 {
+            f.write_char('D')?;
+        }
+        if self.0 & PAGE_COW != 0 {
+            f.write_char('C')?;
+        }
         Ok(())
     }
 }
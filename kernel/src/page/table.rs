@@ -32,6 +32,12 @@ impl PageTable {
 
     #[track_caller]
     pub fn unmap(&mut self, virt: usize, phys: usize, size: usize) {
+        self.unmap_dirty(virt, phys, size);
+    }
+
+    /// Unmaps the range, returning whether the D bit of any of its pages was set.
+    #[track_caller]
+    pub fn unmap_dirty(&mut self, virt: usize, phys: usize, size: usize) -> bool {
         assert!(virt.is_multiple_of(PAGE_SIZE));
         assert!(virt < VIRTUAL_ADDRESSES.end);
         assert!(virt + size <= VIRTUAL_ADDRESSES.end);
@@ -39,13 +45,23 @@ impl PageTable {
         assert!(phys < PHYSICAL_ADDRESSES.end);
         assert!(phys + size <= PHYSICAL_ADDRESSES.end);
         assert!(size.is_multiple_of(PAGE_SIZE));
-        self.unmap_impl(virt, phys, size, 2);
+        self.unmap_impl(virt, phys, size, 2)
     }
 
     pub fn is_mapped(&self, virt: usize) -> bool {
         self.is_mapped_impl(virt, 2)
     }
 
+    pub fn translate(&self, virt: usize) -> Option<(usize, PageFlags)> {
+        self.translate_impl(virt, 2)
+    }
+
+    /// Clears the D bit of the page, returning whether it was set. Needs a TLB flush to make the
+    /// hardware set it again on the next write.
+    pub fn take_dirty(&mut self, virt: usize) -> bool {
+        self.take_dirty_impl(virt, 2)
+    }
+
     fn map_impl(&mut self, virt: usize, phys: usize, size: usize, flags: PageFlags, level: usize) {
         let leaf_size = LEVEL_PAGE_SIZES[level];
         let (prefix, aligned, suffix) = align_by(virt..virt + size, leaf_size);
@@ -91,12 +107,13 @@ impl PageTable {
         }
     }
 
-    fn unmap_impl(&mut self, virt: usize, phys: usize, size: usize, level: usize) {
+    fn unmap_impl(&mut self, virt: usize, phys: usize, size: usize, level: usize) -> bool {
         let leaf_size = LEVEL_PAGE_SIZES[level];
         let (prefix, aligned, suffix) = align_by(virt..virt + size, leaf_size);
+        let mut dirty = false;
 
         if !prefix.is_empty() {
-            self.unwrap_indirect(prefix.start, level).unmap_impl(
+            dirty |= self.unwrap_indirect(prefix.start, level).unmap_impl(
                 prefix.start,
                 phys + (prefix.start - virt),
                 prefix.end - prefix.start,
@@ -107,7 +124,7 @@ impl PageTable {
 
         if !aligned.is_empty() && !(phys + (aligned.start - virt)).is_multiple_of(leaf_size) {
             for v in aligned.step_by(leaf_size) {
-                self.unwrap_indirect(v, level).unmap_impl(
+                dirty |= self.unwrap_indirect(v, level).unmap_impl(
                     v,
                     phys + (v - virt),
                     leaf_size,
@@ -117,12 +134,12 @@ impl PageTable {
             }
         } else {
             for v in aligned.step_by(leaf_size) {
-                self.unmap_leaf(v, phys + (v - virt), level);
+                dirty |= self.unmap_leaf(v, phys + (v - virt), level);
             }
         }
 
         if !suffix.is_empty() {
-            self.unwrap_indirect(suffix.start, level).unmap_impl(
+            dirty |= self.unwrap_indirect(suffix.start, level).unmap_impl(
                 suffix.start,
                 phys + (suffix.start - virt),
                 suffix.end - suffix.start,
@@ -130,6 +147,8 @@ impl PageTable {
             );
             self.check_unmap_indirect(suffix.start, level);
         }
+
+        dirty
     }
 
     fn is_mapped_impl(&self, virt: usize, level: usize) -> bool {
@@ -141,6 +160,32 @@ impl PageTable {
         }
     }
 
+    fn translate_impl(&self, virt: usize, level: usize) -> Option<(usize, PageFlags)> {
+        let vpn_segment = vpn_segment(virt, level);
+        match self.0[vpn_segment].unpack() {
+            PageTableEntryUnpacked::Invalid => None,
+            PageTableEntryUnpacked::Indirect(indirect) => indirect.translate_impl(virt, level - 1),
+            PageTableEntryUnpacked::Leaf { flags, phys_ptr } => {
+                Some((phys_ptr as usize + virt % LEVEL_PAGE_SIZES[level], flags))
+            }
+        }
+    }
+
+    fn take_dirty_impl(&mut self, virt: usize, level: usize) -> bool {
+        let vpn_segment = vpn_segment(virt, level);
+        match self.0[vpn_segment].unpack_mut() {
+            PageTableEntryUnpackedMut::Invalid => false,
+            PageTableEntryUnpackedMut::Indirect(indirect) => {
+                indirect.take_dirty_impl(virt, level - 1)
+            }
+            PageTableEntryUnpackedMut::Leaf { flags, .. } => {
+                let dirty = flags.is_dirty();
+                self.0[vpn_segment].clear_dirty();
+                dirty
+            }
+        }
+    }
+
     fn map_leaf(&mut self, virt: usize, phys: usize, flags: PageFlags, level: usize) {
         let vpn_segment = vpn_segment(virt, level);
         assert!(
@@ -151,10 +196,11 @@ impl PageTable {
         self.0[vpn_segment] = PageTableEntry::leaf(phys, flags);
     }
 
-    fn unmap_leaf(&mut self, virt: usize, phys: usize, level: usize) {
+    fn unmap_leaf(&mut self, virt: usize, phys: usize, level: usize) -> bool {
         let vpn_segment = vpn_segment(virt, level);
-        assert_matches!(self.0[vpn_segment].unpack(), PageTableEntryUnpacked::Leaf { phys_ptr, .. } if phys_ptr as usize == phys);
-        self.0[vpn_segment] = PageTableEntry::invalid();
+        let entry = self.0[vpn_segment].take();
+        assert_matches!(entry.unpack(), PageTableEntryUnpacked::Leaf { phys_ptr, .. } if phys_ptr as usize == phys);
+        entry.is_dirty()
     }
 
     fn map_indirect(&mut self, virt: usize, level: usize) -> &mut PageTable {
@@ -1,8 +1,10 @@
+use crate::hart::{MAX_HARTS, hart_id};
 use crate::page::phys_to_virt;
 use crate::util::volatile::{Volatile, volatile_struct};
-use core::sync::atomic::{AtomicPtr, Ordering};
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
 use fdt::Fdt;
 use fdt::node::FdtNode;
+use log::warn;
 
 volatile_struct! { Plic
     // 0x000000
@@ -26,7 +28,11 @@ volatile_struct! { Context
 const MAX_CONTEXTS: usize = 15872;
 const MAX_SOURCES: usize = 1024;
 
+const SUPERVISOR_EXTERNAL_INTERRUPT: u32 = 9;
+
 static PLIC: AtomicPtr<Plic> = AtomicPtr::null();
+static SUPPORTED_EXTERNAL: AtomicUsize = AtomicUsize::new(0);
+static HART_CONTEXTS: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(usize::MAX) }; _];
 
 pub fn initialize_plic(dt: &Fdt) {
     let mut plic = find_plic(dt).unwrap();
@@ -34,18 +40,41 @@ pub fn initialize_plic(dt: &Fdt) {
     for e in 1..=supported_external.min(MAX_SOURCES - 1) {
         plic.priority().index(e).write(1);
     }
-    for i in 0..supported_external.div_ceil(32) {
-        plic.enable().index(1).index(i).write(!0);
+    SUPPORTED_EXTERNAL.store(supported_external, Ordering::Relaxed);
+    if find_hart_contexts(dt).is_none() {
+        warn!("PLIC contexts missing from device tree, assuming QEMU virt layout");
+        for (hart, context) in HART_CONTEXTS.iter().enumerate() {
+            context.store(2 * hart + 1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Routes every external interrupt to the current hart as well, so whichever hart claims it first
+/// handles it.
+pub fn initialize_plic_context() {
+    let mut plic = get_plic();
+    let context = current_context();
+    for i in 0..SUPPORTED_EXTERNAL.load(Ordering::Relaxed).div_ceil(32) {
+        plic.enable().index(context).index(i).write(!0);
     }
-    plic.contexts().index(1).priority_threshold().write(0);
+    plic.contexts().index(context).priority_threshold().write(0);
 }
 
+/// Returns 0 if another hart claimed the interrupt first.
 pub fn plic_claim() -> u32 {
-    get_plic().contexts().index(1).claim_complete().read()
+    get_plic()
+        .contexts()
+        .index(current_context())
+        .claim_complete()
+        .read()
 }
 
 pub fn plic_complete(irq: u32) {
-    get_plic().contexts().index(1).claim_complete().write(irq);
+    get_plic()
+        .contexts()
+        .index(current_context())
+        .claim_complete()
+        .write(irq);
 }
 
 pub fn plic_node<'a, 'b>(dt: &'b Fdt<'a>) -> Option<FdtNode<'a, 'b>> {
@@ -66,6 +95,46 @@ fn get_plic() -> Volatile<'static, Plic> {
     unsafe { Volatile::new(address) }
 }
 
+fn current_context() -> usize {
+    let context = HART_CONTEXTS[hart_id()].load(Ordering::Relaxed);
+    assert_ne!(
+        context,
+        usize::MAX,
+        "no PLIC context for hart {}",
+        hart_id()
+    );
+    context
+}
+
+// Contexts are numbered by their position in interrupts-extended, which lists the hart-local
+// interrupt controller and the interrupt each context raises there.
+fn find_hart_contexts(dt: &Fdt) -> Option<()> {
+    let lines = plic_node(dt)?.property("interrupts-extended")?.value;
+    for (context, line) in lines.chunks_exact(8).enumerate() {
+        let phandle = u32::from_be_bytes(line[..4].try_into().unwrap());
+        let interrupt = u32::from_be_bytes(line[4..].try_into().unwrap());
+        if interrupt != SUPERVISOR_EXTERNAL_INTERRUPT {
+            continue;
+        }
+        if let Some(hart) = find_hart_by_interrupt_controller(dt, phandle)
+            && hart < MAX_HARTS
+        {
+            HART_CONTEXTS[hart].store(context, Ordering::Relaxed);
+        }
+    }
+    Some(())
+}
+
+fn find_hart_by_interrupt_controller(dt: &Fdt, phandle: u32) -> Option<usize> {
+    let cpu = dt.find_node("/cpus")?.children().find(|cpu| {
+        cpu.children().any(|intc| {
+            intc.name == "interrupt-controller"
+                && intc.property("phandle").and_then(|p| p.as_usize()) == Some(phandle as usize)
+        })
+    })?;
+    Some(cpu.reg()?.next()?.starting_address as usize)
+}
+
 fn supported_external_interrupts(dt: &Fdt) -> Option<usize> {
     plic_node(dt)?.property("riscv,ndev")?.as_usize()
 }
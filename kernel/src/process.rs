@@ -1,27 +1,40 @@
+pub mod handle;
+pub mod scheduler;
 pub mod spawner;
 
+use crate::anonymous::AnonymousRegion;
 use crate::arch::{RiscvRegisters, return_to_userspace, set_userspace_process};
-use crate::capability;
-use crate::capability::{capability_certificate, capability_pages_physical_address};
+use crate::capability::{
+    HandlerRef, allocate_capability_page, capability_certificate, capability_page_physical_address,
+    release_capability_page,
+};
 use crate::device_tree::timebase_frequency;
 use crate::elf::{Elf, load_elf};
+use crate::hart::{hart_id, set_idle, wake_idle_harts};
 use crate::heap::buddy::BuddyAllocator;
-use crate::heap::granularity::{PageGranular, page_granular_vec};
+use crate::heap::granularity::PageGranular;
+use crate::interrupt::handle_external_interrupt;
+use crate::loan::{Loan, MappedLoan};
 use crate::page::{PageFlags, PageTable, map_hh_direct_mapping, virt_to_phys};
+use crate::process::handle::ProcessHandle;
+use crate::process::scheduler::{Candidate, scheduler};
+use crate::rtc::unix_time_at_zero;
+use crate::sbi;
 use crate::shutdown::shutdown;
 use crate::stack::UserStoredCtx;
-use crate::sync::{Mutex, MutexGuard};
+use crate::sync::{Mutex, MutexGuard, lock_kernel, unlock_kernel};
+use crate::timer::{arm_time_slice, now};
 use crate::user::{UserPtr, UserSyscallError};
 use crate::util::untyped_box::UntypedBox;
-use crate::virtual_memory::VirtualMemoryRawMapping;
 use alloc::boxed::Box;
 use alloc::collections::VecDeque;
+use alloc::format;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::alloc::{AllocError, Layout};
 use core::num::NonZeroUsize;
-use core::ops::{DerefMut, Range};
-use core::sync::atomic::{AtomicU16, Ordering};
+use core::ops::Range;
+use core::sync::atomic::Ordering;
 use deravel_types::memory::{USER_CAPABILITIES, USER_HEAP, USER_INPUTS, USER_STACK};
 use deravel_types::*;
 use log::*;
@@ -32,8 +45,9 @@ pub macro kill {
             let mut proc = $proc;
             let pid = proc.id;
             let name = proc.name;
-            error!("killed {name}{pid:?}, {}", format_args!($($tt)*));
-            proc.state = ProcessState::Finished;
+            let message = alloc::format!($($tt)*);
+            error!("killed {name}{pid:?}, {message}");
+            proc.finish(deravel_types::ExitReason::Killed(message));
             drop(proc);
             return Err(crate::syscall::SyscallAction::Yield);
         }
@@ -48,8 +62,9 @@ pub macro kill_manual($user:ident, $($tt:tt)*) {
         let mut proc = $user.process();
         let pid = proc.id;
         let name = proc.name;
-        error!("killed {name}{pid:?}, {}", format_args!($($tt)*));
-        proc.state = ProcessState::Finished;
+        let message = alloc::format!($($tt)*);
+        error!("killed {name}{pid:?}, {message}");
+        proc.finish(deravel_types::ExitReason::Killed(message));
     }
 }
 
@@ -60,6 +75,7 @@ pub enum ProcessState {
     WaitingForReply {
         from: Actor,
         result_buffer: UserPtr<[u8]>,
+        deadline: u64,
     },
     WaitingForStreamMap {
         from: Actor,
@@ -69,29 +85,64 @@ pub enum ProcessState {
         result_buffer: UserPtr<[u8]>,
     },
     ReadyStreamMap {
-        ring: RawCapability,
+        virt: usize,
         declared_size: usize,
     },
+    Waiting {
+        rings: Vec<usize>,
+        deadline: u64,
+    },
 }
 
 pub struct Process {
     pub id: ProcessId,
     pub name: &'static str,
     pub state: ProcessState,
+    pub priority: Priority,
+    pub virtual_runtime: u64,
     pub registers: RiscvRegisters,
     pub pc: usize,
     pub page_table: Box<PageTable>,
     pub heap: BuddyAllocator,
     pub messages: VecDeque<Message>,
-    pub currently_serving: Option<ProcessId>,
-    allocated: Vec<(usize, Arc<UntypedBox<PageGranular>>)>,
-    pub virtual_memory_mappings: Vec<(Range<usize>, &'static (dyn VirtualMemoryRawMapping + Sync))>,
+    pub serving: Vec<ServedCall>,
+    pub next_reply_token: usize,
+    pub oversized_reply: Option<Vec<u8>>,
+    pub loans: Vec<Loan>,
+    pub call_deadline: u64,
+    pub pending_calls: Vec<PendingCall>,
+    pub next_call_id: usize,
+    pub transfers: Vec<(RawCapability, RawCapability)>,
+    pub handle: Arc<ProcessHandle>,
+    // The last element is how much of the process' memory limit the allocation is charged for.
+    allocated: Vec<(usize, Arc<UntypedBox<PageGranular>>, usize)>,
+    anonymous: Vec<(usize, AnonymousRegion)>,
+    memory_limit: usize,
+    memory_committed: usize,
+    pub shared_mappings: Vec<SharedMapping>,
+    stream_mappings: Vec<StreamMapping>,
+    // The hart whose satp points at the page table, from resuming the process until that hart
+    // schedules again. Other harts neither run nor tear down the process meanwhile.
+    pub running_on: Option<usize>,
+}
+
+pub struct SharedMapping {
+    pub range: Range<usize>,
+    pub handler: HandlerRef,
+}
+
+// Kernel streams are mapped directly, so their handlers are only kept alive.
+struct StreamMapping {
+    virt: usize,
+    ring: &'static UntypedRingBuffer,
+    _handler: HandlerRef,
 }
 
 pub struct ProcessReservation<T: ProcessTag, U: 'static> {
     id: ProcessId,
     elf: &'static Elf<T, U>,
     pub export: Capability<T::Export>,
+    pub handle: Arc<ProcessHandle>,
 }
 
 pub struct Message {
@@ -99,14 +150,49 @@ pub struct Message {
     pub method: usize,
     pub args: Vec<u8>,
     pub sender: ProcessId,
+    pub call: Option<usize>,
+    pub loans: Vec<Loan>,
+}
+
+pub struct ServedCall {
+    pub token: usize,
+    pub caller: ProcessId,
+    pub call: Option<usize>,
+    pub loans: Vec<MappedLoan>,
+    pub cancelled: bool,
+}
+
+pub struct PendingCall {
+    pub id: usize,
+    pub from: Actor,
+    pub reply: Option<Vec<u8>>,
+    // Set when the server is torn down, as its process ID could be reused before the caller polls.
+    pub disconnected: bool,
 }
 
-pub const PROCESS_COUNT: usize = 8;
+static PROCESSES: Mutex<Vec<&'static Mutex<Option<Process>>>> = Mutex::new(Vec::new());
+// Freed IDs are reused oldest first, and only once enough others were freed after them, so that IDs
+// kept by other processes are unlikely to refer to an unrelated process.
+static FREE_PROCESS_IDS: Mutex<VecDeque<ProcessId>> = Mutex::new(VecDeque::new());
 
-static PROCESSES: [Mutex<Option<Process>>; PROCESS_COUNT] = [const { Mutex::new(None) }; _];
-static PROCESSES_RESERVED: AtomicU16 = AtomicU16::new(0);
+const PROCESS_ID_REUSE_DELAY: usize = 64;
 
 impl Process {
+    pub fn finish(&mut self, reason: ExitReason) {
+        self.state = ProcessState::Finished;
+        self.handle.notify_exit(reason);
+    }
+
+    /// Makes page table changes visible to the hart running the process. Harts flush their whole
+    /// TLB when switching page tables, so no other hart can have stale entries.
+    pub fn flush_tlb(&self) {
+        match self.running_on {
+            Some(hart) if hart == hart_id() => riscv::asm::sfence_vma_all(),
+            Some(hart) => sbi::remote_sfence_vma(1 << hart, 0, 0, 0).unwrap(),
+            None => {}
+        }
+    }
+
     pub fn alloc(
         &mut self,
         backing: Arc<UntypedBox<PageGranular>>,
@@ -126,17 +212,142 @@ impl Process {
         let size = backing.layout().size();
         let phys = virt_to_phys(backing.as_untyped_ptr().addr());
         self.page_table.map(virt, phys, size, flags);
-        self.allocated.push((virt, backing));
+        self.allocated.push((virt, backing, 0));
+    }
+
+    /// Fails if the committed pages of the region would exceed the memory limit, or if there is no
+    /// free address range left.
+    pub fn alloc_anonymous(&mut self, region: AnonymousRegion) -> Result<*mut u8, AllocError> {
+        self.charge(region.committed_bytes())?;
+        let virt = match self.heap.alloc(region.layout()) {
+            Ok(virt) => virt,
+            Err(err) => {
+                self.memory_committed -= region.committed_bytes();
+                return Err(err);
+            }
+        };
+        region.map(virt, &mut self.page_table);
+        self.anonymous.push((virt, region));
+        Ok(virt as *mut u8)
+    }
+
+    /// Maps a region the process needs to start at all, so it is charged even above the limit.
+    pub fn map_anonymous(&mut self, virt: usize, region: AnonymousRegion) {
+        self.memory_committed += region.committed_bytes();
+        region.map(virt, &mut self.page_table);
+        self.anonymous.push((virt, region));
+    }
+
+    /// Commits the reserved pages in the range, which must lie within a single anonymous region.
+    /// Returns whether the memory limit allowed it.
+    pub fn commit(&mut self, range: Range<usize>) -> Result<bool, ()> {
+        let index = self
+            .anonymous
+            .iter()
+            .position(|(base, region)| {
+                *base <= range.start && range.end <= *base + region.byte_size()
+            })
+            .ok_or(())?;
+        let (base, region) = &mut self.anonymous[index];
+        let pages = (range.start - *base) / PAGE_SIZE..(range.end - *base).div_ceil(PAGE_SIZE);
+        let size = region.uncommitted_bytes(pages.clone());
+        if self.charge(size).is_err() {
+            return Ok(false);
+        }
+        self.anonymous[index].1.commit(pages);
+        Ok(true)
+    }
+
+    pub fn handle_anonymous_fault(&mut self, virt: usize) -> Result<bool, AllocError> {
+        let Some((base, region)) = self
+            .anonymous
+            .iter_mut()
+            .find(|(base, region)| (*base..*base + region.byte_size()).contains(&virt))
+        else {
+            return Ok(false);
+        };
+        region.handle_fault(*base, (virt - *base) / PAGE_SIZE, &mut self.page_table)
+    }
+
+    /// Gives the pages in the range their own memory, so the kernel can access them directly.
+    pub fn fault_in(&mut self, range: Range<usize>, write: bool) -> Result<(), AllocError> {
+        let mut faulted = false;
+        for (base, region) in &mut self.anonymous {
+            let start = range.start.max(*base);
+            let end = range.end.min(*base + region.byte_size());
+            if start >= end {
+                continue;
+            }
+            for page_index in (start - *base) / PAGE_SIZE..(end - *base).div_ceil(PAGE_SIZE) {
+                faulted |= region.fault_in(*base, page_index, write, &mut self.page_table)?;
+            }
+        }
+        if faulted {
+            self.flush_tlb();
+        }
+        Ok(())
+    }
+
+    pub fn lend(&mut self, range: Range<usize>, writable: bool) -> Option<Loan> {
+        // Loans are mapped as a single allocation, so lent anonymous memory stops being lazy.
+        if let Some(index) = self.anonymous.iter().position(|(virt, region)| {
+            *virt <= range.start && range.end <= virt + region.byte_size()
+        }) {
+            if self.anonymous[index].1.committed_bytes() != self.anonymous[index].1.byte_size() {
+                return None;
+            }
+            let (virt, region) = self.anonymous.swap_remove(index);
+            region.unmap(virt, &mut self.page_table);
+            let flags = region.flags();
+            let backing = region.into_contiguous();
+            let size = backing.byte_size();
+            self.alloc_at(virt, backing, flags);
+            self.allocated.last_mut().unwrap().2 = size;
+            self.flush_tlb();
+        }
+        let (virt, backing, _) = self.allocated.iter().find(|(virt, backing, _)| {
+            *virt <= range.start && range.end <= virt + backing.byte_size()
+        })?;
+        let (_, flags) = self.page_table.translate(*virt)?;
+        if writable && !flags.is_writable() {
+            return None;
+        }
+        Some(Loan::new(
+            backing.clone(),
+            range.start - virt,
+            range.end - range.start,
+            writable,
+        ))
+    }
+
+    fn charge(&mut self, size: usize) -> Result<(), AllocError> {
+        if self.memory_committed + size > self.memory_limit {
+            return Err(AllocError);
+        }
+        self.memory_committed += size;
+        Ok(())
     }
 
     pub fn dealloc(&mut self, ptr: *mut u8) -> Result<(), ()> {
+        if let Some(index) = self
+            .anonymous
+            .iter()
+            .position(|(virt, _)| *virt == ptr as usize)
+        {
+            let (virt, region) = self.anonymous.swap_remove(index);
+            region.unmap(virt, &mut self.page_table);
+            self.heap.dealloc(virt, region.layout());
+            self.memory_committed -= region.committed_bytes();
+            return Ok(());
+        }
         let slot = self
             .allocated
             .iter()
             .enumerate()
             .find(|a| a.1.0 == ptr as usize)
             .ok_or(())?;
-        let (virt, backing) = self.allocated.swap_remove(slot.0);
+        let (virt, backing, charged) = self.allocated.swap_remove(slot.0);
+        self.memory_committed -= charged;
         self.page_table.unmap(
             virt,
             virt_to_phys(backing.as_untyped_ptr()) as usize,
@@ -145,6 +356,70 @@ impl Process {
         self.heap.dealloc(virt, backing.layout());
         Ok(())
     }
+
+    pub fn map_shared(&mut self, handler: HandlerRef, length: usize) -> Result<usize, AllocError> {
+        let size = length.next_multiple_of(PAGE_SIZE);
+        let layout = Layout::from_size_align(size, PAGE_SIZE).unwrap();
+        let virt = self.heap.alloc(layout)?;
+        handler.shared_memory_map(self.id, virt, &mut self.page_table);
+        self.shared_mappings.push(SharedMapping {
+            range: virt..virt + size,
+            handler,
+        });
+        Ok(virt)
+    }
+
+    pub fn unmap_shared(&mut self, virt: usize) -> Result<(), ()> {
+        let index = self
+            .shared_mappings
+            .iter()
+            .position(|mapping| mapping.range.start == virt)
+            .ok_or(())?;
+        let mapping = self.shared_mappings.swap_remove(index);
+        let size = mapping.range.end - mapping.range.start;
+        let layout = Layout::from_size_align(size, PAGE_SIZE).unwrap();
+        mapping
+            .handler
+            .shared_memory_unmap(self.id, virt, &mut self.page_table);
+        self.heap.dealloc(virt, layout);
+        Ok(())
+    }
+
+    pub fn map_stream(
+        &mut self,
+        handler: HandlerRef,
+        ring: &'static UntypedRingBuffer,
+    ) -> Result<usize, AllocError> {
+        let virt = self.heap.alloc(stream_layout(ring))?;
+        let phys = virt_to_phys(ring as *const _ as *const u8) as usize;
+        let flags = PageFlags::read_write().user();
+        self.page_table.map(virt, phys, PAGE_SIZE, flags);
+        self.stream_mappings.push(StreamMapping {
+            virt,
+            ring,
+            _handler: handler,
+        });
+        Ok(virt)
+    }
+
+    pub fn unmap_stream(&mut self, virt: usize) -> Result<(), ()> {
+        let index = self
+            .stream_mappings
+            .iter()
+            .position(|mapping| mapping.virt == virt)
+            .ok_or(())?;
+        let StreamMapping { ring, .. } = self.stream_mappings.swap_remove(index);
+        let phys = virt_to_phys(ring as *const _ as *const u8) as usize;
+        self.page_table.unmap(virt, phys, PAGE_SIZE);
+        self.heap.dealloc(virt, stream_layout(ring));
+        Ok(())
+    }
+
+    pub fn unmap_all_shared(&mut self) {
+        while let Some(mapping) = self.shared_mappings.last() {
+            self.unmap_shared(mapping.range.start).unwrap();
+        }
+    }
 }
 
 impl<T: ProcessTag, U: AsRef<[u8]>> ProcessReservation<T, U> {
@@ -158,9 +433,11 @@ impl<T: ProcessTag, U: AsRef<[u8]>> ProcessReservation<T, U> {
 
         create_process(
             self.elf,
+            self.handle,
             ProcessInputs {
                 id: self.id,
                 riscv_timebase_frequency: timebase_frequency().map(NonZeroUsize::get),
+                unix_time_at_zero: unix_time_at_zero(),
                 args,
             },
         )
@@ -169,9 +446,11 @@ impl<T: ProcessTag, U: AsRef<[u8]>> ProcessReservation<T, U> {
     fn spawn_with_ready_caps(self, args: T::Args) {
         create_process(
             self.elf,
+            self.handle,
             ProcessInputs {
                 id: self.id,
                 riscv_timebase_frequency: timebase_frequency().map(NonZeroUsize::get),
+                unix_time_at_zero: unix_time_at_zero(),
                 args,
             },
         )
@@ -179,28 +458,47 @@ impl<T: ProcessTag, U: AsRef<[u8]>> ProcessReservation<T, U> {
 }
 
 pub fn get_process(pid: ProcessId) -> &'static Mutex<Option<Process>> {
-    &PROCESSES[pid.as_u16() as usize]
+    PROCESSES.lock()[pid.as_u16() as usize - 1]
+}
+
+fn process_slots() -> Vec<&'static Mutex<Option<Process>>> {
+    PROCESSES.lock().clone()
 }
 
+/// Returns `None` once every process ID is taken.
 pub fn reserve_process<T: ProcessTag, U: AsRef<[u8]>>(
     elf: &'static Elf<T, U>,
-) -> ProcessReservation<T, U> {
-    let pid = ProcessId::new(PROCESSES_RESERVED.fetch_add(1, Ordering::Relaxed) + 1);
-    ProcessReservation {
+) -> Option<ProcessReservation<T, U>> {
+    let mut free_pids = FREE_PROCESS_IDS.lock();
+    let mut processes = PROCESSES.lock();
+    let pid = if free_pids.len() > PROCESS_ID_REUSE_DELAY || processes.len() == MAX_PROCESSES {
+        free_pids.pop_front()?
+    } else {
+        processes.push(Box::leak(Box::new(Mutex::new(None))));
+        ProcessId::new(processes.len() as u16)
+    };
+    drop(processes);
+    drop(free_pids);
+    allocate_capability_page(pid);
+    Some(ProcessReservation {
         id: pid,
         elf,
         export: unsafe { Capability::new(RawCapability::new(pid, 0)) },
-    }
+        handle: ProcessHandle::new(pid),
+    })
 }
 
 fn create_process<T: ProcessTag, U: AsRef<[u8]>>(
     elf: &'static Elf<T, U>,
+    handle: Arc<ProcessHandle>,
     inputs: ProcessInputs<T>,
 ) {
     let mut proc = Process {
         id: inputs.id,
         name: T::NAME,
         state: ProcessState::Runnable,
+        priority: T::PRIORITY,
+        virtual_runtime: 0,
         registers: RiscvRegisters {
             sp: USER_STACK.end,
             ..RiscvRegisters::default()
@@ -209,41 +507,50 @@ fn create_process<T: ProcessTag, U: AsRef<[u8]>>(
         page_table: Box::new(PageTable::new()),
         heap: BuddyAllocator::new(USER_HEAP),
         messages: VecDeque::new(),
-        currently_serving: None,
+        serving: Vec::new(),
+        next_reply_token: 0,
+        oversized_reply: None,
+        loans: Vec::new(),
+        call_deadline: u64::MAX,
+        pending_calls: Vec::new(),
+        next_call_id: 0,
+        transfers: Vec::new(),
+        handle,
         allocated: Vec::new(),
-        virtual_memory_mappings: Vec::new(),
+        anonymous: Vec::new(),
+        memory_limit: T::MEMORY_LIMIT,
+        memory_committed: 0,
+        shared_mappings: Vec::new(),
+        stream_mappings: Vec::new(),
+        running_on: None,
     };
     map_hh_direct_mapping(&mut proc.page_table);
     load_elf(elf, &mut proc);
-    map_capability_memory(&mut proc.page_table, proc.id);
+    map_capability_page(&mut proc.page_table, proc.id);
     map_inputs_memory(&mut proc, inputs);
     map_user_stack(&mut proc);
+    scheduler().admit(&mut proc);
 
     let pid = proc.id;
     *get_process(pid).lock() = Some(proc);
 }
 
-fn map_capability_memory(table: &mut PageTable, pid: ProcessId) {
-    let pre_v = USER_CAPABILITIES.start;
-    let pre_p = capability_pages_physical_address();
-    let own_v = pre_v + pid.as_u16() as usize * PAGE_SIZE;
-    let own_p = pre_p + pid.as_u16() as usize * PAGE_SIZE;
-    let suf_v = own_v + PAGE_SIZE;
-    let suf_p = own_p + PAGE_SIZE;
-    let suf_l = PROCESS_COUNT - pid.as_u16() as usize - 1;
-    table.map(
-        pre_v,
-        pre_p,
-        (pid.as_u16() as usize) * PAGE_SIZE,
-        PageFlags::readonly().user(),
-    );
-    table.map(own_v, own_p, PAGE_SIZE, PageFlags::read_write().user());
-    table.map(
-        suf_v,
-        suf_p,
-        suf_l * PAGE_SIZE,
-        PageFlags::readonly().user(),
-    );
+fn map_capability_page(table: &mut PageTable, pid: ProcessId) {
+    let virt = USER_CAPABILITIES.start + pid.as_u16() as usize * PAGE_SIZE;
+    let phys = capability_page_physical_address(pid.into()).unwrap();
+    table.map(virt, phys, PAGE_SIZE, PageFlags::read_write().user());
+}
+
+pub fn map_foreign_capability_page(table: &mut PageTable, virt: usize) -> Result<(), ()> {
+    let page_index = (virt - USER_CAPABILITIES.start) / PAGE_SIZE;
+    let certifier = match page_index {
+        0 => Actor::Kernel,
+        _ => Actor::Userspace(ProcessId::new(page_index as u16)),
+    };
+    let phys = capability_page_physical_address(certifier).ok_or(())?;
+    let virt = USER_CAPABILITIES.start + page_index * PAGE_SIZE;
+    table.map(virt, phys, PAGE_SIZE, PageFlags::readonly().user());
+    Ok(())
 }
 
 fn map_inputs_memory<T: ProcessTag>(proc: &mut Process, inputs: ProcessInputs<T>) {
@@ -255,17 +562,27 @@ fn map_inputs_memory<T: ProcessTag>(proc: &mut Process, inputs: ProcessInputs<T>
 
 fn map_user_stack(proc: &mut Process) {
     let stack_size = USER_STACK.end - USER_STACK.start;
-    let pages = Arc::new(UntypedBox::new(
-        page_granular_vec![0u8; stack_size].into_boxed_slice(),
-    ));
-    proc.alloc_at(USER_STACK.start, pages, PageFlags::read_write().user());
+    let region = AnonymousRegion::zeroed(stack_size, PageFlags::read_write().user());
+    proc.map_anonymous(USER_STACK.start, region);
 }
 
 pub fn schedule_userspace(user: &mut UserStoredCtx) -> ! {
+    if let Some(pid) = user.try_pid()
+        && let Some(mut proc) = get_process(pid).lock_if_some()
+    {
+        scheduler().charge(&mut proc, now() - user.scheduled_at);
+        proc.running_on = None;
+    }
     loop {
         let Some(next) = find_runnable_process(user) else {
-            shutdown()
+            let Some(deadline) = next_wakeup() else {
+                shutdown()
+            };
+            wait_for_interrupt(deadline);
+            continue;
         };
+        arm_time_slice();
+        user.scheduled_at = now();
         let Err(err) = resume_process(next, user);
         kill_manual!(user, "{err}");
         schedule_userspace(user)
@@ -274,13 +591,17 @@ pub fn schedule_userspace(user: &mut UserStoredCtx) -> ! {
 
 fn find_runnable_process(user: &UserStoredCtx) -> Option<MutexGuard<'static, Process>> {
     let scan_start = match user.try_pid() {
-        Some(pid) => pid.as_u16() + 1,
+        Some(pid) => pid.as_u16() as usize,
         None => 0,
     };
 
-    for scan_offset in 0..PROCESS_COUNT as u16 {
-        let scan_index = (scan_start + scan_offset) % PROCESS_COUNT as u16;
-        if let Some(mut proc) = PROCESSES[scan_index as usize].lock_if_some() {
+    let processes = process_slots();
+    let mut candidates = Vec::new();
+    for scan_offset in 0..processes.len() {
+        let slot = processes[(scan_start + scan_offset) % processes.len()];
+        if let Some(mut proc) = slot.lock_if_some()
+            && proc.running_on.is_none()
+        {
             inspect_can_progress(&mut proc);
             if matches!(
                 proc.state,
@@ -288,37 +609,217 @@ fn find_runnable_process(user: &UserStoredCtx) -> Option<MutexGuard<'static, Pro
                     | ProcessState::ReadyReply { .. }
                     | ProcessState::ReadyStreamMap { .. }
             ) {
-                return Some(proc);
-            }
-            if proc.state == ProcessState::Finished {
-                drop(proc);
-                // TODO: Race condition here.
-                *PROCESSES[scan_index as usize].lock() = None;
+                candidates.push(Candidate {
+                    id: proc.id,
+                    priority: proc.priority,
+                    virtual_runtime: proc.virtual_runtime,
+                });
             }
         }
+        // Taking the process out under the same lock that checks its state makes sure only one
+        // caller tears it down. A process killed while running elsewhere waits for that hart to
+        // leave its page table.
+        let finished = slot
+            .lock()
+            .take_if(|proc| proc.state == ProcessState::Finished && proc.running_on.is_none());
+        if let Some(proc) = finished {
+            tear_down(proc);
+        }
     }
 
-    None
+    if candidates.is_empty() {
+        return None;
+    }
+    if candidates.len() > 1 {
+        wake_idle_harts();
+    }
+    let chosen = &candidates[scheduler().choose(&candidates)];
+    get_process(chosen.id).lock_if_some()
 }
 
-fn inspect_can_progress(proc: &mut Process) {
-    if let ProcessState::WaitingForReply { from, .. } | ProcessState::WaitingForStreamMap { from } =
-        &proc.state
-        && let Actor::Userspace(from) = *from
-    {
-        let from_slot = get_process(from).lock_if_some();
-        if matches!(
-            from_slot.as_deref(),
-            None | Some(Process {
-                state: ProcessState::Finished,
-                ..
-            })
-        ) {
-            proc.state = ProcessState::Finished;
+fn stream_layout(ring: &UntypedRingBuffer) -> Layout {
+    Layout::from_size_align(size_of_val(ring), PAGE_SIZE).unwrap()
+}
+
+/// Frees everything the finished process owned and fails everyone waiting on it. Its slot is already
+/// empty, so it can't be looked up, and its ID is only reused once nothing refers to it anymore.
+fn tear_down(mut proc: Process) {
+    let pid = proc.id;
+    proc.unmap_all_shared();
+    for slot in process_slots() {
+        if let Some(mut other) = slot.lock_if_some() {
+            disconnect(&mut other, pid);
+        }
+    }
+    release_capability_page(pid);
+    drop(proc);
+    FREE_PROCESS_IDS.lock().push_back(pid);
+}
+
+fn disconnect(proc: &mut Process, finished: ProcessId) {
+    proc.messages.retain(|message| message.sender != finished);
+    let mut loans = Vec::new();
+    for served in &mut proc.serving {
+        if served.caller == finished {
+            served.cancelled = true;
+            loans.append(&mut served.loans);
+        }
+    }
+    if !loans.is_empty() {
+        for loan in loans {
+            loan.unmap(proc);
+        }
+        proc.flush_tlb();
+    }
+    for call in &mut proc.pending_calls {
+        if call.from == finished.into() && call.reply.is_none() {
+            call.disconnected = true;
+        }
+    }
+    match proc.state {
+        ProcessState::WaitingForReply { from, .. } if from == finished.into() => {
+            proc.registers.a0 = IPC_DISCONNECTED;
+            proc.state = ProcessState::Runnable;
+        }
+        ProcessState::WaitingForStreamMap { from } if from == finished.into() => {
             warn!(
                 "stopping {}{:?} waiting on finished {:?}",
                 proc.name, proc.id, from
             );
+            proc.finish(ExitReason::Killed(format!("waiting on finished {from:?}")));
+        }
+        _ => {}
+    }
+}
+
+fn next_wakeup() -> Option<u64> {
+    let mut alive = false;
+    let mut wakeup = u64::MAX;
+    for slot in process_slots() {
+        if let Some(proc) = slot.lock_if_some() {
+            alive = true;
+            if let ProcessState::Waiting { deadline, .. }
+            | ProcessState::WaitingForReply { deadline, .. } = proc.state
+            {
+                wakeup = wakeup.min(deadline);
+            }
+        }
+    }
+    alive.then_some(wakeup)
+}
+
+fn wait_for_interrupt(deadline: u64) {
+    // Interrupts are disabled in supervisor mode, but wfi still wakes up on enabled pending ones, so
+    // they have to be handled manually here. The kernel lock is released meanwhile, and other harts
+    // send an IPI if they find more to run than they can.
+    sbi::set_timer(deadline);
+    set_idle(true);
+    unlock_kernel();
+    riscv::asm::wfi();
+    lock_kernel();
+    set_idle(false);
+    unsafe { riscv::register::sip::clear_ssoft() };
+    if riscv::register::sip::read().sext() {
+        handle_external_interrupt();
+    }
+}
+
+pub fn is_wait_over(proc: &Process) -> bool {
+    let ProcessState::Waiting { rings, deadline } = &proc.state else {
+        return true;
+    };
+    !proc.messages.is_empty()
+        || proc
+            .pending_calls
+            .iter()
+            .any(|call| call.reply.is_some() || call.disconnected)
+        || now() >= *deadline
+        || rings.iter().any(|ring| ring_has_data(*ring))
+}
+
+pub fn has_finished(actor: Actor) -> bool {
+    let Actor::Userspace(pid) = actor else {
+        return false;
+    };
+    matches!(
+        get_process(pid).lock_if_some().as_deref(),
+        None | Some(Process {
+            state: ProcessState::Finished,
+            ..
+        })
+    )
+}
+
+fn ring_has_data(ring: usize) -> bool {
+    let ring = unsafe { &*core::ptr::from_raw_parts::<UntypedRingBuffer>(ring as *const (), 1) };
+    ring.0.written.0.load(Ordering::Acquire) > ring.0.read.0.load(Ordering::Relaxed)
+}
+
+fn inspect_can_progress(proc: &mut Process) {
+    if is_wait_over(proc) && matches!(proc.state, ProcessState::Waiting { .. }) {
+        proc.state = ProcessState::Runnable;
+    }
+    if matches!(proc.state, ProcessState::Waiting { .. })
+        && proc.pending_calls.iter().any(|call| {
+            call.reply.is_none() && call.from != proc.id.into() && has_finished(call.from)
+        })
+    {
+        proc.state = ProcessState::Runnable;
+    }
+    if let ProcessState::WaitingForReply { from, .. } | ProcessState::WaitingForStreamMap { from } =
+        proc.state
+        && has_finished(from)
+    {
+        if let ProcessState::WaitingForReply { .. } = proc.state {
+            proc.registers.a0 = IPC_DISCONNECTED;
+            proc.state = ProcessState::Runnable;
+            return;
+        }
+        warn!(
+            "stopping {}{:?} waiting on finished {:?}",
+            proc.name, proc.id, from
+        );
+        proc.finish(ExitReason::Killed(format!("waiting on finished {from:?}")));
+        return;
+    }
+    if let ProcessState::WaitingForReply {
+        from: Actor::Userspace(server),
+        deadline,
+        ..
+    } = proc.state
+        && now() >= deadline
+    {
+        if let Some(mut server) = get_process(server).lock_if_some() {
+            cancel_call(&mut server, proc.id, None);
+        }
+        proc.registers.a0 = IPC_TIMED_OUT;
+        proc.state = ProcessState::Runnable;
+    }
+}
+
+/// Withdraws the call from the caller, so the server either never sees it or gets woken up to notice
+/// the cancellation, and its eventual reply is dropped. Loans end with the call, so the server loses
+/// access to them right away. Blocking calls have no ID.
+pub fn cancel_call(server: &mut Process, caller: ProcessId, call: Option<usize>) {
+    if let Some(index) = server
+        .messages
+        .iter()
+        .position(|message| message.sender == caller && message.call == call)
+    {
+        server.messages.remove(index);
+    } else if let Some(served) = server
+        .serving
+        .iter_mut()
+        .find(|served| served.caller == caller && served.call == call && !served.cancelled)
+    {
+        served.cancelled = true;
+        let loans = core::mem::take(&mut served.loans);
+        for loan in loans {
+            loan.unmap(server);
+        }
+        server.flush_tlb();
+        if matches!(server.state, ProcessState::Waiting { .. }) {
+            server.state = ProcessState::Runnable;
         }
     }
 }
@@ -335,27 +836,19 @@ fn resume_process(
             reply,
             result_buffer,
         } => {
-            result_buffer.write_to_user(reply)?;
+            let reply = core::mem::take(reply);
+            let fits = result_buffer.write_to_user(&reply).is_ok();
             next.registers.a0 = reply.len();
+            if !fits {
+                next.oversized_reply = Some(reply);
+            }
             next.state = ProcessState::Runnable;
         }
         ProcessState::ReadyStreamMap {
-            ring,
+            virt,
             declared_size,
         } => {
-            let ring = *ring;
-            let declared_size = *declared_size;
-            let handler = capability::get_handler(ring.local_index());
-            let length = handler.shared_memory_size();
-            let layout = Layout::from_size_align(length, PAGE_SIZE).unwrap();
-            let virt = next.heap.alloc(layout).unwrap();
-            let next = next.deref_mut();
-            handler.shared_memory_map(
-                virt,
-                &mut next.page_table,
-                &mut next.virtual_memory_mappings,
-            );
-            riscv::asm::sfence_vma_all();
+            let (virt, declared_size) = (*virt, *declared_size);
             next.registers.a0 = virt;
             next.registers.a1 = declared_size;
             next.state = ProcessState::Runnable;
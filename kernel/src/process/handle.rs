@@ -0,0 +1,67 @@
+use crate::drvli::ProcessHandleServer;
+use crate::process::get_process;
+use crate::sync::Mutex;
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::sync::Arc;
+use deravel_types::{ExitReason, ProcessId, RingBuffer};
+use log::*;
+
+pub struct ProcessHandle {
+    pid: ProcessId,
+    exit_reason: Mutex<Option<ExitReason>>,
+    exited: Box<RingBuffer<u8>>,
+}
+
+impl ProcessHandle {
+    pub fn new(pid: ProcessId) -> Arc<ProcessHandle> {
+        Arc::new(ProcessHandle {
+            pid,
+            exit_reason: Mutex::new(None),
+            exited: RingBuffer::new_single_page(),
+        })
+    }
+
+    pub fn notify_exit(&self, reason: ExitReason) {
+        let mut exit_reason = self.exit_reason.lock();
+        if exit_reason.is_none() {
+            *exit_reason = Some(reason);
+            self.exited.push(0);
+        }
+    }
+}
+
+impl ProcessHandleServer for ProcessHandle {
+    fn pid(&self, _: ProcessId) -> ProcessId {
+        self.pid
+    }
+
+    fn exit_reason(&self, _: ProcessId) -> Option<ExitReason> {
+        self.exit_reason.lock().clone()
+    }
+
+    fn kill(&self, sender: ProcessId) {
+        // The process id could have been reused after the process finished, so check that the slot
+        // still belongs to this handle. A process killing itself would keep running until the next
+        // trap, so that is not supported.
+        if sender == self.pid {
+            return;
+        }
+        let Some(mut proc) = get_process(self.pid).lock_if_some() else {
+            return;
+        };
+        if !core::ptr::eq(&*proc.handle, self) {
+            return;
+        }
+        info!(
+            "killing {}{:?} on request of {sender:?}",
+            proc.name, proc.id
+        );
+        proc.finish(ExitReason::Killed(format!("killed by {sender:?}")));
+    }
+
+    fn exited(&self) -> &'static RingBuffer<u8> {
+        // Processes mapping the stream keep the handle alive.
+        unsafe { &*(&*self.exited as *const _) }
+    }
+}
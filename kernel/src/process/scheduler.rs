@@ -0,0 +1,109 @@
+use crate::device_tree::boot_argument;
+use crate::process::Process;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use deravel_types::{Priority, ProcessId};
+use fdt::Fdt;
+use log::{info, warn};
+
+pub trait Scheduler: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Picks one of the runnable candidates, which are ordered starting from the process after the
+    /// previously scheduled one.
+    fn choose(&self, candidates: &[Candidate]) -> usize;
+
+    fn admit(&self, _proc: &mut Process) {}
+
+    fn charge(&self, _proc: &mut Process, _elapsed: u64) {}
+}
+
+pub struct Candidate {
+    pub id: ProcessId,
+    pub priority: Priority,
+    pub virtual_runtime: u64,
+}
+
+struct RoundRobin;
+
+struct StrictPriority;
+
+struct WeightedFair {
+    floor: AtomicU64,
+}
+
+static WEIGHTED_FAIR: WeightedFair = WeightedFair {
+    floor: AtomicU64::new(0),
+};
+static SCHEDULERS: [&dyn Scheduler; 3] = [&RoundRobin, &StrictPriority, &WEIGHTED_FAIR];
+static SCHEDULER: AtomicUsize = AtomicUsize::new(0);
+
+impl Scheduler for RoundRobin {
+    fn name(&self) -> &'static str {
+        "round_robin"
+    }
+
+    fn choose(&self, _: &[Candidate]) -> usize {
+        0
+    }
+}
+
+impl Scheduler for StrictPriority {
+    fn name(&self) -> &'static str {
+        "strict_priority"
+    }
+
+    fn choose(&self, candidates: &[Candidate]) -> usize {
+        let highest = candidates.iter().map(|c| c.priority).max().unwrap();
+        candidates
+            .iter()
+            .position(|c| c.priority == highest)
+            .unwrap()
+    }
+}
+
+impl Scheduler for WeightedFair {
+    fn name(&self) -> &'static str {
+        "weighted_fair"
+    }
+
+    fn choose(&self, candidates: &[Candidate]) -> usize {
+        let (index, chosen) = candidates
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, c)| c.virtual_runtime)
+            .unwrap();
+        self.floor
+            .fetch_max(chosen.virtual_runtime, Ordering::Relaxed);
+        index
+    }
+
+    fn admit(&self, proc: &mut Process) {
+        proc.virtual_runtime = self.floor.load(Ordering::Relaxed);
+    }
+
+    fn charge(&self, proc: &mut Process, elapsed: u64) {
+        proc.virtual_runtime += elapsed * weight(Priority::Interactive) / weight(proc.priority);
+    }
+}
+
+pub fn initialize_scheduler(dt: &Fdt) {
+    if let Some(name) = boot_argument(dt, "scheduler") {
+        match SCHEDULERS.iter().position(|s| s.name() == name) {
+            Some(index) => SCHEDULER.store(index, Ordering::Relaxed),
+            None => warn!("unknown scheduler {name:?}, using default"),
+        }
+    }
+    info!("using {} scheduler", scheduler().name());
+}
+
+pub fn scheduler() -> &'static dyn Scheduler {
+    SCHEDULERS[SCHEDULER.load(Ordering::Relaxed)]
+}
+
+fn weight(priority: Priority) -> u64 {
+    match priority {
+        Priority::Batch => 1,
+        Priority::Normal => 2,
+        Priority::Interactive => 4,
+    }
+}
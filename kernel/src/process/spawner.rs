@@ -1,53 +1,68 @@
-use crate::capability::{Handler, capability_certificate};
+use crate::capability::{Handler, capability_certificate, grant_shared_kernel_capability};
 use crate::elf::Elf;
+use crate::loan::Loan;
 use crate::page::PageTable;
 use crate::process::reserve_process;
-use crate::virtual_memory::VirtualMemoryRawMapping;
-use alloc::vec;
 use alloc::vec::Vec;
-use core::ops::Range;
 use core::sync::atomic::Ordering;
 use deravel_types::{
-    Actor, CapabilityCertificateValue, ProcessArgs, ProcessId, ProcessTag, UntypedRingBuffer,
+    Actor, Capability, CapabilityCertificateValue, ProcessArgs, ProcessHandle, ProcessId,
+    ProcessTag, UntypedRingBuffer,
 };
 
 impl<T: ProcessTag, U: AsRef<[u8]>> Handler<T::Spawner> for Elf<T, U> {
-    fn call_method(&'static self, _: usize, args: &[u8], sender: ProcessId) -> Vec<u8> {
-        let reserve = reserve_process(self);
+    fn call_method(
+        &'static self,
+        _: usize,
+        args: &[u8],
+        sender: ProcessId,
+        _: &mut [Loan],
+    ) -> Option<Vec<u8>> {
+        let args: <T as ProcessTag>::Args = postcard::from_bytes(args).ok()?;
+        let mut own_caps = true;
+        args.for_all(|cap| own_caps &= cap.certifier() == Actor::Userspace(sender));
+        if !own_caps {
+            return None;
+        }
+        let Some(reserve) = reserve_process(self) else {
+            // Running out of process IDs is the system's fault, so the caller is told instead of
+            // killed.
+            return Some(postcard::to_allocvec(&None::<()>).unwrap());
+        };
         let export = reserve.export;
+        let handle: Capability<ProcessHandle> =
+            grant_shared_kernel_capability(sender, reserve.handle.clone());
         capability_certificate(*export).store(
             CapabilityCertificateValue::granted(sender),
             Ordering::Relaxed,
         );
-        let args: <T as ProcessTag>::Args = postcard::from_bytes(args).unwrap();
         args.for_all(|cap| {
-            assert_eq!(cap.certifier(), Actor::Userspace(sender));
             let slot = capability_certificate(cap);
             let before = slot.load(Ordering::Relaxed);
             let after = before.replace_recipient(reserve.id);
             slot.store(after, Ordering::Relaxed);
         });
         reserve.spawn_with_ready_caps(args);
-        let mut buf = vec![0; 4096];
-        let buf_len = postcard::to_slice(&export, &mut buf).unwrap().len();
-        buf.resize(buf_len, 0);
-        buf
+        Some(postcard::to_allocvec(&Some((export, handle))).unwrap())
+    }
+
+    fn map_stream(&self, _: usize) -> Option<&'static UntypedRingBuffer> {
+        None
     }
 
-    fn map_stream(&self, _: usize) -> &'static UntypedRingBuffer {
+    fn shared_memory_map(&self, _: ProcessId, _: usize, _: &mut PageTable) {
         unreachable!()
     }
 
-    fn shared_memory_map(
-        &self,
-        _: usize,
-        _: &mut PageTable,
-        _: &mut Vec<(Range<usize>, &'static (dyn VirtualMemoryRawMapping + Sync))>,
-    ) {
+    fn shared_memory_unmap(&self, _: ProcessId, _: usize, _: &mut PageTable) {
         unreachable!()
     }
 
-    fn shared_memory_size(&self) -> usize {
+    fn shared_memory_fault(&self, _: usize, _: usize, _: &mut PageTable) -> bool {
         unreachable!()
     }
+
+    fn shared_memory_size(&self) -> Option<usize> {
+        None
+    }
 }
@@ -0,0 +1,48 @@
+use crate::device_tree::timebase_frequency;
+use crate::page::phys_to_virt;
+use crate::timer::now;
+use crate::util::volatile::{Volatile, volatile_struct};
+use core::sync::atomic::{AtomicU64, Ordering};
+use fdt::Fdt;
+use log::{info, warn};
+
+volatile_struct! { GoldfishRtc
+    // Reading the low half latches the high half.
+    time_low: Readonly u32,
+    time_high: Readonly u32,
+}
+
+// Zero means the wall clock time is unknown.
+static UNIX_TIME_AT_ZERO: AtomicU64 = AtomicU64::new(0);
+
+/// Reads the RTC once, as the timebase is enough to keep track of time afterwards.
+pub fn initialize_rtc(dt: &Fdt) {
+    let Some(mut rtc) = find_rtc(dt) else {
+        return warn!("wall clock time unknown as RTC is missing");
+    };
+    let Some(frequency) = timebase_frequency() else {
+        return warn!("wall clock time unknown as timebase frequency is unknown");
+    };
+    let low = rtc.time_low().read();
+    let high = rtc.time_high().read();
+    let unix_time = (((high as u64) << 32) | low as u64) / 1_000_000_000;
+    info!("wall clock time is {unix_time}s since the Unix epoch");
+    let uptime = now() / frequency.get() as u64;
+    UNIX_TIME_AT_ZERO.store(unix_time - uptime, Ordering::Relaxed);
+}
+
+/// Returns the Unix time in seconds at which the `time` CSR was zero.
+pub fn unix_time_at_zero() -> Option<u64> {
+    let unix_time = UNIX_TIME_AT_ZERO.load(Ordering::Relaxed);
+    (unix_time != 0).then_some(unix_time)
+}
+
+fn find_rtc(dt: &Fdt) -> Option<Volatile<'static, GoldfishRtc>> {
+    let address = dt
+        .find_compatible(&["google,goldfish-rtc"])?
+        .reg()?
+        .next()?
+        .starting_address;
+    let address = phys_to_virt(address as *mut GoldfishRtc);
+    Some(unsafe { Volatile::new(address) })
+}
@@ -163,7 +163,6 @@ pub fn get_impl_version() -> usize {
 }
 
 /// Returns if the given SBI extension ID (EID) is available.
-#[allow(dead_code)]
 pub fn probe_extension(extension_id: usize) -> bool {
     ffi::sbi_probe_extension(extension_id) != 0
 }
@@ -246,6 +245,52 @@ pub fn system_reset(type_: ResetType, reason: ResetReason) -> Result<!, Error> {
     ffi::sbi_system_reset(type_ as u32, reason as u32)
 }
 
+/// Send an inter-processor interrupt to all the harts defined in `hart_mask`.
+///
+/// Inter-processor interrupts manifest at the receiving harts as Supervisor Software Interrupts.
+///
+/// | Error code | Description |
+/// | ---------- | ----------- |
+/// | [`InvalidParam`] | At least one hartid constructed from `hart_mask_base` and `hart_mask` is not valid. |
+/// | [`Failed`] | The request failed for unspecified or unknown other reasons. |
+pub fn send_ipi(hart_mask: usize, hart_mask_base: usize) -> Result<(), Error> {
+    ffi::sbi_send_ipi(hart_mask, hart_mask_base)
+}
+
+/// Instructs the remote harts to execute one or more `SFENCE.VMA` instructions, covering the range of
+/// virtual addresses between `start_addr` and `start_addr + size`.
+///
+/// If `start_addr` and `size` are both 0, the whole address space is flushed.
+///
+/// | Error code | Description |
+/// | ---------- | ----------- |
+/// | [`InvalidAddress`] | `start_addr` or `size` is not valid. |
+/// | [`InvalidParam`] | At least one hartid constructed from `hart_mask_base` and `hart_mask` is not valid. |
+/// | [`Failed`] | The request failed for unspecified or unknown other reasons. |
+pub fn remote_sfence_vma(
+    hart_mask: usize,
+    hart_mask_base: usize,
+    start_addr: usize,
+    size: usize,
+) -> Result<(), Error> {
+    ffi::sbi_remote_sfence_vma(hart_mask, hart_mask_base, start_addr, size)
+}
+
+/// Request the SBI implementation to start executing the target hart in supervisor-mode, at the
+/// physical address specified by `start_addr`.
+///
+/// The hart starts with the MMU disabled, `a0` holding its hartid and `a1` holding `opaque`.
+///
+/// | Error code | Description |
+/// | ---------- | ----------- |
+/// | [`InvalidAddress`] | `start_addr` is not valid, possibly due to it not being a physical address or being prohibited. |
+/// | [`InvalidParam`] | `hartid` is not a valid hartid as the corresponding hart cannot be started in supervisor mode. |
+/// | [`AlreadyAvailable`] | The given hartid is already started. |
+/// | [`Failed`] | The start request failed for unspecified or unknown other reasons. |
+pub fn hart_start(hartid: usize, start_addr: usize, opaque: usize) -> Result<(), Error> {
+    ffi::sbi_hart_start(hartid, start_addr, opaque)
+}
+
 pub fn log_sbi_metadata() {
     let spec_version = get_spec_version();
     let impl_id = get_impl_id();
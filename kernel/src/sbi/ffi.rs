@@ -2,8 +2,8 @@ use crate::sbi::Error;
 use core::arch::asm;
 use core::mem::transmute;
 
-macro functions($(#[eid = $eid:expr, fid = $fid:expr] pub fn $name:ident ($($a0name:ident: $a0type:ty $(, $a1name:ident: $a1type:ty $(, $a2name:ident: $a2type:ty)?)?)?) -> $ret:ty;)*) {
-    $(pub fn $name($($a0name: $a0type,)? $($($a1name: $a1type,)?)? $($($($a2name: $a2type,)?)?)?) -> $ret {
+macro functions($(#[eid = $eid:expr, fid = $fid:expr] pub fn $name:ident ($($a0name:ident: $a0type:ty $(, $a1name:ident: $a1type:ty $(, $a2name:ident: $a2type:ty $(, $a3name:ident: $a3type:ty)?)?)?)?) -> $ret:ty;)*) {
+    $(pub fn $name($($a0name: $a0type,)? $($($a1name: $a1type,)?)? $($($($a2name: $a2type,)?)?)? $($($($($a3name: $a3type,)?)?)?)?) -> $ret {
         let error: isize;
         let value: usize;
         unsafe {
@@ -12,7 +12,8 @@ macro functions($(#[eid = $eid:expr, fid = $fid:expr] pub fn $name:ident ($($a0n
                 $(in("a0") $a0name,
                 $(in("a1") $a1name,
                 $(in("a2") $a2name,
-                )?)?)?
+                $(in("a3") $a3name,
+                )?)?)?)?
                 in("a6") $fid,
                 in("a7") $eid,
                 lateout("a0") error,
@@ -86,4 +87,13 @@ functions! {
 
     #[eid = 0x53525354, fid = 0]
     pub fn sbi_system_reset(reset_type: u32, reset_reason: u32) -> Result<!, Error>;
+
+    #[eid = 0x735049, fid = 0]
+    pub fn sbi_send_ipi(hart_mask: usize, hart_mask_base: usize) -> Result<(), Error>;
+
+    #[eid = 0x52464E43, fid = 1]
+    pub fn sbi_remote_sfence_vma(hart_mask: usize, hart_mask_base: usize, start_addr: usize, size: usize) -> Result<(), Error>;
+
+    #[eid = 0x48534D, fid = 0]
+    pub fn sbi_hart_start(hartid: usize, start_addr: usize, opaque: usize) -> Result<(), Error>;
 }
@@ -1,11 +1,11 @@
 use crate::capability::Handler;
 use crate::heap::granularity::PageGranular;
+use crate::loan::Loan;
 use crate::page::{PageFlags, PageTable, virt_to_phys};
 use crate::util::untyped_box::UntypedBox;
-use crate::virtual_memory::VirtualMemoryRawMapping;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
-use core::ops::{Deref, Range};
+use core::ops::Deref;
 use deravel_types::{ProcessId, UntypedRingBuffer};
 
 #[derive(Clone)]
@@ -14,30 +14,38 @@ pub struct SharedMemory {
 }
 
 impl Handler<deravel_types::SharedMemory> for SharedMemory {
-    fn call_method(&self, _: usize, _: &[u8], _: ProcessId) -> Vec<u8> {
-        unreachable!()
+    fn call_method(&self, _: usize, _: &[u8], _: ProcessId, _: &mut [Loan]) -> Option<Vec<u8>> {
+        None
     }
 
-    fn map_stream(&self, _: usize) -> &'static UntypedRingBuffer {
-        unreachable!()
+    fn map_stream(&self, _: usize) -> Option<&'static UntypedRingBuffer> {
+        None
     }
 
-    fn shared_memory_map(
-        &self,
-        virt: usize,
-        page_table: &mut PageTable,
-        _: &mut Vec<(Range<usize>, &'static (dyn VirtualMemoryRawMapping + Sync))>,
-    ) {
-        let phys = virt_to_phys(Arc::deref(&self.backing).as_untyped_ptr().addr());
+    fn shared_memory_map(&self, _: ProcessId, virt: usize, page_table: &mut PageTable) {
         page_table.map(
             virt,
-            phys,
+            self.phys(),
             self.backing.byte_size(),
             PageFlags::read_write().user(),
         );
     }
 
-    fn shared_memory_size(&self) -> usize {
-        self.backing.byte_size()
+    fn shared_memory_unmap(&self, _: ProcessId, virt: usize, page_table: &mut PageTable) {
+        page_table.unmap(virt, self.phys(), self.backing.byte_size());
+    }
+
+    fn shared_memory_fault(&self, _: usize, _: usize, _: &mut PageTable) -> bool {
+        false
+    }
+
+    fn shared_memory_size(&self) -> Option<usize> {
+        Some(self.backing.byte_size())
+    }
+}
+
+impl SharedMemory {
+    fn phys(&self) -> usize {
+        virt_to_phys(Arc::deref(&self.backing).as_untyped_ptr().addr())
     }
 }
@@ -18,7 +18,10 @@ pub struct UserCtx {
 
 #[repr(C, align(16))]
 pub struct UserStoredCtx {
+    // Must stay the first field, as trap entry loads it into tp.
+    pub hart_id: usize,
     pid: Option<ProcessId>,
+    pub scheduled_at: u64,
 }
 
 const _: () = assert!(size_of::<KernelStack>() == STACK_SIZE);
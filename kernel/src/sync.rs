@@ -1,6 +1,8 @@
 use core::cell::UnsafeCell;
+use core::hint::spin_loop;
 use core::ops::{Deref, DerefMut};
 use core::sync::atomic::{AtomicBool, Ordering};
+use riscv::register::sstatus;
 
 pub struct Mutex<T> {
     locked: AtomicBool,
@@ -9,9 +11,14 @@ pub struct Mutex<T> {
 
 pub struct MutexGuard<'a, T> {
     locked: &'a AtomicBool,
+    interrupts: bool,
     value: &'a mut T,
 }
 
+// Kernel code was written for a single hart, so only one hart runs it at a time, while userspace
+// runs on all of them in parallel.
+static KERNEL_LOCK: AtomicBool = AtomicBool::new(false);
+
 impl<T> Mutex<T> {
     pub const fn new(value: T) -> Mutex<T> {
         Mutex {
@@ -21,17 +28,19 @@ impl<T> Mutex<T> {
     }
 
     pub fn lock(&self) -> MutexGuard<'_, T> {
-        lock(&self.locked);
+        let interrupts = lock(&self.locked);
         MutexGuard {
             locked: &self.locked,
+            interrupts,
             value: unsafe { &mut *self.value.get() },
         }
     }
 
     pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
-        try_lock(&self.locked)?;
+        let interrupts = try_lock(&self.locked)?;
         Some(MutexGuard {
             locked: &self.locked,
+            interrupts,
             value: unsafe { &mut *self.value.get() },
         })
     }
@@ -39,15 +48,16 @@ impl<T> Mutex<T> {
 
 impl<T> Mutex<Option<T>> {
     pub fn lock_if_some(&self) -> Option<MutexGuard<'_, T>> {
-        lock(&self.locked);
+        let interrupts = lock(&self.locked);
         let value = unsafe { &mut *self.value.get() };
         if let Some(value) = value {
             Some(MutexGuard {
                 locked: &self.locked,
+                interrupts,
                 value,
             })
         } else {
-            unlock(&self.locked);
+            unlock(&self.locked, interrupts);
             None
         }
     }
@@ -55,7 +65,7 @@ impl<T> Mutex<Option<T>> {
 
 impl<T> Drop for MutexGuard<'_, T> {
     fn drop(&mut self) {
-        unlock(self.locked);
+        unlock(self.locked, self.interrupts);
     }
 }
 
@@ -75,20 +85,60 @@ impl<T> DerefMut for MutexGuard<'_, T> {
 
 unsafe impl<T: Send> Sync for Mutex<T> {}
 
-fn lock(locked: &AtomicBool) {
-    #[allow(clippy::never_loop)]
-    while locked.swap(true, Ordering::Acquire) {
-        panic!("deadlock detected as SMP not implemented yet");
+/// Enters the kernel, waiting for other harts to leave it. Interrupts are already disabled in
+/// supervisor mode, so they don't need masking.
+pub fn lock_kernel() {
+    while KERNEL_LOCK
+        .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        while KERNEL_LOCK.load(Ordering::Relaxed) {
+            spin_loop();
+        }
     }
 }
 
-fn try_lock(locked: &AtomicBool) -> Option<()> {
+pub fn unlock_kernel() {
+    KERNEL_LOCK.store(false, Ordering::Release);
+}
+
+// Interrupts are masked while the lock is held, so a handler can't spin on a lock its own hart
+// holds. Returns whether they were enabled before.
+fn lock(locked: &AtomicBool) -> bool {
+    let interrupts = disable_interrupts();
+    while locked
+        .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        while locked.load(Ordering::Relaxed) {
+            spin_loop();
+        }
+    }
+    interrupts
+}
+
+fn try_lock(locked: &AtomicBool) -> Option<bool> {
+    let interrupts = disable_interrupts();
     if locked.swap(true, Ordering::Acquire) {
+        restore_interrupts(interrupts);
         return None;
     }
-    Some(())
+    Some(interrupts)
 }
 
-fn unlock(locked: &AtomicBool) {
+fn unlock(locked: &AtomicBool, interrupts: bool) {
     locked.store(false, Ordering::Release);
+    restore_interrupts(interrupts);
+}
+
+fn disable_interrupts() -> bool {
+    let enabled = sstatus::read().sie();
+    unsafe { sstatus::clear_sie() };
+    enabled
+}
+
+fn restore_interrupts(enabled: bool) {
+    if enabled {
+        unsafe { sstatus::set_sie() };
+    }
 }
@@ -1,22 +1,30 @@
-use crate::capability::grant_kernel_capability;
+use crate::anonymous::AnonymousRegion;
+use crate::capability::{
+    apply_transfers, grant_shared_kernel_capability, validate_capability,
+    validate_capability_rights,
+};
 use crate::drvli::SyscallHandler;
-use crate::heap::granularity::{PageGranular, page_granular_vec};
+use crate::heap::granularity::PageGranular;
 use crate::log::log_userspace;
-use crate::page::{PageFlags, virt_to_phys};
-use crate::process::{Message, ProcessState, get_process, kill};
+use crate::page::{PageFlags, phys_to_virt};
+use crate::process::{
+    Message, PendingCall, Process, ProcessState, ServedCall, cancel_call, get_process,
+    has_finished, is_wait_over, kill,
+};
 use crate::stack::UserCtx;
 use crate::syscall::SyscallAction::Yield;
-use crate::user::{UserPtr, UserSyscallError, with_sum};
+use crate::user::{UserPtr, UserSyscallError};
 use crate::util::untyped_box::UntypedBox;
 use crate::{capability, shared_memory};
-use alloc::boxed::Box;
-use alloc::string::String;
+use alloc::format;
+use alloc::string::{String, ToString};
 use alloc::sync::Arc;
 use alloc::vec::Vec;
-use core::alloc::Layout;
-use core::ops::DerefMut;
+use core::sync::atomic::Ordering;
 use deravel_types::{
-    Actor, CACHE_LINE_SIZE, Capability, PAGE_SIZE, ProcessId, RawCapability, SharedMemory,
+    Actor, CACHE_LINE_SIZE, Capability, CapabilityCertificate, CapabilityCertificateUnpacked,
+    ExitReason, IPC_DISCONNECTED, IPC_PENDING, PAGE_SIZE, ProcessId, RawCapability, SharedMemory,
+    method_right, stream_right,
 };
 use log::Level;
 
@@ -29,7 +37,13 @@ pub enum SyscallAction {
 
 impl SyscallHandler for () {
     fn exit(user: &mut UserCtx) -> Result<!> {
-        user.process().state = ProcessState::Finished;
+        user.process().finish(ExitReason::Exit);
+        Err(Yield)
+    }
+
+    fn abort(user: &mut UserCtx, message: UserPtr<[u8]>) -> Result<!> {
+        let message = String::from_utf8_lossy(&message.copy_to_kernel()).into_owned();
+        user.process().finish(ExitReason::Panic(message));
         Err(Yield)
     }
 
@@ -41,22 +55,35 @@ impl SyscallHandler for () {
         mut result_buffer: UserPtr<[u8]>,
     ) -> Result<usize> {
         let mut proc = user.process();
-        let cap = match with_sum(|| cap.validate(proc.id)) {
+        let cap = match start_call(&mut proc, cap, method) {
             Ok(cap) => cap,
             Err(err) => kill!(user, proc, "{err}"),
         };
+        let mut loans = core::mem::take(&mut proc.loans);
+        let deadline = core::mem::replace(&mut proc.call_deadline, u64::MAX);
 
         match cap.certifier() {
             Actor::Userspace(dest) => {
+                if dest == proc.id {
+                    kill!(
+                        user,
+                        proc,
+                        "blocking call of {cap:?} served by the caller itself"
+                    )
+                }
                 let Some(mut dest) = get_process(dest).lock_if_some() else {
                     // This can't actually happen because capability validation will catch this
                     // earlier, but let's check in case the design changes later.
                     kill!(user, proc, "ipc send to nonexistent process");
                 };
+                if dest.state == ProcessState::Finished {
+                    return Ok(IPC_DISCONNECTED);
+                }
 
                 proc.state = ProcessState::WaitingForReply {
                     from: cap.certifier(),
                     result_buffer,
+                    deadline,
                 };
 
                 dest.messages.push_back(Message {
@@ -64,6 +91,8 @@ impl SyscallHandler for () {
                     method,
                     args: args_buffer.copy_to_kernel(),
                     sender: user.pid(),
+                    call: None,
+                    loans,
                 });
 
                 Err(Yield)
@@ -71,47 +100,215 @@ impl SyscallHandler for () {
             Actor::Kernel => {
                 drop(proc);
                 let handler = capability::get_handler(cap.local_index());
-                let result = handler.call_method(method, &args_buffer.copy_to_kernel(), user.pid());
-                if let Err(err) = result_buffer.write_to_user(&result) {
-                    kill!(user, "{err}")
+                let args = args_buffer.copy_to_kernel();
+                let Some(result) = handler.call_method(method, &args, user.pid(), &mut loans)
+                else {
+                    kill!(user, "invalid call of method {method} on {cap:?}")
                 };
-                Ok(result.len())
+                let result_len = result.len();
+                if result_buffer.write_to_user(&result).is_err() {
+                    user.process().oversized_reply = Some(result);
+                }
+                Ok(result_len)
             }
         }
     }
 
-    fn ipc_receive(
+    fn ipc_deadline(user: &mut UserCtx, deadline: u64) -> Result<()> {
+        user.process().call_deadline = deadline;
+        Ok(())
+    }
+
+    fn ipc_send(
         user: &mut UserCtx,
-        mut args: UserPtr<[u8]>,
-    ) -> Result<(Option<RawCapability>, usize, usize, Option<ProcessId>)> {
+        cap: RawCapability,
+        method: usize,
+        args_buffer: UserPtr<[u8]>,
+    ) -> Result<usize> {
         let mut proc = user.process();
-        if proc.currently_serving.is_some() {
-            kill!(user, proc, "ipc receive without replying to previous one")
+        if !proc.loans.is_empty() {
+            kill!(user, proc, "loans are only supported in blocking calls")
         }
-        let Some(message) = proc.messages.pop_front() else {
-            return Ok((None, 0, 0, None));
+        if proc.call_deadline != u64::MAX {
+            kill!(user, proc, "deadlines are only supported in blocking calls")
+        }
+        let cap = match start_call(&mut proc, cap, method) {
+            Ok(cap) => cap,
+            Err(err) => kill!(user, proc, "{err}"),
         };
-        if let Err(err) = args.write_to_user(&message.args) {
-            kill!(user, proc, "{err}")
+        let id = proc.next_call_id;
+        proc.next_call_id = id.wrapping_add(1);
+        let args = args_buffer.copy_to_kernel();
+        let reply = match cap.certifier() {
+            Actor::Userspace(dest) => {
+                let message = Message {
+                    cap,
+                    method,
+                    args,
+                    sender: proc.id,
+                    call: Some(id),
+                    loans: Vec::new(),
+                };
+                if dest == proc.id {
+                    proc.messages.push_back(message);
+                } else if let Some(mut dest) = get_process(dest).lock_if_some() {
+                    dest.messages.push_back(message);
+                }
+                None
+            }
+            Actor::Kernel => {
+                // Kernel handlers may need to lock other processes, including this one.
+                drop(proc);
+                let handler = capability::get_handler(cap.local_index());
+                let Some(reply) = handler.call_method(method, &args, user.pid(), &mut []) else {
+                    kill!(user, "invalid call of method {method} on {cap:?}")
+                };
+                proc = user.process();
+                Some(reply)
+            }
         };
-        proc.currently_serving = Some(message.sender);
+        proc.pending_calls.push(PendingCall {
+            id,
+            from: cap.certifier(),
+            reply,
+            disconnected: false,
+        });
+        Ok(id)
+    }
+
+    fn ipc_poll_reply(
+        user: &mut UserCtx,
+        call: usize,
+        mut result_buffer: UserPtr<[u8]>,
+    ) -> Result<usize> {
+        let mut proc = user.process();
+        let Some(index) = proc.pending_calls.iter().position(|c| c.id == call) else {
+            kill!(user, proc, "poll of unknown call {call}")
+        };
+        let pending = &proc.pending_calls[index];
+        let result_len = if let Some(reply) = &pending.reply {
+            // The call stays pending, so the caller can poll again with a large enough buffer.
+            if result_buffer.write_to_user(reply).is_err() {
+                return Ok(reply.len());
+            }
+            reply.len()
+        } else if pending.disconnected
+            || (pending.from != proc.id.into() && has_finished(pending.from))
+        {
+            IPC_DISCONNECTED
+        } else {
+            return Ok(IPC_PENDING);
+        };
+        proc.pending_calls.swap_remove(index);
+        Ok(result_len)
+    }
+
+    fn ipc_discard(user: &mut UserCtx, call: usize) -> Result<()> {
+        let mut proc = user.process();
+        let Some(index) = proc.pending_calls.iter().position(|c| c.id == call) else {
+            kill!(user, proc, "discard of unknown call {call}")
+        };
+        let pending = proc.pending_calls.swap_remove(index);
+        if pending.reply.is_some() {
+            return Ok(());
+        }
+        let pid = proc.id;
+        match pending.from {
+            Actor::Userspace(server) if server == pid => cancel_call(&mut proc, pid, Some(call)),
+            Actor::Userspace(server) => {
+                if let Some(mut server) = get_process(server).lock_if_some() {
+                    cancel_call(&mut server, pid, Some(call));
+                }
+            }
+            Actor::Kernel => {}
+        }
+        Ok(())
+    }
+
+    fn ipc_receive(
+        user: &mut UserCtx,
+        mut args: UserPtr<[u8]>,
+    ) -> Result<(
+        Option<RawCapability>,
+        usize,
+        usize,
+        Option<ProcessId>,
+        usize,
+    )> {
+        let mut proc = user.process();
+        let Some(message) = proc.messages.front() else {
+            return Ok((None, 0, 0, None, 0));
+        };
+        // The message stays queued, so the receiver can retry with a large enough buffer.
+        if args.write_to_user(&message.args).is_err() {
+            return Ok((None, 0, message.args.len(), None, 0));
+        }
+        let message = proc.messages.pop_front().unwrap();
+        let mut loans = Vec::new();
+        for loan in message.loans {
+            loans.push(loan.map_into(&mut proc)?);
+        }
+        proc.flush_tlb();
+        let token = proc.next_reply_token;
+        proc.next_reply_token = token.wrapping_add(1);
+        proc.serving.push(ServedCall {
+            token,
+            caller: message.sender,
+            call: message.call,
+            loans,
+            cancelled: false,
+        });
         Ok((
             Some(message.cap),
             message.method,
             message.args.len(),
             Some(message.sender),
+            token,
         ))
     }
 
-    fn ipc_reply(user: &mut UserCtx, result: UserPtr<[u8]>) -> Result<()> {
+    fn ipc_reply(user: &mut UserCtx, token: usize, result: UserPtr<[u8]>) -> Result<()> {
         let mut proc = user.process();
-        let Some(caller) = proc.currently_serving.take() else {
-            kill!(user, proc, "ipc_reply called without matching ipc_serve")
+        let Some(ServedCall {
+            caller,
+            call,
+            cancelled,
+            ..
+        }) = take_served_call(&mut proc, token)
+        else {
+            kill!(user, proc, "ipc_reply with unknown token {token}")
+        };
+        let transfers = core::mem::take(&mut proc.transfers);
+        if cancelled {
+            // The caller stopped waiting, so the reply and any capabilities in it are dropped.
+            return Ok(());
+        }
+        if caller == proc.id {
+            // Calls a process serves itself can only have been sent without blocking.
+            if let Err(err) = apply_transfers(proc.id, &transfers, caller.into()) {
+                kill!(user, proc, "transfer failed, {err}")
+            }
+            if let Some(pending) = find_pending_call(&mut proc, caller.into(), call) {
+                pending.reply = Some(result.copy_to_kernel());
+            }
+            return Ok(());
+        }
+        let Some(mut caller) = get_process(caller).lock_if_some() else {
+            // The caller was killed while its call was being served.
+            return Ok(());
         };
-        let mut caller = get_process(caller).lock_if_some().unwrap();
-        if let ProcessState::WaitingForReply {
+        if let Err(err) = apply_transfers(proc.id, &transfers, caller.id.into()) {
+            kill!(user, proc, "transfer failed, {err}")
+        }
+        if call.is_some() {
+            if let Some(pending) = find_pending_call(&mut caller, proc.id.into(), call) {
+                pending.reply = Some(result.copy_to_kernel());
+            }
+            Ok(())
+        } else if let ProcessState::WaitingForReply {
             from,
             result_buffer,
+            ..
         } = &caller.state
         {
             if *from != Actor::Userspace(proc.id) {
@@ -131,109 +328,280 @@ impl SyscallHandler for () {
             else {
                 kill!(user, proc, "invalid stream map reply")
             };
-            let Ok(ring) = with_sum(|| stream.validate(caller.id)) else {
+            let Ok(ring) = validate_capability(stream, caller.id) else {
                 kill!(user, proc, "ring {stream:?} not valid for sender");
             };
             if ring.certifier() != Actor::Kernel {
                 kill!(user, proc, "shared memory must be granted by the kernel");
             }
-            let handler = capability::get_handler(ring.local_index());
-            let length = handler.shared_memory_size();
+            let handler = capability::get_handler_ref(ring.local_index());
+            let Some(length) = handler.shared_memory_size() else {
+                kill!(user, proc, "ring {stream:?} is not shared memory")
+            };
             if !length.is_multiple_of(PAGE_SIZE) {
                 kill!(user, proc, "stream size must be a multiple of page size")
             }
             if length < 2 * CACHE_LINE_SIZE + declared_size {
                 kill!(user, proc, "stream length does not match memory size")
             }
+            // Mapped right away, as the server could release the ring before the caller resumes.
+            let Ok(virt) = caller.map_shared(handler, length) else {
+                log::error!(
+                    "killed {}{:?}, out of address space for stream",
+                    caller.name,
+                    caller.id
+                );
+                caller.finish(ExitReason::Killed("out of address space for stream".into()));
+                return Ok(());
+            };
             caller.state = ProcessState::ReadyStreamMap {
-                ring,
+                virt,
                 declared_size,
             };
             Ok(())
+        } else if caller.state == ProcessState::Finished {
+            Ok(())
         } else {
             unimplemented!()
         }
     }
 
+    fn ipc_take_reply(user: &mut UserCtx, mut result_buffer: UserPtr<[u8]>) -> Result<usize> {
+        let mut proc = user.process();
+        let Some(reply) = proc.oversized_reply.take() else {
+            kill!(
+                user,
+                proc,
+                "ipc_take_reply called without an oversized reply"
+            )
+        };
+        if let Err(err) = result_buffer.write_to_user(&reply) {
+            kill!(user, proc, "{err}")
+        }
+        Ok(reply.len())
+    }
+
+    fn ipc_loan(user: &mut UserCtx, buffer: UserPtr<[u8]>, writable: u8) -> Result<()> {
+        let mut proc = user.process();
+        let range = buffer.range()?;
+        let Some(loan) = proc.lend(range.clone(), writable != 0) else {
+            kill!(
+                user,
+                proc,
+                "loan of {range:#x?} outside of a single allocation"
+            )
+        };
+        proc.loans.push(loan);
+        Ok(())
+    }
+
+    fn ipc_loans(
+        user: &mut UserCtx,
+        token: usize,
+        mut addresses: UserPtr<[usize]>,
+    ) -> Result<usize> {
+        let proc = user.process();
+        let Some(served) = proc.serving.iter().find(|served| served.token == token) else {
+            kill!(user, proc, "ipc_loans with unknown token {token}")
+        };
+        let loans: Vec<usize> = served
+            .loans
+            .iter()
+            .flat_map(|loan| [loan.address, loan.len])
+            .collect();
+        // The count alone tells the server the caller lent more buffers than the method takes.
+        let _ = addresses.write_to_user(&loans);
+        Ok(served.loans.len())
+    }
+
+    fn ipc_cancelled(user: &mut UserCtx, token: usize) -> Result<u8> {
+        let proc = user.process();
+        let Some(served) = proc.serving.iter().find(|served| served.token == token) else {
+            kill!(user, proc, "ipc_cancelled with unknown token {token}")
+        };
+        Ok(served.cancelled as u8)
+    }
+
+    fn ipc_abandon(user: &mut UserCtx, token: usize) -> Result<()> {
+        let mut proc = user.process();
+        let Some(served) = take_served_call(&mut proc, token) else {
+            kill!(user, proc, "ipc_abandon with unknown token {token}")
+        };
+        if served.cancelled {
+            return Ok(());
+        }
+        if served.caller == proc.id {
+            let pid = proc.id;
+            if let Some(pending) = find_pending_call(&mut proc, pid.into(), served.call) {
+                pending.disconnected = true;
+            }
+            return Ok(());
+        }
+        let Some(mut caller) = get_process(served.caller).lock_if_some() else {
+            return Ok(());
+        };
+        // The caller sees the call fail as if the server had finished.
+        if served.call.is_some() {
+            if let Some(pending) = find_pending_call(&mut caller, proc.id.into(), served.call) {
+                pending.disconnected = true;
+            }
+        } else if let ProcessState::WaitingForReply { from, .. } = caller.state
+            && from == proc.id.into()
+        {
+            caller.registers.a0 = IPC_DISCONNECTED;
+            caller.state = ProcessState::Runnable;
+        } else if let ProcessState::WaitingForStreamMap { from } = caller.state
+            && from == proc.id.into()
+        {
+            log::error!(
+                "killed {}{:?}, stream map abandoned by {:?}",
+                caller.name,
+                caller.id,
+                proc.id
+            );
+            caller.finish(ExitReason::Killed("stream map abandoned".into()));
+        }
+        Ok(())
+    }
+
+    fn ipc_transfer(user: &mut UserCtx, caps: UserPtr<[usize]>) -> Result<()> {
+        let mut proc = user.process();
+        let caps = caps.copy_to_kernel();
+        if !caps.len().is_multiple_of(2) {
+            kill!(user, proc, "transfer of an odd number of capability slots")
+        }
+        for pair in caps.chunks_exact(2) {
+            let cap = RawCapability::try_from(pair[0] as *const CapabilityCertificate)?;
+            let slot = RawCapability::try_from(pair[1] as *const CapabilityCertificate)?;
+            if slot.certifier() != Actor::Userspace(proc.id) {
+                kill!(
+                    user,
+                    proc,
+                    "transfer into {slot:?} certified by someone else"
+                )
+            }
+            proc.transfers.push((cap, slot));
+        }
+        Ok(())
+    }
+
     fn ipc_stream(
         user: &mut UserCtx,
         cap: RawCapability,
         stream: usize,
     ) -> Result<(*mut (), usize)> {
         let mut proc = user.process();
-        let cap = match with_sum(|| cap.validate(proc.id)) {
+        let (cap, rights) = match validate_capability_rights(cap, proc.id) {
             Ok(cap) => cap,
             Err(err) => kill!(user, proc, "{err}"),
         };
+        if rights & stream_right(stream) == 0 {
+            kill!(user, proc, "{cap:?} does not grant stream {stream}")
+        }
         match cap.certifier() {
             Actor::Userspace(original_pid) => {
+                if original_pid == proc.id {
+                    kill!(user, proc, "stream of {cap:?} served by the caller itself")
+                }
+                let Some(mut dest) = get_process(original_pid).lock_if_some() else {
+                    kill!(user, proc, "stream of nonexistent process");
+                };
                 proc.state = ProcessState::WaitingForStreamMap {
                     from: original_pid.into(),
                 };
-                let mut dest = get_process(original_pid).lock_if_some().unwrap();
                 dest.messages.push_back(Message {
                     cap,
                     method: 1000 + stream,
                     args: Vec::new(),
                     sender: user.pid(),
+                    call: None,
+                    loans: Vec::new(),
                 });
 
                 Err(Yield)
             }
             Actor::Kernel => {
-                let handler = capability::get_handler(cap.local_index());
-                let ring_buffer = handler.map_stream(stream);
-                let ring_buffer_size = size_of_val(ring_buffer);
-                let ring_buffer_layout =
-                    Layout::from_size_align(ring_buffer_size, PAGE_SIZE).unwrap();
-
-                let virt = proc.heap.alloc(ring_buffer_layout)?;
-                proc.page_table.map(
-                    virt,
-                    virt_to_phys(ring_buffer as *const _ as *const u8) as usize,
-                    PAGE_SIZE,
-                    PageFlags::read_write().user(),
-                );
-                riscv::asm::sfence_vma_all();
-                Ok((virt as *mut (), ring_buffer.0.data.0.len()))
+                let handler = capability::get_handler_ref(cap.local_index());
+                let Some(ring) = handler.map_stream(stream) else {
+                    kill!(user, proc, "{cap:?} has no stream {stream}")
+                };
+                let virt = proc.map_stream(handler, ring)?;
+                proc.flush_tlb();
+                Ok((virt as *mut (), ring.0.data.0.len()))
             }
         }
     }
 
+    fn unmap_stream(user: &mut UserCtx, ring: UserPtr<u8>) -> Result<()> {
+        let mut proc = user.process();
+        let virt = ring.as_ptr().addr();
+        // Streams served by userspace are mapped as shared memory.
+        if proc.unmap_stream(virt).is_err() && proc.unmap_shared(virt).is_err() {
+            kill!(user, proc, "unmap of unmapped stream")
+        }
+        proc.flush_tlb();
+        Ok(())
+    }
+
+    fn revoke(user: &mut UserCtx, cap: RawCapability) -> Result<()> {
+        if cap.certifier() != Actor::Userspace(user.pid()) {
+            kill!(user, "revoke of {cap:?} certified by someone else")
+        }
+        capability::revoke_capability(cap);
+        Ok(())
+    }
+
     fn alloc(user: &mut UserCtx, size: usize) -> Result<*mut u8> {
         let size = size.next_multiple_of(PAGE_SIZE);
-        let mut pages = Vec::try_with_capacity_in(size, PageGranular::new())?;
-        pages.resize(size, 0u8);
-        let pages = Arc::new(UntypedBox::new(pages.into_boxed_slice()));
-        let virt = user
-            .process()
-            .alloc(pages, PageFlags::read_write().user())?;
-        riscv::asm::sfence_vma_all();
-        Ok(virt)
+        let region = AnonymousRegion::zeroed(size, PageFlags::read_write().user());
+        let virt = user.process().alloc_anonymous(region);
+        Ok(virt.unwrap_or_default())
+    }
+
+    fn reserve(user: &mut UserCtx, size: usize) -> Result<*mut u8> {
+        let size = size.next_multiple_of(PAGE_SIZE);
+        let region = AnonymousRegion::reserved(size, PageFlags::read_write().user());
+        let virt = user.process().alloc_anonymous(region);
+        Ok(virt.unwrap_or_default())
+    }
+
+    fn commit(user: &mut UserCtx, p: UserPtr<u8>, size: usize) -> Result<u8> {
+        let mut proc = user.process();
+        let start = p.as_ptr().addr();
+        let Some(end) = start.checked_add(size) else {
+            kill!(user, proc, "commit range overflows")
+        };
+        match proc.commit(start..end) {
+            Ok(committed) => Ok(committed as u8),
+            Err(()) => kill!(user, proc, "commit outside of a single reservation"),
+        }
     }
 
     fn alloc_shared(
         user: &mut UserCtx,
         size: usize,
-    ) -> Result<(*mut u8, Capability<SharedMemory>)> {
+    ) -> Result<(*mut u8, Option<Capability<SharedMemory>>)> {
         let size = size.next_multiple_of(PAGE_SIZE);
-        let pages = Arc::new(UntypedBox::new(
-            page_granular_vec![0u8; size].into_boxed_slice(),
-        ));
-        let virt = user
-            .process()
-            .alloc(pages.clone(), PageFlags::read_write().user())?;
-        riscv::asm::sfence_vma_all();
-        let cap = grant_kernel_capability(
+        let Ok(mut pages) = Vec::try_with_capacity_in(size, PageGranular::new()) else {
+            return Ok((core::ptr::null_mut(), None));
+        };
+        pages.resize(size, 0u8);
+        let pages = Arc::new(UntypedBox::new(pages.into_boxed_slice()));
+        let mut proc = user.process();
+        let Ok(virt) = proc.alloc(pages.clone(), PageFlags::read_write().user()) else {
+            return Ok((core::ptr::null_mut(), None));
+        };
+        proc.flush_tlb();
+        let cap = grant_shared_kernel_capability(
             user.pid(),
-            Box::leak(Box::new(shared_memory::SharedMemory { backing: pages })),
+            Arc::new(shared_memory::SharedMemory { backing: pages }),
         );
-        Ok((virt, cap))
+        Ok((virt, Some(cap)))
     }
 
     fn map_shared(user: &mut UserCtx, cap: Capability<SharedMemory>) -> Result<(*mut u8, usize)> {
         let mut proc = user.process();
-        let cap = match with_sum(|| cap.validate(proc.id)) {
+        let cap = match validate_capability(cap.as_raw(), proc.id) {
             Ok(cap) => cap,
             Err(err) => kill!(user, proc, "{err}"),
         };
@@ -241,23 +609,45 @@ impl SyscallHandler for () {
             kill!(user, proc, "non-kernel shared memory capability")
         }
 
-        let handler = capability::get_handler(cap.local_index());
-        let length = handler.shared_memory_size();
-        let padded_length = length.next_multiple_of(PAGE_SIZE);
-        let layout = Layout::from_size_align(padded_length, PAGE_SIZE).unwrap();
-
-        let virt = proc.heap.alloc(layout)?;
-        let proc = proc.deref_mut();
-        handler.shared_memory_map(
-            virt,
-            &mut proc.page_table,
-            &mut proc.virtual_memory_mappings,
-        );
-        riscv::asm::sfence_vma_all();
+        let handler = capability::get_handler_ref(cap.local_index());
+        let Some(length) = handler.shared_memory_size() else {
+            kill!(user, proc, "{cap:?} is not shared memory")
+        };
+        let virt = proc.map_shared(handler, length)?;
+        proc.flush_tlb();
 
         Ok((virt as *mut u8, length))
     }
 
+    fn unmap_shared(user: &mut UserCtx, ptr: UserPtr<u8>) -> Result<()> {
+        let mut proc = user.process();
+        if proc.unmap_shared(ptr.as_ptr().addr()).is_err() {
+            kill!(user, proc, "unmap of unmapped pointer")
+        }
+        proc.flush_tlb();
+        Ok(())
+    }
+
+    fn release(user: &mut UserCtx, cap: RawCapability) -> Result<()> {
+        let proc = user.process();
+        if let Err(err) = validate_capability(cap, proc.id) {
+            kill!(user, proc, "{err}");
+        }
+        drop(proc);
+        // Only the caller's own certificate is revoked, as the capability it was forwarded from
+        // still belongs to whoever forwarded it.
+        let owns_handler = cap.certifier() == Actor::Kernel
+            && matches!(
+                capability::capability_certificate(cap).load(Ordering::Relaxed).unpack(),
+                CapabilityCertificateUnpacked::Granted { grantee } if grantee == Actor::Userspace(user.pid())
+            );
+        capability::revoke_capability(cap);
+        if owns_handler {
+            capability::release_kernel_capability(cap.local_index());
+        }
+        Ok(())
+    }
+
     fn free(user: &mut UserCtx, ptr: UserPtr<u8>) -> Result<()> {
         let mut proc = user.process();
         if proc.dealloc(ptr.as_ptr()).is_err() {
@@ -270,6 +660,30 @@ impl SyscallHandler for () {
         Err(Yield)
     }
 
+    fn wait(user: &mut UserCtx, rings: UserPtr<[usize]>, deadline: u64) -> Result<()> {
+        let mut proc = user.process();
+        let mut watched = Vec::new();
+        for ring in rings.copy_to_kernel() {
+            let Some((phys, flags)) = proc.page_table.translate(ring) else {
+                kill!(user, proc, "wait on unmapped ring {ring:#x}")
+            };
+            // Both counters must be in the same page as the start of the ring.
+            if !flags.is_user() || phys % PAGE_SIZE + 2 * CACHE_LINE_SIZE > PAGE_SIZE {
+                kill!(user, proc, "wait on invalid ring {ring:#x}")
+            }
+            watched.push(phys_to_virt(phys));
+        }
+        proc.state = ProcessState::Waiting {
+            rings: watched,
+            deadline,
+        };
+        if is_wait_over(&proc) {
+            proc.state = ProcessState::Runnable;
+            return Ok(());
+        }
+        Err(Yield)
+    }
+
     fn log(user: &mut UserCtx, message: UserPtr<[u8]>, level: u64) -> Result<()> {
         let Ok(text) = String::from_utf8(message.copy_to_kernel()) else {
             kill!(user, "invalid utf-8")
@@ -292,3 +706,44 @@ impl<T: Into<UserSyscallError>> From<T> for SyscallAction {
         SyscallAction::UserErr(err.into())
     }
 }
+
+// Finds the call the process sent without blocking to the actor, if it's still pending.
+fn find_pending_call(
+    proc: &mut Process,
+    from: Actor,
+    call: Option<usize>,
+) -> Option<&mut PendingCall> {
+    let call = call?;
+    proc.pending_calls
+        .iter_mut()
+        .find(|pending| pending.id == call && pending.from == from)
+}
+
+// Takes the call out of the ones the process serves, unmapping what the caller lent for it.
+fn take_served_call(proc: &mut Process, token: usize) -> Option<ServedCall> {
+    let index = proc
+        .serving
+        .iter()
+        .position(|served| served.token == token)?;
+    let mut served = proc.serving.swap_remove(index);
+    for loan in served.loans.drain(..) {
+        loan.unmap(proc);
+    }
+    proc.flush_tlb();
+    Some(served)
+}
+
+fn start_call(
+    proc: &mut Process,
+    cap: RawCapability,
+    method: usize,
+) -> core::result::Result<RawCapability, String> {
+    let (cap, rights) = validate_capability_rights(cap, proc.id).map_err(|err| err.to_string())?;
+    if rights & method_right(method) == 0 {
+        return Err(format!("{cap:?} does not grant method {method}"));
+    }
+    let transfers = core::mem::take(&mut proc.transfers);
+    apply_transfers(proc.id, &transfers, cap.certifier())
+        .map_err(|err| format!("transfer failed, {err}"))?;
+    Ok(cap)
+}
@@ -0,0 +1,39 @@
+use crate::device_tree::{boot_argument, timebase_frequency};
+use crate::sbi;
+use core::sync::atomic::{AtomicU64, Ordering};
+use fdt::Fdt;
+use log::{info, warn};
+
+static TIME_SLICE: AtomicU64 = AtomicU64::new(0);
+
+const DEFAULT_TIME_SLICE_MICROS: u64 = 10_000;
+
+pub fn initialize_time_slice(dt: &Fdt) {
+    let Some(frequency) = timebase_frequency() else {
+        return warn!("preemption disabled as timebase frequency is unknown");
+    };
+    let micros = match boot_argument(dt, "time_slice_us").map(str::parse) {
+        Some(Ok(micros)) => micros,
+        Some(Err(_)) => {
+            warn!("invalid time_slice_us boot argument, using default");
+            DEFAULT_TIME_SLICE_MICROS
+        }
+        None => DEFAULT_TIME_SLICE_MICROS,
+    };
+    let ticks = (frequency.get() as u64 * micros / 1_000_000).max(1);
+    info!("time slice is {micros}us, {ticks} timebase ticks");
+    TIME_SLICE.store(ticks, Ordering::Relaxed);
+}
+
+pub fn arm_time_slice() {
+    let ticks = TIME_SLICE.load(Ordering::Relaxed);
+    if ticks == 0 {
+        sbi::set_timer(u64::MAX);
+    } else {
+        sbi::set_timer(now() + ticks);
+    }
+}
+
+pub fn now() -> u64 {
+    riscv::register::time::read64()
+}
@@ -1,6 +1,7 @@
 use alloc::collections::TryReserveError;
 use alloc::vec::Vec;
 use core::alloc::AllocError;
+use core::ops::Range;
 use core::ptr::NonNull;
 use deravel_types::InvalidCapabilityError;
 
@@ -63,6 +64,17 @@ impl<T: SafeUserType> UserPtr<[T]> {
         Ok(UserPtr(ptr))
     }
 
+    pub fn range(&self) -> Result<Range<usize>, UserPtrInvalid> {
+        let start = self.0.as_mut_ptr().addr();
+        let end = self
+            .0
+            .len()
+            .checked_mul(size_of::<T>())
+            .and_then(|size| start.checked_add(size))
+            .ok_or(UserPtrInvalid)?;
+        Ok(start..end)
+    }
+
     pub fn copy_to_kernel(&self) -> Vec<T> {
         let mut kernel = Vec::with_capacity(self.0.len());
         with_sum(|| unsafe {
@@ -86,6 +98,8 @@ impl<T: SafeUserType> UserPtr<[T]> {
 
 unsafe impl SafeUserType for u8 {}
 
+unsafe impl SafeUserType for usize {}
+
 unsafe impl<T: Send + ?Sized> Send for UserPtr<T> {}
 
 impl<T: ?Sized> Clone for UserPtr<T> {
@@ -1,5 +1,5 @@
-use crate::capability::grant_kernel_capability;
-use crate::drvli::DriveServer;
+use crate::capability::grant_shared_kernel_capability;
+use crate::drvli::{DriveMappingServer, DriveServer};
 use crate::interrupt::InterruptHandler;
 use crate::page::Page;
 use crate::sync::Mutex;
@@ -10,8 +10,9 @@ use crate::virtio::registers::{STATUS_ACKNOWLEDGE, STATUS_DRIVER, STATUS_DRIVER_
 use crate::virtio::{Capabilities, Isr};
 use crate::virtual_memory::{VirtualMemoryLoader, VirtualMemoryMapping};
 use alloc::boxed::Box;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
-use deravel_types::{Capability, PAGE_SIZE, ProcessId, SharedMemory};
+use deravel_types::{Capability, DriveMapping, PAGE_SIZE, ProcessId, SharedMemory};
 use log::*;
 
 volatile_struct! { pub Config
@@ -40,6 +41,10 @@ struct MappedRegion {
     sector_offset: u64,
 }
 
+struct WritableMapping {
+    mapping: Arc<VirtualMemoryMapping<MappedRegion>>,
+}
+
 #[derive(Debug)]
 pub struct VirtioBlkError;
 
@@ -103,6 +108,18 @@ impl VirtioBlk {
     pub fn capacity(&self) -> u64 {
         self.state.lock().device.capacity().read()
     }
+
+    fn mapped_region(&self, first_sector: u64, sector_count: u64) -> (MappedRegion, usize) {
+        let region = MappedRegion {
+            // TODO: Add 'static to {}Server or figure out kernel cap lifetime design.
+            blk: unsafe { &*(self as *const _) },
+            sector_offset: first_sector,
+        };
+        let sector_count = usize::try_from(sector_count).unwrap();
+        let size = sector_count.checked_mul(SECTOR_SIZE).unwrap();
+        assert!(size.is_multiple_of(PAGE_SIZE));
+        (region, size)
+    }
 }
 
 impl InterruptHandler for VirtioBlk {
@@ -124,24 +141,52 @@ impl DriveServer for VirtioBlk {
         first_sector: u64,
         sector_count: u64,
     ) -> Capability<SharedMemory> {
-        let region = MappedRegion {
-            // TODO: Add 'static to {}Server or figure out kernel cap lifetime design.
-            blk: unsafe { &*(self as *const _) },
-            sector_offset: first_sector,
-        };
-        let sector_count = usize::try_from(sector_count).unwrap();
-        let size = sector_count.checked_mul(SECTOR_SIZE).unwrap();
-        assert!(size.is_multiple_of(PAGE_SIZE));
-        grant_kernel_capability(
-            sender,
-            Box::leak(Box::new(VirtualMemoryMapping::new(region, size))),
-        )
+        let (region, size) = self.mapped_region(first_sector, sector_count);
+        grant_shared_kernel_capability(sender, Arc::new(VirtualMemoryMapping::new(region, size)))
+    }
+
+    fn map(
+        &self,
+        sender: ProcessId,
+        first_sector: u64,
+        sector_count: u64,
+    ) -> Capability<DriveMapping> {
+        let (region, size) = self.mapped_region(first_sector, sector_count);
+        let mapping = Arc::new(VirtualMemoryMapping::writable(region, size));
+        grant_shared_kernel_capability(sender, Arc::new(WritableMapping { mapping }))
+    }
+
+    fn read_into(&self, _: ProcessId, first_sector: u64, buf: &mut [u8]) {
+        let (sectors, rest) = buf.as_chunks_mut::<SECTOR_SIZE>();
+        let sector_count = sectors.len() as u64;
+        for (sector, block) in (first_sector..).zip(sectors) {
+            self.read(sector, block).unwrap();
+        }
+        if !rest.is_empty() {
+            let mut block = [0; SECTOR_SIZE];
+            self.read(first_sector + sector_count, &mut block).unwrap();
+            rest.copy_from_slice(&block[..rest.len()]);
+        }
     }
 
     fn write(&self, _: ProcessId, sector: u64, data: &[u8]) {
         self.write(sector, data.try_into().unwrap()).unwrap()
     }
 
+    fn write_from(&self, _: ProcessId, first_sector: u64, buf: &[u8]) {
+        let (sectors, rest) = buf.as_chunks::<SECTOR_SIZE>();
+        let sector_count = sectors.len() as u64;
+        for (sector, block) in (first_sector..).zip(sectors) {
+            self.write(sector, block).unwrap();
+        }
+        if !rest.is_empty() {
+            let mut block = [0; SECTOR_SIZE];
+            self.read(first_sector + sector_count, &mut block).unwrap();
+            block[..rest.len()].copy_from_slice(rest);
+            self.write(first_sector + sector_count, &block).unwrap();
+        }
+    }
+
     fn capacity(&self, _: ProcessId) -> u64 {
         self.capacity()
     }
@@ -156,6 +201,27 @@ impl VirtualMemoryLoader for MappedRegion {
         }
         page
     }
+
+    fn store_page(&self, page_index: usize, page: &Page) {
+        let sector_offset = self.sector_offset + (page_index * PAGE_SIZE / SECTOR_SIZE) as u64;
+        for (i, block) in page.0.as_chunks().0.iter().enumerate() {
+            self.blk.write(sector_offset + i as u64, block).unwrap();
+        }
+    }
+}
+
+impl DriveMappingServer for WritableMapping {
+    fn memory(&self, sender: ProcessId) -> Capability<SharedMemory> {
+        grant_shared_kernel_capability(sender, self.mapping.clone())
+    }
+
+    fn sync(&self, _: ProcessId) {
+        self.mapping.sync()
+    }
+
+    fn evict(&self, _: ProcessId) {
+        self.mapping.evict()
+    }
 }
 
 fn result_from_status(status: u8) -> Result<(), VirtioBlkError> {
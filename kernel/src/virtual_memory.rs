@@ -1,71 +1,213 @@
 use crate::capability::Handler;
+use crate::loan::Loan;
 use crate::page::{Page, PageFlags, PageTable, virt_to_phys};
+use crate::process::get_process;
 use crate::sync::Mutex;
 use alloc::boxed::Box;
 use alloc::vec::Vec;
-use core::ops::Range;
 use deravel_types::{PAGE_SIZE, ProcessId, SharedMemory, UntypedRingBuffer};
 
 pub trait VirtualMemoryLoader {
     fn load_page(&self, page_index: usize) -> Box<Page>;
-}
 
-pub trait VirtualMemoryRawMapping {
-    fn load_page(&self, virt_base: usize, page_index: usize, page_table: &mut PageTable);
+    fn store_page(&self, page_index: usize, page: &Page);
 }
 
-pub struct VirtualMemoryMapping<T> {
+pub struct VirtualMemoryMapping<T: VirtualMemoryLoader> {
     loader: T,
     size: usize,
-    backed_pages: Mutex<Vec<(usize, Box<Page>)>>,
+    flags: PageFlags,
+    state: Mutex<MappingState>,
+}
+
+struct MappingState {
+    backed_pages: Vec<(usize, Box<Page>)>,
+    // Mappings are remembered so dirty pages can be found by looking at the D bits in every page
+    // table, and so evicted pages can be unmapped.
+    mappers: Vec<(ProcessId, usize)>,
+    // Pages whose D bit was lost because the page table mapping them went away.
+    dirty: Vec<usize>,
 }
 
 impl<T: VirtualMemoryLoader> VirtualMemoryMapping<T> {
     pub fn new(loader: T, size: usize) -> VirtualMemoryMapping<T> {
+        VirtualMemoryMapping::with_flags(loader, size, PageFlags::readonly().user())
+    }
+
+    pub fn writable(loader: T, size: usize) -> VirtualMemoryMapping<T> {
+        VirtualMemoryMapping::with_flags(loader, size, PageFlags::read_write().user())
+    }
+
+    fn with_flags(loader: T, size: usize, flags: PageFlags) -> VirtualMemoryMapping<T> {
         assert!(size.is_multiple_of(PAGE_SIZE));
         VirtualMemoryMapping {
-            backed_pages: Mutex::new(Vec::new()),
+            state: Mutex::new(MappingState {
+                backed_pages: Vec::new(),
+                mappers: Vec::new(),
+                dirty: Vec::new(),
+            }),
+            flags,
             size,
             loader,
         }
     }
+
+    /// Stores every page written to since the last sync. Writes that race with the sync are either
+    /// stored now or on the next one.
+    pub fn sync(&self) {
+        let mut dirty = self.collect_dirty();
+        let mut state = self.state.lock();
+        dirty.append(&mut state.dirty);
+        for (page_index, page) in &state.backed_pages {
+            if dirty.contains(page_index) {
+                self.loader.store_page(*page_index, page);
+            }
+        }
+    }
+
+    /// Drops the pages not written to since the last sync, to be loaded again on the next access.
+    pub fn evict(&self) {
+        // Every page is unmapped first, as a page found clean could otherwise be written to right
+        // before being dropped. Dirty ones get mapped again on the next access.
+        let mut dirty = Vec::new();
+        let mappers = self.state.lock().mappers.clone();
+        for (pid, virt_base) in mappers {
+            let Some(mut proc) = get_process(pid).lock_if_some() else {
+                continue;
+            };
+            let state = self.state.lock();
+            for (page_index, page) in &state.backed_pages {
+                let virt = virt_base + PAGE_SIZE * page_index;
+                if maps_page(&proc.page_table, virt, page)
+                    && proc
+                        .page_table
+                        .unmap_dirty(virt, page_phys(page), PAGE_SIZE)
+                    && !dirty.contains(page_index)
+                {
+                    dirty.push(*page_index);
+                }
+            }
+            proc.flush_tlb();
+        }
+        let mut state = self.state.lock();
+        // The D bits of the pages are gone with their entries, so they must be remembered until a
+        // sync.
+        state.dirty.append(&mut dirty);
+        let MappingState {
+            backed_pages,
+            dirty,
+            ..
+        } = &mut *state;
+        backed_pages.retain(|(page_index, _)| dirty.contains(page_index));
+    }
+
+    fn collect_dirty(&self) -> Vec<usize> {
+        let mut dirty = Vec::new();
+        let mappers = self.state.lock().mappers.clone();
+        for (pid, virt_base) in mappers {
+            let Some(mut proc) = get_process(pid).lock_if_some() else {
+                continue;
+            };
+            let state = self.state.lock();
+            for (page_index, page) in &state.backed_pages {
+                let virt = virt_base + PAGE_SIZE * page_index;
+                if maps_page(&proc.page_table, virt, page)
+                    && proc.page_table.take_dirty(virt)
+                    && !dirty.contains(page_index)
+                {
+                    dirty.push(*page_index);
+                }
+            }
+            // Otherwise the hart running the process could keep writing through a cached entry
+            // without setting the D bit again.
+            proc.flush_tlb();
+        }
+        dirty
+    }
 }
 
 impl<T: VirtualMemoryLoader + Sync + 'static> Handler<SharedMemory> for VirtualMemoryMapping<T> {
-    fn call_method(&self, _: usize, _: &[u8], _: ProcessId) -> Vec<u8> {
-        unreachable!()
+    fn call_method(&self, _: usize, _: &[u8], _: ProcessId, _: &mut [Loan]) -> Option<Vec<u8>> {
+        None
     }
 
-    fn map_stream(&self, _: usize) -> &'static UntypedRingBuffer {
-        unreachable!()
+    fn map_stream(&self, _: usize) -> Option<&'static UntypedRingBuffer> {
+        None
     }
 
-    fn shared_memory_map(
+    fn shared_memory_map(&self, mapper: ProcessId, virt: usize, page_table: &mut PageTable) {
+        let mut state = self.state.lock();
+        for (page_index, page) in &state.backed_pages {
+            let virt = virt + PAGE_SIZE * page_index;
+            page_table.map(virt, page_phys(page), PAGE_SIZE, self.flags);
+        }
+        state.mappers.push((mapper, virt));
+    }
+
+    fn shared_memory_unmap(&self, mapper: ProcessId, virt: usize, page_table: &mut PageTable) {
+        let mut state = self.state.lock();
+        let MappingState {
+            backed_pages,
+            mappers,
+            dirty,
+        } = &mut *state;
+        for (page_index, page) in backed_pages.iter() {
+            let virt = virt + PAGE_SIZE * page_index;
+            if page_table.is_mapped(virt)
+                && page_table.unmap_dirty(virt, page_phys(page), PAGE_SIZE)
+                && !dirty.contains(page_index)
+            {
+                dirty.push(*page_index);
+            }
+        }
+        mappers.retain(|m| *m != (mapper, virt));
+    }
+
+    fn shared_memory_fault(
         &self,
         virt: usize,
+        page_index: usize,
         page_table: &mut PageTable,
-        vmms: &mut Vec<(Range<usize>, &'static (dyn VirtualMemoryRawMapping + Sync))>,
-    ) {
-        let backed_pages = self.backed_pages.lock();
-        for (page_index, page) in &*backed_pages {
-            let virt = virt + PAGE_SIZE * page_index;
-            let phys = virt_to_phys(page.as_ref() as *const _) as usize;
-            page_table.map(virt, phys, PAGE_SIZE, PageFlags::readonly().user());
-        }
-        vmms.push((virt..virt + self.size, unsafe { &*(self as *const _) }));
+    ) -> bool {
+        let mut state = self.state.lock();
+        // Other processes mapping the same region must see the same page, or their writes would
+        // get lost.
+        let page = match state
+            .backed_pages
+            .iter()
+            .position(|(i, _)| *i == page_index)
+        {
+            Some(position) => &state.backed_pages[position].1,
+            None => {
+                let page = self.loader.load_page(page_index);
+                state.backed_pages.push((page_index, page));
+                &state.backed_pages.last().unwrap().1
+            }
+        };
+        let virt = virt + PAGE_SIZE * page_index;
+        page_table.map(virt, page_phys(page), PAGE_SIZE, self.flags);
+        true
     }
 
-    fn shared_memory_size(&self) -> usize {
-        self.size
+    fn shared_memory_size(&self) -> Option<usize> {
+        Some(self.size)
     }
 }
 
-impl<T: VirtualMemoryLoader + Sync + 'static> VirtualMemoryRawMapping for VirtualMemoryMapping<T> {
-    fn load_page(&self, virt_base: usize, page_index: usize, page_table: &mut PageTable) {
-        let virt = virt_base + PAGE_SIZE * page_index;
-        let page = self.loader.load_page(page_index);
-        let phys = virt_to_phys(page.as_ref() as *const _) as usize;
-        page_table.map(virt, phys, PAGE_SIZE, PageFlags::readonly().user());
-        self.backed_pages.lock().push((page_index, page));
+impl<T: VirtualMemoryLoader> Drop for VirtualMemoryMapping<T> {
+    fn drop(&mut self) {
+        // No process maps it anymore, so the pages written to are all in the dirty list by now.
+        if self.flags.is_writable() {
+            self.sync();
+        }
     }
 }
+
+// The process could have exited and its ID could have been reused since it was recorded.
+fn maps_page(page_table: &PageTable, virt: usize, page: &Page) -> bool {
+    page_table.translate(virt).map(|(phys, _)| phys) == Some(page_phys(page))
+}
+
+fn page_phys(page: &Page) -> usize {
+    virt_to_phys(page as *const Page) as usize
+}
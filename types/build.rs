@@ -1,6 +1,6 @@
 use deravel_codegen::RustTypeCtx::*;
 use deravel_codegen::parse::parse_drvli;
-use deravel_codegen::{Interface, InterfaceDetails, camel_case, rust_escape_name};
+use deravel_codegen::{ErrorEnum, Interface, InterfaceDetails, camel_case, rust_escape_name};
 use std::fmt::Write;
 
 fn main() {
@@ -9,11 +9,15 @@ fn main() {
         std::fs::read_to_string(format!("{manifest_dir}/../interfaces.drvli")).unwrap();
     let drvli = parse_drvli(&drvli_text);
     let mut output = String::new();
+    for error in &drvli.errors {
+        generate_error(error, &mut output);
+    }
     for struct_ in &drvli.structs {
         let name_camel = camel_case(struct_.name);
+        let copy = if struct_.is_copy() { " Copy," } else { "" };
         writeln!(
             &mut output,
-            "#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]"
+            "#[derive(Clone,{copy} Debug, Default, Deserialize, Serialize)]"
         )
         .unwrap();
         writeln!(&mut output, "#[repr(C)]").unwrap();
@@ -37,7 +41,14 @@ fn main() {
         )
         .unwrap();
         writeln!(&mut output, "}}").unwrap();
-        if let InterfaceDetails::App { args, implements } = &interface.details {
+        generate_rights(interface, &mut output);
+        if let InterfaceDetails::App {
+            args,
+            implements,
+            memory_limit,
+            priority,
+        } = &interface.details
+        {
             writeln!(&mut output, "#[repr(C)]").unwrap();
             writeln!(&mut output, "#[derive(Debug, Deserialize, Serialize)]").unwrap();
             writeln!(&mut output, "pub struct {name_camel}Args {{").unwrap();
@@ -72,6 +83,21 @@ fn main() {
                 "    const NAME: &'static str = \"{name_snake}\";"
             )
             .unwrap();
+            let memory_limit = match memory_limit {
+                Some(memory_limit) => format!("{memory_limit} << 20"),
+                None => "DEFAULT_MEMORY_LIMIT".to_owned(),
+            };
+            writeln!(
+                &mut output,
+                "    const MEMORY_LIMIT: usize = {memory_limit};"
+            )
+            .unwrap();
+            let priority = camel_case(priority.unwrap_or("normal"));
+            writeln!(
+                &mut output,
+                "    const PRIORITY: Priority = Priority::{priority};"
+            )
+            .unwrap();
             writeln!(&mut output, "}}").unwrap();
             generate_spawner(interface, &mut output);
         }
@@ -84,6 +110,68 @@ fn main() {
     println!("cargo::rerun-if-changed=../interfaces.drvli");
 }
 
+fn generate_error(error: &ErrorEnum, out: &mut String) {
+    let name_camel = camel_case(error.name);
+    writeln!(
+        out,
+        "#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]"
+    )
+    .unwrap();
+    writeln!(out, "pub enum {name_camel} {{").unwrap();
+    for variant in &error.variants {
+        let variant_camel = camel_case(variant);
+        writeln!(out, "    {variant_camel},").unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+    writeln!(out, "impl core::fmt::Display for {name_camel} {{").unwrap();
+    writeln!(
+        out,
+        "    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {{"
+    )
+    .unwrap();
+    writeln!(out, "        match self {{").unwrap();
+    for variant in &error.variants {
+        let variant_camel = camel_case(variant);
+        let message = variant.replace('_', " ");
+        writeln!(
+            out,
+            "            {name_camel}::{variant_camel} => write!(f, \"{message}\"),"
+        )
+        .unwrap();
+    }
+    writeln!(out, "        }}").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}").unwrap();
+    writeln!(out, "impl core::error::Error for {name_camel} {{}}").unwrap();
+}
+
+fn generate_rights(interface: &Interface, out: &mut String) {
+    let name_camel = camel_case(interface.name);
+    assert!(
+        interface.methods.len() + interface.streams.len() <= 16,
+        "interface {} has too many methods and streams for a rights mask",
+        interface.name
+    );
+    writeln!(out, "impl {name_camel} {{").unwrap();
+    for (method_index, method) in interface.methods.iter().enumerate() {
+        let name_upper = method.name.to_ascii_uppercase();
+        writeln!(
+            out,
+            "    pub const {name_upper}: u16 = method_right({method_index});"
+        )
+        .unwrap();
+    }
+    for (stream_index, stream) in interface.streams.iter().enumerate() {
+        let name_upper = stream.name.to_ascii_uppercase();
+        writeln!(
+            out,
+            "    pub const {name_upper}: u16 = stream_right({stream_index});"
+        )
+        .unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+}
+
 fn generate_spawner(interface: &Interface, out: &mut String) {
     let camel_name = camel_case(interface.name);
     let snake_case = interface.name;
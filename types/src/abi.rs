@@ -30,6 +30,8 @@ unsafe impl<T: ?Sized> SyscallAbi for *mut T {}
 
 unsafe impl<T> SyscallAbi for Capability<T> {}
 
+unsafe impl<T> SyscallAbi for Option<Capability<T>> {}
+
 unsafe impl SyscallAbi for RawCapability {}
 
 unsafe impl SyscallAbi for Option<RawCapability> {}
@@ -1,11 +1,15 @@
 mod certificate;
 mod pages;
 mod raw;
+mod rights;
 mod typed;
 
 pub use certificate::{
     CapabilityCertificate, CapabilityCertificateUnpacked, CapabilityCertificateValue,
 };
-pub use pages::{CapabilityPage, get_capability_certificate_page};
-pub use raw::{InvalidCapabilityError, RawCapability};
+pub use pages::{
+    CAPABILITIES_PER_PAGE, CapabilityPage, CapabilityPageLookup, get_capability_certificate_page,
+};
+pub use raw::{CapabilityError, InvalidCapabilityError, RawCapability};
+pub use rights::{ALL_RIGHTS, MAX_RIGHTS, method_right, stream_right};
 pub use typed::Capability;
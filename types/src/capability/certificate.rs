@@ -1,4 +1,4 @@
-use crate::{Actor, ProcessId, RawCapability};
+use crate::{ALL_RIGHTS, Actor, ProcessId, RawCapability};
 use core::sync::atomic::{AtomicU64, Ordering};
 
 #[derive(Default)]
@@ -6,18 +6,21 @@ pub struct CapabilityCertificate(AtomicU64);
 
 #[derive(Clone, Copy)]
 pub struct CapabilityCertificateValue {
-    grantee: u32,
+    grantee: u16,
+    rights: u16,
     payload: u32,
 }
 
 #[derive(Debug)]
 pub enum CapabilityCertificateUnpacked {
+    Empty,
     Granted {
         grantee: Actor,
     },
     Forwarded {
         forwardee: Actor,
         inner: RawCapability,
+        rights: u16,
     },
 }
 
@@ -30,6 +33,10 @@ impl CapabilityCertificate {
         unsafe { core::mem::transmute::<u64, CapabilityCertificateValue>(self.0.load(ordering)) }
     }
 
+    pub fn clear(&self, ordering: Ordering) {
+        self.store(CapabilityCertificateValue::empty(), ordering)
+    }
+
     pub fn store(&self, value: CapabilityCertificateValue, ordering: Ordering) {
         self.0.store(
             unsafe { core::mem::transmute::<CapabilityCertificateValue, u64>(value) },
@@ -41,17 +48,16 @@ impl CapabilityCertificate {
 impl CapabilityCertificateValue {
     pub const fn empty() -> CapabilityCertificateValue {
         CapabilityCertificateValue {
-            grantee: u32::MAX,
+            grantee: u16::MAX,
+            rights: 0,
             payload: 0,
         }
     }
 
     pub fn granted(grantee: impl Into<Actor>) -> CapabilityCertificateValue {
         CapabilityCertificateValue {
-            grantee: match grantee.into() {
-                Actor::Userspace(pid) => pid.as_u16() as u32,
-                Actor::Kernel => 0,
-            },
+            grantee: actor_to_grantee(grantee.into()),
+            rights: ALL_RIGHTS,
             payload: 0,
         }
     }
@@ -59,21 +65,30 @@ impl CapabilityCertificateValue {
     pub fn forwarded(
         forwardee: impl Into<Actor>,
         capability: RawCapability,
+    ) -> CapabilityCertificateValue {
+        Self::forwarded_with_rights(forwardee, capability, ALL_RIGHTS)
+    }
+
+    pub fn forwarded_with_rights(
+        forwardee: impl Into<Actor>,
+        capability: RawCapability,
+        rights: u16,
     ) -> CapabilityCertificateValue {
         CapabilityCertificateValue {
-            grantee: match forwardee.into() {
-                Actor::Userspace(pid) => pid.as_u16() as u32,
-                Actor::Kernel => 0,
-            },
+            grantee: actor_to_grantee(forwardee.into()),
+            rights,
             payload: capability.as_usize() as u32,
         }
     }
 
     pub fn unpack(self) -> CapabilityCertificateUnpacked {
+        if self.grantee == u16::MAX {
+            return CapabilityCertificateUnpacked::Empty;
+        }
         let recipient = if self.grantee == 0 {
             Actor::Kernel
         } else {
-            Actor::Userspace(ProcessId::new(self.grantee as u16))
+            Actor::Userspace(ProcessId::new(self.grantee))
         };
         if self.payload == 0 {
             CapabilityCertificateUnpacked::Granted { grantee: recipient }
@@ -82,16 +97,25 @@ impl CapabilityCertificateValue {
                 forwardee: recipient,
                 inner: RawCapability::try_from(self.payload as *const CapabilityCertificate)
                     .unwrap(),
+                rights: self.rights,
             }
         }
     }
 
     pub fn replace_recipient(self, recipient: impl Into<Actor>) -> CapabilityCertificateValue {
         match self.unpack() {
+            CapabilityCertificateUnpacked::Empty => self,
             CapabilityCertificateUnpacked::Granted { .. } => Self::granted(recipient),
-            CapabilityCertificateUnpacked::Forwarded { inner, .. } => {
-                Self::forwarded(recipient, inner)
+            CapabilityCertificateUnpacked::Forwarded { inner, rights, .. } => {
+                Self::forwarded_with_rights(recipient, inner, rights)
             }
         }
     }
 }
+
+fn actor_to_grantee(actor: Actor) -> u16 {
+    match actor {
+        Actor::Userspace(pid) => pid.as_u16(),
+        Actor::Kernel => 0,
+    }
+}
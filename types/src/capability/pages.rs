@@ -1,11 +1,14 @@
 use crate::memory::USER_CAPABILITIES;
-use crate::{Actor, CapabilityCertificate, MAX_PROCESSES, PAGE_SIZE};
+use crate::{Actor, CapabilityCertificate, PAGE_SIZE};
 
 #[repr(align(4096))]
 pub struct CapabilityPage(pub [CapabilityCertificate; CAPABILITIES_PER_PAGE]);
 
 pub const CAPABILITIES_PER_PAGE: usize = PAGE_SIZE / size_of::<CapabilityCertificate>();
 
+pub type CapabilityPageLookup =
+    fn(Actor) -> &'static [CapabilityCertificate; CAPABILITIES_PER_PAGE];
+
 pub fn get_capability_certificate_page(
     actor: Actor,
 ) -> &'static [CapabilityCertificate; CAPABILITIES_PER_PAGE] {
@@ -13,9 +16,5 @@ pub fn get_capability_certificate_page(
         Actor::Userspace(pid) => pid.as_u16() as usize,
         Actor::Kernel => 0,
     };
-    &pages()[offset]
-}
-
-fn pages() -> &'static [[CapabilityCertificate; CAPABILITIES_PER_PAGE]; MAX_PROCESSES + 1] {
-    unsafe { &*(USER_CAPABILITIES.start as *const _) }
+    unsafe { &*((USER_CAPABILITIES.start + offset * PAGE_SIZE) as *const _) }
 }
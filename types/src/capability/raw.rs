@@ -1,10 +1,12 @@
-use crate::capability::pages::get_capability_certificate_page;
+use crate::capability::pages::{CapabilityPageLookup, get_capability_certificate_page};
 use crate::memory::USER_CAPABILITIES;
-use crate::{Actor, CapabilityCertificate, CapabilityCertificateUnpacked, PAGE_SIZE, ProcessId};
+use crate::{
+    ALL_RIGHTS, Actor, CapabilityCertificate, CapabilityCertificateUnpacked, PAGE_SIZE, ProcessId,
+};
 use core::sync::atomic::Ordering;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-pub struct CapabilityError(RawCapability, ProcessId);
+pub struct CapabilityError(RawCapability, ProcessId, CapabilityPageLookup);
 
 #[derive(Clone, Copy)]
 #[repr(transparent)]
@@ -42,22 +44,42 @@ impl RawCapability {
     }
 
     pub fn validate(self, orig_claimer: ProcessId) -> Result<RawCapability, CapabilityError> {
+        self.validate_with(orig_claimer, get_capability_certificate_page)
+    }
+
+    pub fn validate_with(
+        self,
+        orig_claimer: ProcessId,
+        pages: CapabilityPageLookup,
+    ) -> Result<RawCapability, CapabilityError> {
+        Ok(self.validate_rights_with(orig_claimer, pages)?.0)
+    }
+
+    pub fn validate_rights_with(
+        self,
+        orig_claimer: ProcessId,
+        pages: CapabilityPageLookup,
+    ) -> Result<(RawCapability, u16), CapabilityError> {
         let mut capability = self;
         let mut claimer = Actor::Userspace(orig_claimer);
+        let mut effective_rights = ALL_RIGHTS;
         loop {
             let certifier = capability.certifier();
-            let certificate = &get_capability_certificate_page(certifier)[capability.local_index()];
+            let certificate = &pages(certifier)[capability.local_index()];
             match certificate.load(Ordering::Relaxed).unpack() {
                 CapabilityCertificateUnpacked::Granted { grantee } if grantee == claimer => {
-                    break Ok(capability);
+                    break Ok((capability, effective_rights));
                 }
-                CapabilityCertificateUnpacked::Forwarded { forwardee, inner }
-                    if forwardee == claimer =>
-                {
+                CapabilityCertificateUnpacked::Forwarded {
+                    forwardee,
+                    inner,
+                    rights,
+                } if forwardee == claimer => {
                     capability = inner;
                     claimer = certifier;
+                    effective_rights &= rights;
                 }
-                _ => return Err(CapabilityError(self, orig_claimer)),
+                _ => return Err(CapabilityError(self, orig_claimer, pages)),
             }
         }
     }
@@ -78,12 +100,17 @@ impl core::fmt::Display for CapabilityError {
         let mut claimer = Actor::Userspace(self.1);
         loop {
             let certifier = capability.certifier();
-            let certificate = &get_capability_certificate_page(certifier)[capability.local_index()];
+            let certificate = &(self.2)(certifier)[capability.local_index()];
             match certificate.load(Ordering::Relaxed).unpack() {
+                CapabilityCertificateUnpacked::Empty => {
+                    return write!(f, " but it was revoked");
+                }
                 CapabilityCertificateUnpacked::Granted { grantee } => {
                     return write!(f, " but it was granted to {grantee:?}");
                 }
-                CapabilityCertificateUnpacked::Forwarded { forwardee, inner } => {
+                CapabilityCertificateUnpacked::Forwarded {
+                    forwardee, inner, ..
+                } => {
                     if forwardee != claimer {
                         return write!(f, " but it was forwarded to {forwardee:?}");
                     } else {
@@ -0,0 +1,15 @@
+pub const ALL_RIGHTS: u16 = u16::MAX;
+
+pub const MAX_RIGHTS: usize = u16::BITS as usize;
+
+pub const fn method_right(method: usize) -> u16 {
+    if method < MAX_RIGHTS { 1 << method } else { 0 }
+}
+
+pub const fn stream_right(stream: usize) -> u16 {
+    if stream < MAX_RIGHTS {
+        1 << (MAX_RIGHTS - 1 - stream)
+    } else {
+        0
+    }
+}
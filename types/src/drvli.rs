@@ -1,7 +1,8 @@
 #![allow(non_camel_case_types, unused)]
 
-use crate::SharedMemory;
-use crate::capability::{Capability, RawCapability};
+use crate::capability::{Capability, RawCapability, method_right, stream_right};
+use crate::{DEFAULT_MEMORY_LIMIT, ExitReason, Priority, ProcessId, SharedMemory};
+use alloc::string::String;
 use core::fmt::Debug;
 use serde::{Deserialize, Serialize};
 
@@ -23,6 +24,11 @@ pub trait ProcessTag: 'static {
     type Spawner;
 
     const NAME: &'static str;
+
+    const PRIORITY: Priority;
+
+    /// Bytes of anonymous memory the process can commit, not counting shared memory.
+    const MEMORY_LIMIT: usize;
 }
 
 include!(concat!(env!("OUT_DIR"), "/drvli.rs"));
@@ -0,0 +1,19 @@
+use alloc::string::String;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum ExitReason {
+    Exit,
+    Killed(String),
+    Panic(String),
+}
+
+impl core::fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ExitReason::Exit => write!(f, "exited"),
+            ExitReason::Killed(message) => write!(f, "killed, {message}"),
+            ExitReason::Panic(message) => write!(f, "panicked at {message}"),
+        }
+    }
+}
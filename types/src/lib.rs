@@ -10,8 +10,10 @@ mod actor;
 mod align;
 mod capability;
 mod drvli;
+mod exit_reason;
 pub mod input;
 pub mod memory;
+mod priority;
 mod process_id;
 mod ring_buffer;
 
@@ -19,6 +21,8 @@ pub use actor::Actor;
 pub use align::*;
 pub use capability::*;
 pub use drvli::*;
+pub use exit_reason::ExitReason;
+pub use priority::Priority;
 pub use process_id::ProcessId;
 pub use ring_buffer::{RingBuffer, UntypedRingBuffer};
 
@@ -27,12 +31,30 @@ pub use ring_buffer::{RingBuffer, UntypedRingBuffer};
 pub struct ProcessInputs<T: ProcessTag> {
     pub id: ProcessId,
     pub riscv_timebase_frequency: Option<usize>,
+    /// Unix time in seconds at which the `time` CSR was zero, if the kernel found an RTC.
+    pub unix_time_at_zero: Option<u64>,
     pub args: T::Args,
 }
 
 pub struct SharedMemory;
 
-pub const MAX_PROCESSES: usize = 8;
+pub const MAX_PROCESSES: usize =
+    (memory::USER_CAPABILITIES.end - memory::USER_CAPABILITIES.start) / PAGE_SIZE - 1;
+
+/// Set in `FileInfo::attributes` for directories, which is the FAT directory attribute.
+pub const ATTR_DIRECTORY: u8 = 0x10;
+
+/// Returned by `ipc_call` instead of a reply length when the server finished before replying.
+pub const IPC_DISCONNECTED: usize = usize::MAX;
+
+/// Returned by `ipc_poll_reply` when the server has not replied yet.
+pub const IPC_PENDING: usize = usize::MAX - 1;
+
+/// Returned by `ipc_call` instead of a reply length when the deadline passed before the reply.
+pub const IPC_TIMED_OUT: usize = usize::MAX - 2;
+
+/// Memory limit of apps that don't declare one in their interface.
+pub const DEFAULT_MEMORY_LIMIT: usize = 64 << 20;
 
 impl Interface for SharedMemory {
     const NAME: &'static str = "shared_memory";
@@ -0,0 +1,7 @@
+#[derive(Clone, Copy, Debug, Default, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Priority {
+    Batch,
+    #[default]
+    Normal,
+    Interactive,
+}
@@ -1,8 +1,10 @@
 use crate::MAX_PROCESSES;
 use core::num::NonZeroU16;
+use serde::{Deserialize, Serialize};
 
 #[repr(transparent)]
-#[derive(Clone, Copy, Eq, PartialEq)]
+#[derive(Clone, Copy, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(transparent)]
 pub struct ProcessId(NonZeroU16);
 
 impl ProcessId {